@@ -1,17 +1,16 @@
 use crate::config::Config;
 use crate::ignore::IgnoreRules;
-use crate::objects::{read_file, Commit, GitObject, IndexEntry, TreeEntry};
+use crate::objects::{Commit, GitObject, IndexEntry, ObjectFormat, Signature, TreeEntry};
 use crate::repository::Repository;
+use crate::sequencer::{Action, Instruction, Sequencer};
 use anyhow::{Context, Result};
-use chrono::Utc;
 use colored::*;
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
-pub fn init() -> Result<()> {
-    Repository::create()?;
+pub fn init(object_format: &str) -> Result<()> {
+    Repository::create(ObjectFormat::parse(object_format)?)?;
     Ok(())
 }
 
@@ -30,10 +29,18 @@ pub fn add(paths: Vec<String>) -> Result<()> {
         };
 
         if !full_path.exists() {
-            anyhow::bail!("Path does not exist: {}", path_str);
+            // A tracked path that is gone from disk stages its deletion
+            let rel = worktree_rel_path(&repo, &full_path)?;
+            let before = index.len();
+            index.retain(|e| !path_is_within(&e.path, &rel));
+            if index.len() == before {
+                anyhow::bail!("Path does not exist: {}", path_str);
+            }
+            println!("Removed {}", rel);
+            continue;
         }
 
-        if full_path.is_file() {
+        if crate::worktree::is_worktree_file(&repo, &full_path) {
             if !ignore_rules.should_ignore(&full_path, &repo) {
                 add_file(&repo, &mut index, &full_path)?;
             }
@@ -41,10 +48,22 @@ pub fn add(paths: Vec<String>) -> Result<()> {
             for entry in WalkDir::new(&full_path) {
                 let entry = entry?;
                 let entry_path = entry.path();
-                if entry_path.is_file() && !ignore_rules.should_ignore(entry_path, &repo) {
+                if crate::worktree::is_worktree_file(&repo, entry_path)
+                    && !ignore_rules.should_ignore(entry_path, &repo)
+                {
                     add_file(&repo, &mut index, entry_path)?;
                 }
             }
+
+            // Stage deletions of tracked files under this directory
+            let rel = worktree_rel_path(&repo, &full_path)?;
+            index.retain(|e| {
+                let gone = path_is_within(&e.path, &rel) && !repo.worktree.join(&e.path).exists();
+                if gone {
+                    println!("Removed {}", e.path);
+                }
+                !gone
+            });
         }
     }
 
@@ -52,36 +71,9 @@ pub fn add(paths: Vec<String>) -> Result<()> {
     Ok(())
 }
 
-fn add_file(repo: &Repository, index: &mut Vec<IndexEntry>, path: &Path) -> Result<()> {
-    let mut data = read_file(path)?;
-
-    // Normalize line endings: CRLF -> LF for consistency across platforms
-    // This prevents false "modified" files when collaborating across Windows/Unix
-    // Only normalize if the file appears to be text (no null bytes)
-    if data.contains(&b'\r') && !data.contains(&b'\0') {
-        let mut normalized = Vec::with_capacity(data.len());
-        let mut i = 0;
-        while i < data.len() {
-            if data[i] == b'\r' && i + 1 < data.len() && data[i + 1] == b'\n' {
-                // CRLF -> LF
-                normalized.push(b'\n');
-                i += 2;
-            } else if data[i] == b'\r' {
-                // Standalone CR -> LF
-                normalized.push(b'\n');
-                i += 1;
-            } else {
-                normalized.push(data[i]);
-                i += 1;
-            }
-        }
-        data = normalized;
-    }
-
-    let blob = GitObject::Blob(data.clone());
-    let hash = repo.write_object(&blob)?;
-
-    let rel_path = path
+/// Converts a path under the worktree to the forward-slash form stored in the index.
+fn worktree_rel_path(repo: &Repository, path: &Path) -> Result<String> {
+    Ok(path
         .strip_prefix(&repo.worktree)
         .with_context(|| {
             format!(
@@ -92,16 +84,24 @@ fn add_file(repo: &Repository, index: &mut Vec<IndexEntry>, path: &Path) -> Resu
         })?
         .to_string_lossy()
         .replace('\\', "/")
-        .to_string();
+        .trim_end_matches('/')
+        .to_string())
+}
 
-    // Get file permissions
-    let metadata = std::fs::metadata(path)?;
-    let mode = if cfg!(unix) {
-        use std::os::unix::fs::PermissionsExt;
-        format!("{:o}", metadata.permissions().mode())
-    } else {
-        "100644".to_string() // Regular file on Windows
-    };
+/// Whether index path `path` is `dir` itself or lies beneath it (`""` is the worktree root).
+fn path_is_within(path: &str, dir: &str) -> bool {
+    dir.is_empty() || path == dir || path.starts_with(&format!("{}/", dir))
+}
+
+fn add_file(repo: &Repository, index: &mut Vec<IndexEntry>, path: &Path) -> Result<()> {
+    let data = crate::worktree::read_worktree_blob(repo, path)?;
+
+    let blob = GitObject::Blob(data.clone());
+    let hash = repo.write_object(&blob)?;
+
+    let rel_path = worktree_rel_path(repo, path)?;
+
+    let mode = crate::worktree::file_mode(repo, path)?;
 
     // Remove existing entry if any
     index.retain(|e| e.path != rel_path);
@@ -112,18 +112,44 @@ fn add_file(repo: &Repository, index: &mut Vec<IndexEntry>, path: &Path) -> Resu
         hash: hash.clone(),
         size: data.len() as u64,
         mode,
+        stage: 0,
     });
 
     println!("Added {}", path_clone);
     Ok(())
 }
 
-pub fn commit(message: &str) -> Result<()> {
+/// Commits the index. The message comes from `-m`, from a file (`-F`, `-`
+/// for stdin), or from the editor. With `amend`, the new commit replaces the
+/// tip, reusing its parents and author. `author` (`Name <email>`) and `date`
+/// override the author recorded in the commit.
+pub fn commit(
+    message: Option<String>,
+    file: Option<String>,
+    amend: bool,
+    author: Option<String>,
+    date: Option<String>,
+) -> Result<()> {
     let repo = Repository::get_repo()?;
     let _lock = crate::lock::RepoLock::new(&repo)?;
     let index = repo.get_index()?;
 
-    if index.is_empty() {
+    // Get parent commit
+    let parent = repo.get_head()?;
+    let merge_head = repo.merge_head()?;
+
+    if index.iter().any(|e| e.stage > 0) {
+        anyhow::bail!("Cannot commit: you have unmerged paths (fix conflicts and 'vcs add' them)");
+    }
+    let amended = match (amend, &parent) {
+        (false, _) => None,
+        (true, None) => anyhow::bail!("Nothing to amend: there are no commits yet"),
+        (true, Some(_)) if merge_head.is_some() => {
+            anyhow::bail!("Cannot amend in the middle of a merge (commit the merge first)")
+        }
+        (true, Some(hash)) => Some(crate::history::read_commit(&repo, hash)?),
+    };
+    if index.is_empty() && parent.is_none() {
         anyhow::bail!("Nothing to commit (use 'vcs add' to stage files)");
     }
 
@@ -132,36 +158,161 @@ pub fn commit(message: &str) -> Result<()> {
     let tree = GitObject::Tree(tree_entries);
     let tree_hash = repo.write_object(&tree)?;
 
-    // Get parent commit
-    let parent = repo.get_head()?;
+    if amended.is_none()
+        && merge_head.is_none()
+        && get_head_tree(&repo)?.as_deref() == Some(tree_hash.as_str())
+    {
+        anyhow::bail!("Nothing to commit, working tree clean (use 'vcs add' to stage changes)");
+    }
 
-    // Get author from config
-    let config = Config::new(&repo);
-    let author_name = config.get_user_name();
-    let author_email = config.get_user_email();
+    let message = match (message, file) {
+        (Some(message), _) => message,
+        (None, Some(file)) if file == "-" => {
+            let mut message = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut message)?;
+            message.trim_end().to_string()
+        }
+        (None, Some(file)) => std::fs::read_to_string(&file)
+            .with_context(|| format!("Failed to read commit message from {}", file))?
+            .trim_end()
+            .to_string(),
+        (None, None) => {
+            let initial = match (&amended, repo.merge_message()?) {
+                (Some(commit), _) => commit.message.clone(),
+                (None, Some(merge_message)) => merge_message,
+                (None, None) => String::new(),
+            };
+            let base = match &amended {
+                Some(commit) => commit.parents.first().cloned(),
+                None => parent.clone(),
+            };
+            let template = commit_template(&repo, &initial, &index, base.as_deref())?;
+            crate::editor::edit_text(&repo, "COMMIT_EDITMSG", &template)?
+        }
+    };
+    if message.trim().is_empty() {
+        anyhow::bail!("Aborting commit due to empty commit message");
+    }
 
-    // Create commit
-    let commit = Commit {
-        tree: tree_hash,
-        parent,
-        author: format!("{} <{}>", author_name, author_email),
-        message: message.to_string(),
-        timestamp: Utc::now().timestamp(),
+    // Create commit; concluding a merge adds the merged commit as a second parent
+    let is_merge = merge_head.is_some();
+    let mut commit = match amended {
+        Some(previous) => {
+            let mut commit = new_commit(&repo, tree_hash, previous.parents, &message)?;
+            commit.author = previous.author;
+            commit
+        }
+        None => {
+            let parents: Vec<String> = parent.iter().cloned().chain(merge_head).collect();
+            new_commit(&repo, tree_hash, parents, &message)?
+        }
     };
+    if let Some(author) = author {
+        (commit.author.name, commit.author.email) = Signature::parse_identity(&author)?;
+    }
+    if let Some(date) = date {
+        (commit.author.time, commit.author.offset) = Signature::parse_date(&date)?;
+    }
+    let parent_count = commit.parents.len();
+    let subject = message.lines().next().unwrap_or("").to_string();
 
     let commit_obj = GitObject::Commit(commit);
     let commit_hash = repo.write_object(&commit_obj)?;
 
-    // Update HEAD
-    repo.set_head(&commit_hash)?;
-
-    // Clear index
-    repo.write_index(&[])?;
+    // Update HEAD; the index already matches the new commit's tree
+    let kind = match (parent_count, is_merge, amend) {
+        (_, _, true) => "commit (amend)",
+        (0, _, _) => "commit (initial)",
+        (_, true, _) => "commit (merge)",
+        _ => "commit",
+    };
+    repo.set_head(&commit_hash, &format!("{}: {}", kind, subject))?;
+    repo.clear_merge_state()?;
 
-    println!("Committed {}: {}", &commit_hash[..8], message);
+    println!("Committed {}: {}", &commit_hash[..8], subject);
     Ok(())
 }
 
+/// The text the editor opens with for a commit: `initial`, followed by
+/// commented-out instructions and the changes staged relative to `base`.
+fn commit_template(
+    repo: &Repository,
+    initial: &str,
+    index: &[IndexEntry],
+    base: Option<&str>,
+) -> Result<String> {
+    let mut text = initial.trim_end().to_string();
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(
+        "\n# Please enter the commit message for your changes. Lines starting\n\
+         # with '#' will be ignored, and an empty message aborts the commit.\n#\n",
+    );
+    match repo.current_branch()? {
+        Some(branch) => text.push_str(&format!("# On branch {}\n", branch)),
+        None => text.push_str("# HEAD detached\n"),
+    }
+
+    let base = get_files_from_tree(
+        repo,
+        &base
+            .map(|hash| crate::worktree::commit_tree(repo, hash))
+            .transpose()?,
+    )?;
+    let staged: HashMap<&str, &str> = index
+        .iter()
+        .map(|e| (e.path.as_str(), e.hash.as_str()))
+        .collect();
+    let mut changes: Vec<(&str, &str)> = Vec::new();
+    for (path, hash) in &staged {
+        match base.get(*path) {
+            None => changes.push((path, "new file:")),
+            Some(old) if old != hash => changes.push((path, "modified:")),
+            Some(_) => {}
+        }
+    }
+    for path in base.keys() {
+        if !staged.contains_key(path.as_str()) {
+            changes.push((path, "deleted:"));
+        }
+    }
+    changes.sort();
+    if !changes.is_empty() {
+        text.push_str("#\n# Changes to be committed:\n");
+        for (path, kind) in changes {
+            text.push_str(&format!("#\t{:<12}{}\n", kind, path));
+        }
+    }
+    Ok(text)
+}
+
+/// Git always terminates commit and tag messages with a newline.
+fn stored_message(repo: &Repository, message: &str) -> String {
+    let mut message = message.to_string();
+    if repo.format == ObjectFormat::Git && !message.ends_with('\n') {
+        message.push('\n');
+    }
+    message
+}
+
+/// Builds a commit authored and committed now by the configured user.
+fn new_commit(
+    repo: &Repository,
+    tree: String,
+    parents: Vec<String>,
+    message: &str,
+) -> Result<Commit> {
+    Ok(Commit {
+        tree,
+        parents,
+        author: repo.author_signature()?,
+        committer: repo.committer_signature()?,
+        extra_headers: Vec::new(),
+        message: stored_message(repo, message),
+    })
+}
+
 fn create_tree_from_index(repo: &Repository, index: &[IndexEntry]) -> Result<Vec<TreeEntry>> {
     create_tree_for_path(repo, index, "")
 }
@@ -259,47 +410,14 @@ fn create_tree_for_path(
         });
     }
 
-    // Sort tree entries for consistent hashing (files before directories, then alphabetically)
-    tree_entries.sort_by(|a, b| {
-        // Directories come after files
-        let a_is_dir = a.mode == "40000";
-        let b_is_dir = b.mode == "40000";
-        if a_is_dir != b_is_dir {
-            return a_is_dir.cmp(&b_is_dir);
-        }
-        // Alphabetical sort within same type
-        a.name.cmp(&b.name)
-    });
-
-    // Create subtrees for subdirectories
-    let mut subdir_names: Vec<String> = subdirs.keys().cloned().collect();
-    subdir_names.sort();
-
-    for subdir_name in subdir_names {
-        let _subdir_entries = &subdirs[&subdir_name];
-        let subdir_path = if base == Path::new("") {
-            subdir_name.clone()
-        } else {
-            format!("{}/{}", base_path, subdir_name)
-        };
-
-        let sub_tree_entries = create_tree_for_path(repo, all_entries, &subdir_path)?;
-        let sub_tree = GitObject::Tree(sub_tree_entries);
-        let sub_tree_hash = repo.write_object(&sub_tree)?;
-
-        tree_entries.push(TreeEntry {
-            mode: "40000".to_string(), // Directory
-            name: subdir_name,
-            hash: sub_tree_hash,
-        });
-    }
+    crate::objects::sort_tree_entries(&mut tree_entries, repo.format);
 
     Ok(tree_entries)
 }
 
 pub fn status() -> Result<()> {
     let repo = Repository::get_repo()?;
-    let index = repo.get_index()?;
+    let mut index = repo.get_index()?;
     let ignore_rules = IgnoreRules::load(&repo)?;
 
     // Get HEAD tree for comparison
@@ -311,10 +429,12 @@ pub fn status() -> Result<()> {
     for entry in WalkDir::new(&repo.worktree) {
         let entry = entry?;
         let path = entry.path();
-        if path.is_file() && !ignore_rules.should_ignore(path, &repo) {
+        if crate::worktree::is_worktree_file(&repo, path)
+            && !ignore_rules.should_ignore(path, &repo)
+        {
             if let Ok(rel_path) = path.strip_prefix(&repo.worktree) {
                 let rel_str = rel_path.to_string_lossy().replace('\\', "/");
-                if let Ok(data) = fs::read(path) {
+                if let Ok(data) = crate::worktree::read_worktree_blob(&repo, path) {
                     working_files.insert(rel_str, data);
                 }
             }
@@ -329,88 +449,76 @@ pub fn status() -> Result<()> {
     let mut deleted: Vec<String> = Vec::new();
     let mut untracked: Vec<String> = Vec::new();
 
+    // Conflicted paths are reported on their own, keyed by the stages present
+    let mut unmerged: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    for entry in index.iter().filter(|e| e.stage > 0) {
+        unmerged
+            .entry(entry.path.clone())
+            .or_default()
+            .push(entry.stage);
+    }
+    index.retain(|e| e.stage == 0);
+
     // Build index map
     let mut index_paths: HashMap<String, String> = HashMap::new();
     for entry in &index {
         index_paths.insert(entry.path.clone(), entry.hash.clone());
     }
 
-    // Check all files in working directory
-    for (path, working_data) in &working_files {
-        let blob = GitObject::Blob(working_data.clone());
-        let working_hash = crate::utils::hash_object(&blob.serialize()?);
-
-        let in_index = index_paths.contains_key(path);
-        let in_head = head_files.contains_key(path);
-
-        if in_index {
-            // File is in index (staged)
-            let idx_hash = index_paths.get(path).unwrap().clone();
-            if working_hash == idx_hash {
-                // Working directory matches index
-                if in_head {
-                    let hd_hash = head_files.get(path).unwrap().clone();
-                    if idx_hash == hd_hash {
-                        // Matches both index and HEAD - shouldn't be staged, but if it is, ignore
-                        // This shouldn't happen in normal operation
-                    } else {
-                        // Index differs from HEAD - staged modification
-                        staged_modified.push(path.clone());
-                    }
-                } else {
-                    // New file, staged
-                    staged_new.push(path.clone());
+    // Index vs HEAD: changes to be committed
+    for entry in &index {
+        match head_files.get(&entry.path) {
+            None => staged_new.push(entry.path.clone()),
+            Some(hd_hash) if *hd_hash != entry.hash => staged_modified.push(entry.path.clone()),
+            Some(_) => {}
+        }
+    }
+    for path in head_files.keys() {
+        if !index_paths.contains_key(path) && !unmerged.contains_key(path) {
+            staged_deleted.push(path.clone());
+        }
+    }
+
+    // A deleted file whose content reappears as a new file was renamed;
+    // among identical files, those that kept their name are paired first
+    let mut staged_renamed: Vec<(String, String)> = Vec::new();
+    staged_new.sort();
+    staged_deleted.sort();
+    let name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+    for same_name in [true, false] {
+        staged_deleted.retain(|old| {
+            let found = staged_new.iter().position(|new| {
+                index_paths.get(new) == head_files.get(old)
+                    && (!same_name || name(new) == name(old))
+            });
+            match found {
+                Some(i) => {
+                    staged_renamed.push((old.clone(), staged_new.remove(i)));
+                    false
                 }
-            } else {
-                // Working directory differs from index
-                // File is staged (index version) but also modified in working directory
-                if in_head {
-                    let hd_hash = head_files.get(path).unwrap().clone();
-                    if idx_hash == hd_hash {
-                        // Index matches HEAD, working differs - just modified, not staged
-                        modified.push(path.clone());
-                    } else {
-                        // Index differs from HEAD (staged), working differs from index (modified)
-                        staged_modified.push(path.clone());
-                        modified.push(path.clone());
-                    }
-                } else {
-                    // New file staged, then modified in working directory
-                    staged_new.push(path.clone());
+                None => true,
+            }
+        });
+    }
+    staged_renamed.sort();
+
+    // Working directory vs index: changes not staged, and untracked files
+    for (path, working_data) in &working_files {
+        match index_paths.get(path) {
+            Some(idx_hash) => {
+                let blob = GitObject::Blob(working_data.clone());
+                let working_hash = crate::utils::hash_object(&blob.serialize()?);
+                if working_hash != *idx_hash {
                     modified.push(path.clone());
                 }
             }
-        } else if in_head {
-            // File is tracked (in HEAD) but not in index
-            let hd_hash = head_files.get(path).unwrap().clone();
-            if working_hash != hd_hash {
-                // Modified from HEAD
-                modified.push(path.clone());
-            }
-            // If it matches HEAD, it's clean - don't show it
-        } else {
-            // Not in index, not in HEAD - untracked
-            untracked.push(path.clone());
+            None if unmerged.contains_key(path) => {}
+            None => untracked.push(path.clone()),
         }
     }
-
-    // Check for deleted files (in index or HEAD but not in working directory)
     for entry in &index {
         if !working_files.contains_key(&entry.path) {
-            // File is staged but deleted from working directory
-            if head_files.contains_key(&entry.path) {
-                staged_deleted.push(entry.path.clone());
-            } else {
-                // Was staged as new, then deleted - remove from staged, show as untracked?
-                // Actually, if it was just added and deleted, it shouldn't be committed
-            }
-        }
-    }
-
-    // Check for files in HEAD but deleted from working directory (not staged)
-    for path in head_files.keys() {
-        if !working_files.contains_key(path) && !index_paths.contains_key(path) {
-            deleted.push(path.clone());
+            deleted.push(entry.path.clone());
         }
     }
 
@@ -423,9 +531,39 @@ pub fn status() -> Result<()> {
     untracked.sort();
 
     // Print status
-    println!("{}", "On branch master".bright_white().bold());
+    match repo.current_branch()? {
+        Some(branch) => println!("{}", format!("On branch {}", branch).bright_white().bold()),
+        None => {
+            let head = repo.get_head()?.unwrap_or_default();
+            let short = &head[..head.len().min(8)];
+            println!("{}", format!("HEAD detached at {}", short).red().bold());
+        }
+    }
+
+    if repo.merge_head()?.is_some() {
+        if unmerged.is_empty() {
+            println!("\nAll conflicts fixed but you are still merging.");
+            println!("  (use \"vcs commit\" to conclude merge)");
+        } else {
+            println!("\nYou have unmerged paths.");
+            println!("  (fix conflicts and run \"vcs commit\")");
+            println!("  (use \"vcs merge --abort\" to abort the merge)");
+        }
+    }
+
+    let sequencer = Sequencer::new(&repo);
+    if sequencer.in_progress() {
+        let command = sequencer.command()?;
+        println!("\nA {} is in progress.", command);
+        println!("  (fix conflicts and run \"vcs {} --continue\")", command);
+        println!("  (use \"vcs {} --abort\" to cancel)", command);
+    }
 
-    if !staged_new.is_empty() || !staged_modified.is_empty() || !staged_deleted.is_empty() {
+    if !staged_new.is_empty()
+        || !staged_modified.is_empty()
+        || !staged_deleted.is_empty()
+        || !staged_renamed.is_empty()
+    {
         println!("\n{}", "Changes to be committed:".green());
         println!("  (use \"vcs reset <file>...\" to unstage)");
         for file in &staged_new {
@@ -437,14 +575,40 @@ pub fn status() -> Result<()> {
         for file in &staged_deleted {
             println!("        {} {}", "deleted:".red(), file);
         }
+        for (old, new) in &staged_renamed {
+            println!("        {} {} -> {}", "renamed:".green(), old, new);
+        }
+    }
+
+    if !unmerged.is_empty() {
+        println!("\n{}", "Unmerged paths:".red());
+        println!("  (fix conflicts and run \"vcs add <file>...\" to mark resolution)");
+        for (file, stages) in &unmerged {
+            let kind = match (
+                stages.contains(&1),
+                stages.contains(&2),
+                stages.contains(&3),
+            ) {
+                (true, true, true) => "both modified:",
+                (false, true, true) => "both added:",
+                (true, true, false) => "deleted by them:",
+                (true, false, true) => "deleted by us:",
+                _ => "unmerged:",
+            };
+            println!("        {} {}", kind.red(), file);
+        }
     }
 
-    if !modified.is_empty() {
+    if !modified.is_empty() || !deleted.is_empty() {
         println!("\n{}", "Changes not staged for commit:".yellow());
-        println!("  (use \"vcs add <file>...\" to update what will be committed)");
+        println!("  (use \"vcs add/rm <file>...\" to update what will be committed)");
+        println!("  (use \"vcs restore <file>...\" to discard changes in working directory)");
         for file in &modified {
             println!("        {} {}", "modified:".yellow(), file);
         }
+        for file in &deleted {
+            println!("        {} {}", "deleted:".red(), file);
+        }
     }
 
     if !untracked.is_empty() {
@@ -456,8 +620,10 @@ pub fn status() -> Result<()> {
     }
 
     if staged_new.is_empty()
+        && unmerged.is_empty()
         && staged_modified.is_empty()
         && staged_deleted.is_empty()
+        && staged_renamed.is_empty()
         && modified.is_empty()
         && deleted.is_empty()
         && untracked.is_empty()
@@ -520,59 +686,83 @@ fn collect_files_from_tree(
     Ok(())
 }
 
-pub fn log(oneline: bool, number: Option<usize>) -> Result<()> {
+pub fn log(revision: Option<String>, oneline: bool, number: Option<usize>) -> Result<()> {
     let repo = Repository::get_repo()?;
-    let mut commit_hash = repo.get_head()?;
-
-    if commit_hash.is_none() {
-        println!("No commits yet");
-        return Ok(());
-    }
-
-    let mut count = 0;
-    let limit = number.unwrap_or(usize::MAX);
-
-    while let Some(hash) = commit_hash {
-        if count >= limit {
-            break;
-        }
-
-        let obj = repo.read_object(&hash)?;
-        if let GitObject::Commit(commit) = obj {
-            if oneline {
-                let short_hash = &hash[..8];
-                let first_line = commit.message.lines().next().unwrap_or("");
-                println!("{} {}", short_hash.bright_yellow(), first_line);
-            } else {
-                println!("{}", format!("commit {}", hash).bright_yellow().bold());
-                println!("Author: {}", commit.author.bright_white());
-                println!(
-                    "Date:   {}",
-                    chrono::DateTime::from_timestamp(commit.timestamp, 0)
-                        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp"))?
-                        .format("%Y-%m-%d %H:%M:%S")
-                        .to_string()
-                        .bright_white()
-                );
-                println!();
-                for line in commit.message.lines() {
-                    println!("    {}", line);
-                }
-                println!();
+    let range = match revision {
+        Some(expr) => repo.resolve_range(&expr)?,
+        None => match repo.get_head()? {
+            Some(head) => crate::revision::RevRange {
+                include: vec![head],
+                exclude: Vec::new(),
+            },
+            None => {
+                println!("No commits yet");
+                return Ok(());
             }
+        },
+    };
+    let hidden = crate::history::reachable(&repo, &range.exclude)?;
 
-            commit_hash = commit.parent;
-            count += 1;
+    let limit = number.unwrap_or(usize::MAX);
+    let walk = crate::history::RevWalk::new(&repo, &range.include)?
+        .filter(|item| !matches!(item, Ok((hash, _)) if hidden.contains(hash)));
+    for item in walk.take(limit) {
+        let (hash, commit) = item?;
+        if oneline {
+            let short_hash = &hash[..8];
+            let first_line = commit.message.lines().next().unwrap_or("");
+            println!("{} {}", short_hash.bright_yellow(), first_line);
         } else {
-            break;
+            println!("{}", format!("commit {}", hash).bright_yellow().bold());
+            if commit.parents.len() > 1 {
+                let short: Vec<&str> = commit.parents.iter().map(|p| &p[..8]).collect();
+                println!("Merge: {}", short.join(" "));
+            }
+            println!("Author: {}", commit.author.identity().bright_white());
+            println!(
+                "Date:   {}",
+                commit
+                    .author
+                    .date_time()?
+                    .format("%Y-%m-%d %H:%M:%S %z")
+                    .to_string()
+                    .bright_white()
+            );
+            println!();
+            for line in commit.message.lines() {
+                println!("    {}", line);
+            }
+            println!();
         }
     }
 
     Ok(())
 }
 
-pub fn cat_file(hash: &str) -> Result<()> {
+pub fn reflog(reference: Option<String>, number: Option<usize>) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let name = reference.unwrap_or_else(|| "HEAD".to_string());
+    let ref_name = crate::revision::logged_ref(&repo, &name)?;
+    let entries = crate::reflog::read(&repo, &ref_name)?;
+
+    let limit = number.unwrap_or(usize::MAX);
+    for (i, entry) in entries.iter().rev().enumerate().take(limit) {
+        let when = entry.who.date_time()?;
+        println!(
+            "{} {}@{{{}}}: {} {}",
+            entry.new[..8].bright_yellow(),
+            name,
+            i,
+            entry.message,
+            format!("({})", when.format("%Y-%m-%d %H:%M:%S")).dimmed()
+        );
+    }
+    Ok(())
+}
+
+pub fn cat_file(rev: &str) -> Result<()> {
     let repo = Repository::get_repo()?;
+    let hash = &repo.resolve_object(rev)?;
     let obj = repo.read_object(hash)?;
 
     match obj {
@@ -584,15 +774,15 @@ pub fn cat_file(hash: &str) -> Result<()> {
                 println!("{} {} {}", entry.mode, entry.hash, entry.name);
             }
         }
-        GitObject::Commit(commit) => {
-            println!("tree {}", commit.tree);
-            if let Some(parent) = commit.parent {
-                println!("parent {}", parent);
+        GitObject::Commit(_) | GitObject::Tag(_) => {
+            // Show the object exactly as stored, in whichever format it was written
+            let raw = repo.read_raw_object(hash)?;
+            let (_, payload) = crate::pack::split_raw(&raw)?;
+            let text = String::from_utf8_lossy(payload);
+            print!("{}", text);
+            if !text.ends_with('\n') {
+                println!();
             }
-            println!("author {}", commit.author);
-            println!("timestamp {}", commit.timestamp);
-            println!();
-            println!("{}", commit.message);
         }
     }
 
@@ -618,6 +808,10 @@ pub fn config(key: Option<String>, value: Option<String>, global: bool, list: bo
 
     if let Some(key) = key {
         if let Some(value) = value {
+            if key == crate::repository::FORMAT_KEY {
+                anyhow::bail!("The object format is chosen at init (vcs init --object-format)");
+            }
+            crate::config::validate(&key, &value)?;
             // Set config
             config.set(&key, &value, global)?;
             println!("Set {} = {}", key, value);
@@ -636,50 +830,2909 @@ pub fn config(key: Option<String>, value: Option<String>, global: bool, list: bo
     Ok(())
 }
 
-pub fn reset(paths: Vec<String>) -> Result<()> {
+/// Resolves a command-line path (absolute, or relative to the worktree root) to its index form.
+fn index_path(repo: &Repository, path_str: &str) -> Result<String> {
+    let path = PathBuf::from(path_str);
+    let full_path = if path.is_absolute() {
+        path
+    } else {
+        repo.worktree.join(path)
+    };
+    worktree_rel_path(repo, &full_path)
+}
+
+/// Removes files from the index and, unless `cached`, from the working tree.
+///
+/// Unless `force` is set, files whose staged or working tree content differs
+/// from HEAD are kept, so that no uncommitted work is lost.
+pub fn rm(paths: Vec<String>, cached: bool, recursive: bool, force: bool) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let mut index = repo.get_index()?;
+    let head_files = get_files_from_tree(&repo, &get_head_tree(&repo)?)?;
+
+    let mut targets: BTreeSet<String> = BTreeSet::new();
+    for path_str in &paths {
+        let rel = index_path(&repo, path_str)?;
+        let matched: BTreeSet<String> = index
+            .iter()
+            .filter(|e| path_is_within(&e.path, &rel))
+            .map(|e| e.path.clone())
+            .collect();
+        if matched.is_empty() {
+            anyhow::bail!("Path '{}' did not match any tracked files", path_str);
+        }
+        let is_file = matched.len() == 1 && matched.contains(&rel);
+        if !recursive && !is_file {
+            anyhow::bail!("Not removing '{}' recursively without -r", path_str);
+        }
+        targets.extend(matched);
+    }
+
+    if !force {
+        let mut blocked = Vec::new();
+        for path in &targets {
+            let mut stages = index.iter().filter(|e| &e.path == path);
+            // Removing a conflicted path is how a conflict is resolved as a deletion
+            let Some(entry) = stages.next().filter(|e| e.stage == 0) else {
+                continue;
+            };
+            let staged_changed = head_files.get(path) != Some(&entry.hash);
+            let on_disk = crate::worktree::worktree_hash(&repo, path)?;
+            let disk_changed = on_disk.is_some_and(|h| h != entry.hash);
+            let lost = if cached {
+                staged_changed && disk_changed
+            } else {
+                staged_changed || disk_changed
+            };
+            if lost {
+                blocked.push(path.clone());
+            }
+        }
+        if !blocked.is_empty() {
+            anyhow::bail!(
+                "The following files have changes that are not committed:\n    {}\n\
+                 Use --cached to keep the files, or -f to remove them anyway",
+                blocked.join("\n    ")
+            );
+        }
+    }
+
+    index.retain(|e| !targets.contains(&e.path));
+    repo.write_index(&index)?;
+    for path in &targets {
+        if !cached {
+            crate::worktree::remove_worktree_file(&repo, path)?;
+        }
+        println!("Removed {}", path);
+    }
+    Ok(())
+}
+
+/// Moves or renames tracked files and directories, in the working tree and
+/// the index alike. With several sources, the destination must be a directory.
+pub fn mv(mut paths: Vec<String>, force: bool) -> Result<()> {
     let repo = Repository::get_repo()?;
     let _lock = crate::lock::RepoLock::new(&repo)?;
     let mut index = repo.get_index()?;
 
+    let destination = paths.pop().context("Destination required")?;
     if paths.is_empty() {
-        // Reset all
-        repo.write_index(&[])?;
-        println!("Unstaged all files");
-        return Ok(());
+        anyhow::bail!("Source required");
+    }
+    let dest = index_path(&repo, &destination)?;
+    let into_dir = repo.worktree.join(&dest).is_dir();
+    if paths.len() > 1 && !into_dir {
+        anyhow::bail!("Destination '{}' is not a directory", destination);
     }
 
-    let mut removed = Vec::new();
-    for path_str in paths {
-        let path = PathBuf::from(&path_str);
-        let rel_path = if path.is_absolute() {
-            // Convert absolute path to relative path from worktree
-            match path.strip_prefix(&repo.worktree) {
-                Ok(rel) => rel.to_string_lossy().replace('\\', "/"),
-                Err(_) => {
-                    // Path is not under worktree, skip it
-                    continue;
-                }
+    let mut moves = Vec::new();
+    for path_str in &paths {
+        let src = index_path(&repo, path_str)?;
+        if src.is_empty() || !repo.worktree.join(&src).exists() {
+            anyhow::bail!("'{}' does not exist", path_str);
+        }
+        let target = if into_dir {
+            let name = src.rsplit('/').next().unwrap_or(&src);
+            if dest.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", dest, name)
             }
         } else {
-            path_str.replace('\\', "/")
+            dest.clone()
         };
+        if path_is_within(&target, &src) {
+            anyhow::bail!("Cannot move '{}' into itself", path_str);
+        }
+        let tracked: Vec<&IndexEntry> = index
+            .iter()
+            .filter(|e| path_is_within(&e.path, &src))
+            .collect();
+        if tracked.is_empty() {
+            anyhow::bail!("'{}' is not under version control", path_str);
+        }
+        if tracked.iter().any(|e| e.stage > 0) {
+            anyhow::bail!("'{}' has unresolved conflicts", path_str);
+        }
+        let target_path = repo.worktree.join(&target);
+        if target_path.is_dir() || (target_path.exists() && !force) {
+            anyhow::bail!(
+                "Destination '{}' already exists{}",
+                target,
+                if target_path.is_dir() {
+                    ""
+                } else {
+                    " (use -f to overwrite it)"
+                }
+            );
+        }
+        moves.push((src, target));
+    }
+
+    for (src, target) in &moves {
+        let to = repo.worktree.join(target);
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(repo.worktree.join(src), &to)
+            .with_context(|| format!("Failed to move {} to {}", src, target))?;
 
-        let before_len = index.len();
-        index.retain(|e| e.path != rel_path);
-        if index.len() < before_len {
-            removed.push(rel_path);
+        index.retain(|e| !path_is_within(&e.path, target));
+        for entry in index.iter_mut().filter(|e| path_is_within(&e.path, src)) {
+            entry.path = format!("{}{}", target, &entry.path[src.len()..]);
+        }
+        // Drop directories the move left empty
+        if let Some(parent) = std::path::Path::new(src).parent() {
+            let mut dir = Some(repo.worktree.join(parent));
+            while let Some(d) = dir {
+                if d == repo.worktree || std::fs::remove_dir(&d).is_err() {
+                    break;
+                }
+                dir = d.parent().map(Path::to_path_buf);
+            }
         }
+        println!("Renamed {} -> {}", src, target);
     }
 
     repo.write_index(&index)?;
+    Ok(())
+}
 
-    if removed.is_empty() {
-        println!("No files were unstaged");
-    } else {
-        for file in &removed {
-            println!("Unstaged {}", file);
+/// Restores files in the working tree (the default) and/or the index.
+///
+/// The working tree is restored from the index unless `source` is given;
+/// the index is restored from `source`, or HEAD. Files missing from the
+/// source are removed.
+pub fn restore(
+    paths: Vec<String>,
+    source: Option<String>,
+    staged: bool,
+    worktree: bool,
+) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let worktree = worktree || !staged;
+    let mut index = repo.get_index()?;
+
+    let commit = match &source {
+        Some(rev) => Some(repo.resolve_revision(rev)?),
+        None if staged => repo.get_head()?,
+        None => None,
+    };
+    let from_index = source.is_none() && !staged;
+    let source_entries = match commit {
+        Some(hash) => {
+            let tree = crate::worktree::commit_tree(&repo, &hash)?;
+            crate::worktree::read_tree_entries(&repo, &tree)?
+        }
+        None if from_index => index.iter().filter(|e| e.stage == 0).cloned().collect(),
+        None => Vec::new(),
+    };
+
+    let mut files: BTreeSet<String> = BTreeSet::new();
+    for path_str in &paths {
+        let rel = index_path(&repo, path_str)?;
+        let before = files.len();
+        files.extend(
+            index
+                .iter()
+                .chain(&source_entries)
+                .filter(|e| path_is_within(&e.path, &rel))
+                .map(|e| e.path.clone()),
+        );
+        if files.len() == before {
+            anyhow::bail!("Path '{}' did not match any tracked files", path_str);
+        }
+    }
+
+    for file in &files {
+        let unmerged = index.iter().any(|e| &e.path == file && e.stage > 0);
+        if unmerged && from_index {
+            anyhow::bail!("Path '{}' is unmerged", file);
+        }
+        let entry = source_entries.iter().find(|e| &e.path == file);
+        if staged {
+            index.retain(|e| &e.path != file);
+            index.extend(entry.cloned());
+        }
+        if worktree {
+            match entry {
+                Some(entry) => {
+                    let full_path = repo.worktree.join(file);
+                    let unchanged = crate::worktree::worktree_hash(&repo, file)?.as_ref()
+                        == Some(&entry.hash)
+                        && crate::worktree::file_mode(&repo, &full_path)? == entry.mode;
+                    if !unchanged {
+                        crate::worktree::write_worktree_file(
+                            &repo,
+                            file,
+                            &entry.hash,
+                            &entry.mode,
+                        )?;
+                    }
+                }
+                None => crate::worktree::remove_worktree_file(&repo, file)?,
+            }
         }
+        println!("Restored {}", file);
     }
 
+    if staged {
+        repo.write_index(&index)?;
+    }
     Ok(())
 }
+
+/// How far `reset` goes beyond moving the current branch.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ResetMode {
+    /// Only move the branch; the index and working tree are kept.
+    Soft,
+    /// Also make the index match the target commit.
+    Mixed,
+    /// Also make the working tree match, discarding local changes.
+    Hard,
+}
+
+/// Resets the current branch to `target`, or, with paths, resets just those
+/// index entries to their version in `target` (HEAD by default).
+///
+/// Without `--`, a first argument that is not a revision is taken as a path.
+pub fn reset(mode: Option<ResetMode>, args: Vec<String>, paths: Vec<String>) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+
+    let (target, paths) = if !paths.is_empty() {
+        if args.len() > 1 {
+            anyhow::bail!("Only one revision may be given before --");
+        }
+        (args.into_iter().next(), paths)
+    } else {
+        let mut args = args.into_iter().peekable();
+        let target = args.next_if(|a| repo.resolve_revision(a).is_ok());
+        (target, args.collect())
+    };
+
+    if paths.is_empty() {
+        reset_head(&repo, mode.unwrap_or(ResetMode::Mixed), target.as_deref())
+    } else if mode.is_some_and(|m| m != ResetMode::Mixed) {
+        anyhow::bail!("Cannot do a soft or hard reset with paths")
+    } else {
+        reset_paths(&repo, target.as_deref(), paths)
+    }
+}
+
+fn reset_head(repo: &Repository, mode: ResetMode, target: Option<&str>) -> Result<()> {
+    let head = repo.get_head()?;
+    let commit = match target {
+        Some(rev) => Some(repo.resolve_revision(rev)?),
+        None => head.clone(),
+    };
+    let entries = |hash: Option<&String>| -> Result<Vec<IndexEntry>> {
+        match hash {
+            Some(hash) => {
+                let tree = crate::worktree::commit_tree(repo, hash)?;
+                crate::worktree::read_tree_entries(repo, &tree)
+            }
+            None => Ok(Vec::new()),
+        }
+    };
+
+    if mode == ResetMode::Soft && repo.merge_head()?.is_some() {
+        anyhow::bail!("Cannot do a soft reset in the middle of a merge");
+    }
+    let target_entries = entries(commit.as_ref())?;
+    match mode {
+        ResetMode::Soft => {}
+        ResetMode::Mixed => repo.write_index(&target_entries)?,
+        ResetMode::Hard => {
+            crate::worktree::switch_tree(repo, &entries(head.as_ref())?, &target_entries, true)?
+        }
+    }
+    if mode != ResetMode::Soft {
+        repo.clear_merge_state()?;
+    }
+
+    let Some(commit) = commit else {
+        println!("Unstaged all files");
+        return Ok(());
+    };
+    let rev = target.unwrap_or("HEAD");
+    repo.set_head(&commit, &format!("reset: moving to {}", rev))?;
+    let summary = crate::history::read_commit(repo, &commit)?
+        .message
+        .lines()
+        .next()
+        .unwrap_or("")
+        .to_string();
+    println!("HEAD is now at {} {}", &commit[..8], summary);
+    Ok(())
+}
+
+fn reset_paths(repo: &Repository, target: Option<&str>, paths: Vec<String>) -> Result<()> {
+    let mut index = repo.get_index()?;
+
+    // Unstaging means making the index entries match the target (HEAD) again
+    let commit = match target {
+        Some(rev) => Some(repo.resolve_revision(rev)?),
+        None => repo.get_head()?,
+    };
+    let source_entries = match commit {
+        Some(hash) => {
+            let tree = crate::worktree::commit_tree(repo, &hash)?;
+            crate::worktree::read_tree_entries(repo, &tree)?
+        }
+        None => Vec::new(),
+    };
+
+    let mut removed = Vec::new();
+    for path_str in paths {
+        let rel_path = index_path(repo, &path_str)?;
+
+        let staged: BTreeSet<String> = index
+            .iter()
+            .chain(&source_entries)
+            .filter(|e| path_is_within(&e.path, &rel_path))
+            .map(|e| e.path.clone())
+            .collect();
+        if staged.is_empty() && !repo.worktree.join(&rel_path).exists() {
+            anyhow::bail!("'{}' is neither a revision nor a known path", path_str);
+        }
+        for file in staged {
+            let current: Vec<&IndexEntry> = index.iter().filter(|e| e.path == file).collect();
+            let source = source_entries.iter().find(|e| e.path == file);
+            let changed = match (current.as_slice(), source) {
+                ([s], Some(h)) => s.stage != 0 || s.hash != h.hash || s.mode != h.mode,
+                ([], None) => false,
+                _ => true,
+            };
+            if changed {
+                index.retain(|e| e.path != file);
+                index.extend(source.cloned());
+                removed.push(file);
+            }
+        }
+    }
+
+    repo.write_index(&index)?;
+
+    if removed.is_empty() {
+        println!("No files were unstaged");
+    } else {
+        for file in &removed {
+            match target {
+                Some(rev) => println!("Reset {} to {}", file, rev),
+                None => println!("Unstaged {}", file),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn branch(
+    name: Option<String>,
+    target: Option<String>,
+    delete: bool,
+    force_delete: bool,
+    rename: bool,
+) -> Result<()> {
+    let repo = Repository::get_repo()?;
+
+    if delete || force_delete {
+        let name = name.context("Branch name required")?;
+        crate::repository::validate_branch_name(&name)?;
+        let _lock = crate::lock::RepoLock::new(&repo)?;
+        let hash = repo.resolve_ref(&format!("refs/heads/{}", name))?;
+        if let (Some(tip), Some(head), false) = (&hash, repo.get_head()?, force_delete) {
+            if !crate::history::is_ancestor(&repo, tip, &head)? {
+                anyhow::bail!(
+                    "The branch '{}' is not fully merged (use -D to delete it anyway)",
+                    name
+                );
+            }
+        }
+        repo.delete_branch(&name)?;
+        let short = hash.as_deref().map(|h| &h[..h.len().min(8)]).unwrap_or("");
+        println!("Deleted branch {} (was {})", name, short);
+        return Ok(());
+    }
+
+    if rename {
+        let _lock = crate::lock::RepoLock::new(&repo)?;
+        let (old, new) = match (name, target) {
+            (Some(old), Some(new)) => (old, new),
+            (Some(new), None) => (
+                repo.current_branch()?
+                    .context("HEAD is detached; specify the branch to rename")?,
+                new,
+            ),
+            _ => anyhow::bail!("Branch name required"),
+        };
+        repo.rename_branch(&old, &new)?;
+        println!("Renamed branch {} to {}", old, new);
+        return Ok(());
+    }
+
+    if let Some(name) = name {
+        let _lock = crate::lock::RepoLock::new(&repo)?;
+        let start = target.unwrap_or_else(|| "HEAD".to_string());
+        let hash = repo.resolve_revision(&start)?;
+        crate::history::read_commit(&repo, &hash)?;
+        repo.create_branch(&name, &hash, &format!("branch: Created from {}", start))?;
+        println!("Created branch {} at {}", name, &hash[..8]);
+        return Ok(());
+    }
+
+    let current = repo.current_branch()?;
+    if current.is_none() {
+        if let Some(head) = repo.get_head()? {
+            println!("* {}", format!("(HEAD detached at {})", &head[..8]).green());
+        }
+    }
+    for branch in repo.list_branches()? {
+        if current.as_deref() == Some(branch.as_str()) {
+            println!("* {}", branch.green());
+        } else {
+            println!("  {}", branch);
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves `target` for checkout, returning the commit hash and the branch it names (if any).
+fn resolve_checkout_target(repo: &Repository, target: &str) -> Result<(String, Option<String>)> {
+    if target != "HEAD" {
+        if let Some(hash) = repo.resolve_ref(&format!("refs/heads/{}", target))? {
+            return Ok((hash, Some(target.to_string())));
+        }
+    }
+    Ok((repo.resolve_revision(target)?, None))
+}
+
+/// Moves HEAD to `commit_hash` (attached to `branch` if given), updating the working tree and index.
+fn checkout_commit(
+    repo: &Repository,
+    commit_hash: &str,
+    branch: Option<&str>,
+    force: bool,
+) -> Result<()> {
+    let current = match repo.get_head()? {
+        Some(head) => {
+            let tree = crate::worktree::commit_tree(repo, &head)?;
+            crate::worktree::read_tree_entries(repo, &tree)?
+        }
+        None => Vec::new(),
+    };
+    let target_tree = crate::worktree::commit_tree(repo, commit_hash)?;
+    let target = crate::worktree::read_tree_entries(repo, &target_tree)?;
+
+    crate::worktree::switch_tree(repo, &current, &target, force)?;
+
+    let from = match (repo.current_branch()?, repo.get_head()?) {
+        (Some(name), _) => name,
+        (None, Some(head)) => head,
+        (None, None) => "nothing".to_string(),
+    };
+    let reason = format!(
+        "checkout: moving from {} to {}",
+        from,
+        branch.unwrap_or(commit_hash)
+    );
+    match branch {
+        Some(name) => {
+            repo.set_symbolic_head(&format!("refs/heads/{}", name), &reason)?;
+            println!("Switched to branch '{}'", name);
+        }
+        None => {
+            repo.write_ref("HEAD", commit_hash, &reason)?;
+            let summary = match repo.read_object(commit_hash)? {
+                GitObject::Commit(commit) => {
+                    commit.message.lines().next().unwrap_or("").to_string()
+                }
+                _ => String::new(),
+            };
+            println!("HEAD is now at {} {}", &commit_hash[..8], summary);
+        }
+    }
+    Ok(())
+}
+
+pub fn tag(
+    name: Option<String>,
+    target: Option<String>,
+    message: Option<String>,
+    annotate: bool,
+    delete: bool,
+    force: bool,
+    list: bool,
+) -> Result<()> {
+    let repo = Repository::get_repo()?;
+
+    if delete {
+        let name = name.context("Tag name required")?;
+        crate::repository::validate_tag_name(&name)?;
+        let _lock = crate::lock::RepoLock::new(&repo)?;
+        let ref_name = format!("refs/tags/{}", name);
+        let hash = repo
+            .resolve_ref(&ref_name)?
+            .with_context(|| format!("Tag '{}' not found", name))?;
+        repo.delete_ref(&ref_name)?;
+        println!("Deleted tag '{}' (was {})", name, &hash[..8]);
+        return Ok(());
+    }
+
+    let name = match name {
+        Some(name) if !list => name,
+        pattern => {
+            let pattern = glob::Pattern::new(pattern.as_deref().unwrap_or("*"))?;
+            for (ref_name, _) in repo.list_refs()? {
+                if let Some(tag) = ref_name.strip_prefix("refs/tags/") {
+                    if pattern.matches(tag) {
+                        println!("{}", tag);
+                    }
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    crate::repository::validate_tag_name(&name)?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let ref_name = format!("refs/tags/{}", name);
+    if !force && repo.resolve_ref(&ref_name)?.is_some() {
+        anyhow::bail!("Tag '{}' already exists (use -f to replace it)", name);
+    }
+    let target = match target {
+        Some(rev) => repo.resolve_revision(&rev)?,
+        None => repo
+            .get_head()?
+            .context("Cannot tag: there are no commits yet")?,
+    };
+
+    if annotate || message.is_some() {
+        let message = message.context("Annotated tags need a message (use -m)")?;
+        let raw = repo.read_raw_object(&target)?;
+        let (target_type, _) = crate::pack::split_raw(&raw)?;
+        let tag = crate::objects::Tag {
+            object: target.clone(),
+            target_type: target_type.to_string(),
+            name: name.clone(),
+            tagger: Some(repo.committer_signature()?),
+            extra_headers: Vec::new(),
+            message: stored_message(&repo, &message),
+        };
+        let hash = repo.write_object(&GitObject::Tag(tag))?;
+        repo.write_ref(&ref_name, &hash, "tag")?;
+        println!("Created annotated tag '{}' at {}", name, &target[..8]);
+    } else {
+        repo.write_ref(&ref_name, &target, "tag")?;
+        println!("Created tag '{}' at {}", name, &target[..8]);
+    }
+    Ok(())
+}
+
+/// Creates branch `name` at the commit `start` names and checks it out. The
+/// branch is removed again if the checkout is refused.
+fn checkout_new_branch(repo: &Repository, name: &str, start: &str, force: bool) -> Result<()> {
+    let hash = repo.resolve_revision(start)?;
+    crate::history::read_commit(repo, &hash)?;
+    repo.create_branch(name, &hash, &format!("branch: Created from {}", start))?;
+    checkout_commit(repo, &hash, Some(name), force).inspect_err(|_| {
+        let _ = repo.delete_branch(name);
+    })
+}
+
+pub fn checkout(target: Option<String>, new_branch: Option<String>, force: bool) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+
+    if let Some(name) = new_branch {
+        let start = target.as_deref().unwrap_or("HEAD");
+        return checkout_new_branch(&repo, &name, start, force);
+    }
+
+    let target = target.context("Branch or commit required")?;
+    let (hash, branch) = resolve_checkout_target(&repo, &target)?;
+    checkout_commit(&repo, &hash, branch.as_deref(), force)
+}
+
+pub fn switch(
+    target: Option<String>,
+    create: Option<String>,
+    detach: bool,
+    force: bool,
+) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+
+    if let Some(name) = create {
+        let start = target.as_deref().unwrap_or("HEAD");
+        return checkout_new_branch(&repo, &name, start, force);
+    }
+
+    let target = target.context("Branch name required")?;
+    let (hash, branch) = resolve_checkout_target(&repo, &target)?;
+    if detach {
+        return checkout_commit(&repo, &hash, None, force);
+    }
+    match branch {
+        Some(name) => checkout_commit(&repo, &hash, Some(&name), force),
+        None => anyhow::bail!(
+            "'{}' is not a branch (use --detach to check out a commit)",
+            target
+        ),
+    }
+}
+
+/// One side of a `diff`: path -> (blob hash, mode), read from the object store or the worktree.
+struct DiffSide {
+    files: BTreeMap<String, (String, String)>,
+    worktree: bool,
+}
+
+impl DiffSide {
+    fn from_entries(entries: Vec<IndexEntry>) -> Self {
+        DiffSide {
+            files: entries
+                .into_iter()
+                .filter(|e| e.stage == 0)
+                .map(|e| (e.path, (e.hash, e.mode)))
+                .collect(),
+            worktree: false,
+        }
+    }
+
+    fn from_worktree<'a>(
+        repo: &Repository,
+        paths: impl Iterator<Item = &'a String>,
+    ) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for path in paths {
+            if let Some(hash) = crate::worktree::worktree_hash(repo, path)? {
+                let mode = crate::worktree::file_mode(repo, &repo.worktree.join(path))?;
+                files.insert(path.clone(), (hash, mode));
+            }
+        }
+        Ok(DiffSide {
+            files,
+            worktree: true,
+        })
+    }
+
+    fn content(&self, repo: &Repository, path: &str) -> Result<Option<Vec<u8>>> {
+        let Some((hash, _)) = self.files.get(path) else {
+            return Ok(None);
+        };
+        if self.worktree {
+            let data = crate::worktree::read_worktree_blob(repo, &repo.worktree.join(path))?;
+            return Ok(Some(data));
+        }
+        match repo.read_object(hash)? {
+            GitObject::Blob(data) => Ok(Some(data)),
+            _ => anyhow::bail!("{} is not a blob", hash),
+        }
+    }
+}
+
+fn rev_tree_entries(repo: &Repository, rev: &str) -> Result<Vec<IndexEntry>> {
+    let hash = repo.resolve_revision(rev)?;
+    let tree = crate::worktree::commit_tree(repo, &hash)?;
+    crate::worktree::read_tree_entries(repo, &tree)
+}
+
+pub fn diff(
+    staged: bool,
+    context: usize,
+    revisions: Vec<String>,
+    paths: Vec<String>,
+) -> Result<()> {
+    let repo = Repository::get_repo()?;
+
+    let head_entries = || -> Result<Vec<IndexEntry>> {
+        match get_head_tree(&repo)? {
+            Some(tree) => crate::worktree::read_tree_entries(&repo, &tree),
+            None => Ok(Vec::new()),
+        }
+    };
+
+    let (old, new) = match (revisions.as_slice(), staged) {
+        ([], true) => (
+            DiffSide::from_entries(head_entries()?),
+            DiffSide::from_entries(repo.get_index()?),
+        ),
+        ([], false) => {
+            let index = DiffSide::from_entries(repo.get_index()?);
+            let worktree = DiffSide::from_worktree(&repo, index.files.keys())?;
+            (index, worktree)
+        }
+        ([rev], true) => (
+            DiffSide::from_entries(rev_tree_entries(&repo, rev)?),
+            DiffSide::from_entries(repo.get_index()?),
+        ),
+        ([rev], false) => {
+            let tree = DiffSide::from_entries(rev_tree_entries(&repo, rev)?);
+            let index = repo.get_index()?;
+            let tracked: BTreeSet<&String> = tree
+                .files
+                .keys()
+                .chain(index.iter().map(|e| &e.path))
+                .collect();
+            let worktree = DiffSide::from_worktree(&repo, tracked.into_iter())?;
+            (tree, worktree)
+        }
+        ([from, to], false) => (
+            DiffSide::from_entries(rev_tree_entries(&repo, from)?),
+            DiffSide::from_entries(rev_tree_entries(&repo, to)?),
+        ),
+        ([_, _], true) => anyhow::bail!("--staged takes at most one revision"),
+        _ => anyhow::bail!("Too many revisions (expected at most two)"),
+    };
+
+    let filters: Vec<String> = paths
+        .iter()
+        .map(|p| p.replace('\\', "/").trim_end_matches('/').to_string())
+        .collect();
+    let all_paths: BTreeSet<&String> = old.files.keys().chain(new.files.keys()).collect();
+
+    for path in all_paths {
+        if !filters.is_empty() && !filters.iter().any(|f| path_is_within(path, f)) {
+            continue;
+        }
+        let (old_entry, new_entry) = (old.files.get(path), new.files.get(path));
+        if old_entry == new_entry {
+            continue;
+        }
+
+        let old_content = old.content(&repo, path)?;
+        let new_content = new.content(&repo, path)?;
+        let (old_hash, old_mode) = old_entry.cloned().unwrap_or_default();
+        let (new_hash, new_mode) = new_entry.cloned().unwrap_or_default();
+        crate::diff::print_file_diff(
+            path,
+            &crate::diff::FileVersion {
+                content: old_content.as_deref(),
+                hash: &old_hash,
+                mode: &old_mode,
+            },
+            &crate::diff::FileVersion {
+                content: new_content.as_deref(),
+                hash: &new_hash,
+                mode: &new_mode,
+            },
+            context,
+        );
+    }
+
+    Ok(())
+}
+
+fn tree_entries_of(repo: &Repository, commit_hash: Option<&str>) -> Result<Vec<IndexEntry>> {
+    match commit_hash {
+        Some(hash) => {
+            let tree = crate::worktree::commit_tree(repo, hash)?;
+            crate::worktree::read_tree_entries(repo, &tree)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+pub fn merge(
+    target: Option<String>,
+    message: Option<String>,
+    no_ff: bool,
+    abort: bool,
+) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+
+    if abort {
+        let Some(merge_head) = repo.merge_head()? else {
+            anyhow::bail!("There is no merge to abort");
+        };
+        let head = tree_entries_of(&repo, repo.get_head()?.as_deref())?;
+        let paths = touched_paths(&repo, repo.get_head()?.as_deref(), Some(&merge_head))?;
+        crate::worktree::restore_paths(&repo, &head, &paths)?;
+        repo.clear_merge_state()?;
+        println!("Merge aborted");
+        return Ok(());
+    }
+
+    let target = target.context("Branch or commit to merge required")?;
+    if repo.merge_head()?.is_some() {
+        anyhow::bail!(
+            "A merge is already in progress (commit the result or use 'vcs merge --abort')"
+        );
+    }
+    let theirs = repo.resolve_revision(&target)?;
+    let ours = repo.get_head()?;
+
+    let Some(ours) = ours else {
+        // Nothing committed yet: adopt the other history wholesale
+        let target_entries = tree_entries_of(&repo, Some(&theirs))?;
+        crate::worktree::switch_tree(&repo, &[], &target_entries, false)?;
+        repo.set_head(&theirs, &format!("merge {}: Fast-forward", target))?;
+        println!("Fast-forward to {}", &theirs[..8]);
+        return Ok(());
+    };
+
+    if crate::history::is_ancestor(&repo, &theirs, &ours)? {
+        println!("Already up to date.");
+        return Ok(());
+    }
+
+    let our_entries = tree_entries_of(&repo, Some(&ours))?;
+    let index = repo.get_index()?;
+    let index_matches_head = index.len() == our_entries.len()
+        && index
+            .iter()
+            .zip(&our_entries)
+            .all(|(a, b)| a.path == b.path && a.hash == b.hash && a.mode == b.mode && a.stage == 0);
+    if !index_matches_head {
+        anyhow::bail!(
+            "Your index contains uncommitted changes; commit or reset them before merging"
+        );
+    }
+
+    if !no_ff && crate::history::is_ancestor(&repo, &ours, &theirs)? {
+        let target_entries = tree_entries_of(&repo, Some(&theirs))?;
+        crate::worktree::switch_tree(&repo, &our_entries, &target_entries, false)?;
+        repo.set_head(&theirs, &format!("merge {}: Fast-forward", target))?;
+        println!("Updating {}..{}", &ours[..8], &theirs[..8]);
+        println!("Fast-forward");
+        return Ok(());
+    }
+
+    // Merging several bases into a virtual one is not supported, and picking
+    // just one of them could silently drop changes
+    let bases = crate::history::merge_bases(&repo, &ours, &theirs)?;
+    if bases.len() > 1 {
+        anyhow::bail!(
+            "Cannot merge {}: the histories have {} merge bases (criss-cross merge); \
+             merge one of them into the other branch first",
+            target,
+            bases.len()
+        );
+    }
+    let base_entries = tree_entries_of(&repo, bases.first().map(|s| s.as_str()))?;
+    let their_entries = tree_entries_of(&repo, Some(&theirs))?;
+    let labels = crate::merge::ConflictLabels {
+        ours: "HEAD",
+        theirs: &target,
+    };
+    let merged =
+        crate::merge::merge_trees(&repo, &base_entries, &our_entries, &their_entries, &labels)?;
+
+    // Updating the working tree refuses to clobber local modifications
+    crate::worktree::switch_tree(&repo, &our_entries, &merged.worktree, false)?;
+
+    let message = message.unwrap_or_else(|| {
+        if repo
+            .resolve_ref(&format!("refs/heads/{}", target))
+            .ok()
+            .flatten()
+            .is_some()
+        {
+            format!("Merge branch '{}'", target)
+        } else {
+            format!("Merge commit '{}'", target)
+        }
+    });
+
+    if !merged.conflicts.is_empty() {
+        repo.write_index(&merged.index)?;
+        repo.write_merge_state(&theirs, &message)?;
+        for (path, kind) in &merged.conflicts {
+            println!("CONFLICT ({}): {}", kind, path);
+        }
+        anyhow::bail!("Automatic merge failed; fix conflicts and then commit the result");
+    }
+
+    let tree_entries = create_tree_from_index(&repo, &merged.index)?;
+    let tree_hash = repo.write_object(&GitObject::Tree(tree_entries))?;
+    let commit = new_commit(&repo, tree_hash, vec![ours, theirs], &message)?;
+    let commit_hash = repo.write_object(&GitObject::Commit(commit))?;
+    repo.set_head(
+        &commit_hash,
+        &format!("merge {}: Merge made by the three-way strategy.", target),
+    )?;
+
+    println!("Merge made by the three-way strategy.");
+    println!("Committed {}: {}", &commit_hash[..8], message);
+    Ok(())
+}
+
+/// The paths a merge between `a` and `b` may have written: those that differ
+/// between the two trees, plus any the index holds as conflicted.
+fn touched_paths(repo: &Repository, a: Option<&str>, b: Option<&str>) -> Result<BTreeSet<String>> {
+    let side = |hash| -> Result<BTreeSet<(String, String, String)>> {
+        Ok(tree_entries_of(repo, hash)?
+            .into_iter()
+            .map(|e| (e.path, e.hash, e.mode))
+            .collect())
+    };
+    let mut paths: BTreeSet<String> = repo
+        .get_index()?
+        .into_iter()
+        .filter(|e| e.stage > 0)
+        .map(|e| e.path)
+        .collect();
+    paths.extend(
+        side(a)?
+            .symmetric_difference(&side(b)?)
+            .map(|(path, _, _)| path.clone()),
+    );
+    Ok(paths)
+}
+
+pub fn cherry_pick(revisions: Vec<String>, cont: bool, abort: bool) -> Result<()> {
+    sequence_command(Action::Pick, revisions, cont, abort)
+}
+
+pub fn revert(revisions: Vec<String>, cont: bool, abort: bool) -> Result<()> {
+    sequence_command(Action::Revert, revisions, cont, abort)
+}
+
+/// Runs `cherry-pick` or `revert`: starts a new sequence over `revisions`
+/// (ranges expand to their commits), or resumes or aborts the one that
+/// stopped on a conflict.
+fn sequence_command(action: Action, revisions: Vec<String>, cont: bool, abort: bool) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let mut lock = crate::lock::RepoLock::new(&repo)?;
+    let sequencer = Sequencer::new(&repo);
+    let command = match action {
+        Action::Revert => "revert",
+        _ => "cherry-pick",
+    };
+
+    if cont || abort {
+        check_sequence(&sequencer, command)?;
+        if abort {
+            return abort_sequence(&repo, &sequencer);
+        }
+        return continue_sequence(&repo, &mut lock, &sequencer, command);
+    }
+
+    if revisions.is_empty() {
+        anyhow::bail!("At least one commit is required");
+    }
+    check_can_start(&repo, &sequencer, command)?;
+
+    let mut todo = Vec::new();
+    for revision in &revisions {
+        let range = repo.resolve_range(revision)?;
+        let hashes = if range.exclude.is_empty() {
+            range.include
+        } else {
+            let mut commits = commits_between(&repo, &range.include, &range.exclude)?;
+            // Picks replay history oldest first; reverts undo it newest first
+            if action == Action::Pick {
+                commits.reverse();
+            }
+            commits
+        };
+        todo.extend(
+            hashes
+                .into_iter()
+                .map(|target| Instruction { action, target }),
+        );
+    }
+
+    let head = repo
+        .get_head()?
+        .context("HEAD does not point to a commit")?;
+    sequencer.start(command, &head, &todo)?;
+    run_sequence(&repo, &mut lock, &sequencer, command)
+}
+
+/// Replays the commits of the current branch that are not in `upstream` on
+/// top of `onto` (default `upstream`), then moves the branch to the result.
+/// With `interactive`, the list of steps is edited first.
+pub fn rebase(
+    upstream: Option<String>,
+    onto: Option<String>,
+    interactive: bool,
+    cont: bool,
+    skip: bool,
+    abort: bool,
+) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let mut lock = crate::lock::RepoLock::new(&repo)?;
+    let sequencer = Sequencer::new(&repo);
+    let command = "rebase";
+
+    if cont || skip || abort {
+        check_sequence(&sequencer, command)?;
+        if abort {
+            return abort_sequence(&repo, &sequencer);
+        }
+        if skip {
+            // Drop what the stopped step left behind, keeping other local edits
+            let (a, b) = match sequencer.current()? {
+                Some(current) => {
+                    let commit = crate::history::read_commit(&repo, &current.target)?;
+                    (commit.parents.first().cloned(), Some(current.target))
+                }
+                None => (None, None),
+            };
+            let paths = touched_paths(&repo, a.as_deref(), b.as_deref())?;
+            let head = tree_entries_of(&repo, repo.get_head()?.as_deref())?;
+            crate::worktree::restore_paths(&repo, &head, &paths)?;
+            sequencer.set_current(None)?;
+            sequencer.set_value("amend", None)?;
+        }
+        return continue_sequence(&repo, &mut lock, &sequencer, command);
+    }
+
+    let upstream = upstream.context("Upstream branch or commit required")?;
+    check_can_start(&repo, &sequencer, command)?;
+    let head = repo
+        .get_head()?
+        .context("HEAD does not point to a commit")?;
+    let upstream_hash = repo.resolve_revision(&upstream)?;
+    let onto_hash = match &onto {
+        Some(rev) => repo.resolve_revision(rev)?,
+        None => upstream_hash.clone(),
+    };
+    if !interactive
+        && onto_hash == upstream_hash
+        && crate::history::is_ancestor(&repo, &upstream_hash, &head)?
+    {
+        println!("Current branch is up to date.");
+        return Ok(());
+    }
+
+    // Merge commits are left out, flattening the history being replayed
+    let mut commits = Vec::new();
+    for hash in commits_between(
+        &repo,
+        std::slice::from_ref(&head),
+        std::slice::from_ref(&upstream_hash),
+    )? {
+        if crate::history::read_commit(&repo, &hash)?.parents.len() <= 1 {
+            commits.push(hash);
+        }
+    }
+    commits.reverse();
+    let mut todo: Vec<Instruction> = commits
+        .into_iter()
+        .map(|target| Instruction {
+            action: Action::Pick,
+            target,
+        })
+        .collect();
+
+    if interactive {
+        let text = format!(
+            "{}\n# Rebase {}..{} onto {} ({} commands)\n{}",
+            crate::sequencer::format_todo(&repo, &todo)?,
+            &upstream_hash[..8],
+            &head[..8],
+            &onto_hash[..8],
+            todo.len(),
+            REBASE_TODO_HELP
+        );
+        let edited = crate::editor::edit_text(&repo, "REBASE_TODO", &text)?;
+        todo = crate::sequencer::parse_todo(&repo, &edited)?;
+        if todo.is_empty() {
+            println!("Nothing to do");
+            return Ok(());
+        }
+    }
+    let first_commit = todo
+        .iter()
+        .find(|i| !matches!(i.action, Action::Exec | Action::Drop));
+    if let Some(first) = first_commit.filter(|i| matches!(i.action, Action::Squash | Action::Fixup))
+    {
+        anyhow::bail!("Cannot '{}' without a previous commit", first.action.name());
+    }
+
+    // Replay on a detached HEAD; the branch only moves once everything applied
+    let head_entries = tree_entries_of(&repo, Some(&head))?;
+    let onto_entries = tree_entries_of(&repo, Some(&onto_hash))?;
+    crate::worktree::switch_tree(&repo, &head_entries, &onto_entries, false)?;
+    sequencer.start(command, &head, &todo)?;
+    sequencer.set_value("onto", Some(&onto_hash))?;
+    if let Some(ref_name) = repo.head_ref()? {
+        sequencer.set_value("head-name", Some(&ref_name))?;
+    }
+    repo.write_ref(
+        "HEAD",
+        &onto_hash,
+        &format!("rebase (start): checkout {}", onto.unwrap_or(upstream)),
+    )?;
+    run_sequence(&repo, &mut lock, &sequencer, command)
+}
+
+const REBASE_TODO_HELP: &str = "#
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# e, edit <commit> = use commit, but stop for amending
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\", but discard this commit's message
+# x, exec <command> = run command (the rest of the line) using shell
+# d, drop <commit> = remove commit
+#
+# Lines can be re-ordered; they are executed from top to bottom.
+# If you remove everything, the rebase will be aborted.
+";
+
+/// Commits reachable from `include` but not from `exclude`, newest first.
+fn commits_between(
+    repo: &Repository,
+    include: &[String],
+    exclude: &[String],
+) -> Result<Vec<String>> {
+    let hidden = crate::history::reachable(repo, exclude)?;
+    let mut commits = Vec::new();
+    for item in crate::history::RevWalk::new(repo, include)? {
+        let (hash, _) = item?;
+        if !hidden.contains(&hash) {
+            commits.push(hash);
+        }
+    }
+    Ok(commits)
+}
+
+/// Checks that `command`'s sequence is the one in progress.
+fn check_sequence(sequencer: &Sequencer, command: &str) -> Result<()> {
+    if !sequencer.in_progress() {
+        anyhow::bail!("No {} in progress", command);
+    }
+    let running = sequencer.command()?;
+    if running != command {
+        anyhow::bail!("A {} is in progress, not a {}", running, command);
+    }
+    Ok(())
+}
+
+/// Checks that a new sequence can start: nothing else in progress, and an
+/// index that matches HEAD.
+fn check_can_start(repo: &Repository, sequencer: &Sequencer, command: &str) -> Result<()> {
+    if sequencer.in_progress() {
+        anyhow::bail!(
+            "A {} is already in progress (use --continue or --abort)",
+            sequencer.command()?
+        );
+    }
+    if repo.merge_head()?.is_some() {
+        anyhow::bail!("A merge is in progress (commit the result or use 'vcs merge --abort')");
+    }
+    let head = repo
+        .get_head()?
+        .with_context(|| format!("Cannot {}: there are no commits yet", command))?;
+    let index = repo.get_index()?;
+    let head_entries = tree_entries_of(repo, Some(&head))?;
+    let index_matches_head = index.len() == head_entries.len()
+        && index
+            .iter()
+            .zip(&head_entries)
+            .all(|(a, b)| a.path == b.path && a.hash == b.hash && a.mode == b.mode && a.stage == 0);
+    if !index_matches_head {
+        anyhow::bail!("Your index contains uncommitted changes; commit or reset them first");
+    }
+    Ok(())
+}
+
+/// Resumes a stopped sequence: commits the resolved step, folds staged
+/// changes into a commit stopped at by `edit`, then runs the rest.
+fn continue_sequence(
+    repo: &Repository,
+    lock: &mut crate::lock::RepoLock,
+    sequencer: &Sequencer,
+    command: &str,
+) -> Result<()> {
+    if let Some(current) = sequencer.current()? {
+        commit_instruction(repo, command, &current)?;
+        sequencer.set_current(None)?;
+        if current.action == Action::Edit {
+            return stop_for_edit(repo, sequencer);
+        }
+    } else if let Some(edited) = sequencer.value("amend")? {
+        if repo.get_head()?.as_deref() == Some(edited.as_str()) {
+            amend_head_tree(repo)?;
+        }
+        sequencer.set_value("amend", None)?;
+    }
+    run_sequence(repo, lock, sequencer, command)
+}
+
+/// Applies the remaining instructions one by one, stopping at the first
+/// conflict, failed `exec` or `edit`.
+fn run_sequence(
+    repo: &Repository,
+    lock: &mut crate::lock::RepoLock,
+    sequencer: &Sequencer,
+    command: &str,
+) -> Result<()> {
+    let mut todo = sequencer.todo()?;
+    while !todo.is_empty() {
+        let instruction = todo.remove(0);
+        sequencer.set_todo(&todo)?;
+        let reused = match instruction.action {
+            Action::Drop => continue,
+            Action::Exec => {
+                // The command may itself run vcs (e.g. `vcs commit`), so it
+                // must not find the repository locked
+                lock.released(|| run_exec(repo, &instruction.target, command))??;
+                continue;
+            }
+            Action::Pick | Action::Edit if command == "rebase" => {
+                fast_forward_instruction(repo, &instruction)?
+            }
+            _ => false,
+        };
+        if !reused {
+            if !apply_instruction(repo, &instruction)? {
+                sequencer.set_current(Some(&instruction))?;
+                anyhow::bail!(
+                    "Could not {} {}; fix the conflicts, 'vcs add' them and run \
+                     'vcs {} --continue' (or 'vcs {} --abort' to give up)",
+                    instruction.action.name(),
+                    &instruction.target[..8],
+                    command,
+                    command
+                );
+            }
+            // A failed commit (e.g. an empty message) is retried by --continue
+            if let Err(err) = commit_instruction(repo, command, &instruction) {
+                sequencer.set_current(Some(&instruction))?;
+                return Err(err);
+            }
+        }
+        if instruction.action == Action::Edit {
+            return stop_for_edit(repo, sequencer);
+        }
+    }
+    if command == "rebase" {
+        finish_rebase(repo, sequencer)?;
+    }
+    sequencer.finish()
+}
+
+fn stop_for_edit(repo: &Repository, sequencer: &Sequencer) -> Result<()> {
+    let head = repo
+        .get_head()?
+        .context("HEAD does not point to a commit")?;
+    sequencer.set_value("amend", Some(&head))?;
+    println!(
+        "Stopped at {}; make your changes, 'vcs add' them and run 'vcs rebase --continue'",
+        &head[..8]
+    );
+    Ok(())
+}
+
+fn run_exec(repo: &Repository, shell_command: &str, command: &str) -> Result<()> {
+    println!("Executing: {}", shell_command);
+    let status = if cfg!(windows) {
+        std::process::Command::new("cmd")
+            .arg("/C")
+            .arg(shell_command)
+            .current_dir(&repo.worktree)
+            .status()
+    } else {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(shell_command)
+            .current_dir(&repo.worktree)
+            .status()
+    }
+    .with_context(|| format!("Failed to run '{}'", shell_command))?;
+    if !status.success() {
+        anyhow::bail!(
+            "Command '{}' failed ({}); fix the problem and run 'vcs {} --continue'",
+            shell_command,
+            status,
+            command
+        );
+    }
+    Ok(())
+}
+
+/// Reuses a commit unchanged when its parent is already HEAD.
+fn fast_forward_instruction(repo: &Repository, instruction: &Instruction) -> Result<bool> {
+    let commit = crate::history::read_commit(repo, &instruction.target)?;
+    let head = repo.get_head()?;
+    if commit.parents.len() != 1 || commit.parents.first() != head.as_ref() {
+        return Ok(false);
+    }
+    crate::worktree::switch_tree(
+        repo,
+        &tree_entries_of(repo, head.as_deref())?,
+        &tree_entries_of(repo, Some(&instruction.target))?,
+        false,
+    )?;
+    let subject = commit.message.lines().next().unwrap_or("");
+    repo.write_ref(
+        "HEAD",
+        &instruction.target,
+        &format!("rebase ({}): {}", instruction.action.name(), subject),
+    )?;
+    println!("Kept {}: {}", &instruction.target[..8], subject);
+    Ok(true)
+}
+
+/// Merges the change introduced (or, for a revert, undone) by a commit into
+/// HEAD's tree, updating the index and working tree. Returns false when this
+/// left conflicts.
+fn apply_instruction(repo: &Repository, instruction: &Instruction) -> Result<bool> {
+    let commit = crate::history::read_commit(repo, &instruction.target)?;
+    if commit.parents.len() > 1 {
+        anyhow::bail!(
+            "{} is a merge commit, which cannot be {}",
+            &instruction.target[..8],
+            match instruction.action {
+                Action::Revert => "reverted",
+                _ => "picked",
+            }
+        );
+    }
+    let parent = commit.parents.first().map(String::as_str);
+    let (base, theirs) = match instruction.action {
+        Action::Revert => (Some(instruction.target.as_str()), parent),
+        _ => (parent, Some(instruction.target.as_str())),
+    };
+    let head = repo
+        .get_head()?
+        .context("HEAD does not point to a commit")?;
+    let our_entries = tree_entries_of(repo, Some(&head))?;
+    let label = format!(
+        "{} ({})",
+        &instruction.target[..8],
+        commit.message.lines().next().unwrap_or("")
+    );
+    let labels = crate::merge::ConflictLabels {
+        ours: "HEAD",
+        theirs: &label,
+    };
+    let merged = crate::merge::merge_trees(
+        repo,
+        &tree_entries_of(repo, base)?,
+        &our_entries,
+        &tree_entries_of(repo, theirs)?,
+        &labels,
+    )?;
+    crate::worktree::switch_tree(repo, &our_entries, &merged.worktree, false)?;
+    if merged.conflicts.is_empty() {
+        return Ok(true);
+    }
+    repo.write_index(&merged.index)?;
+    for (path, kind) in &merged.conflicts {
+        println!("CONFLICT ({}): {}", kind, path);
+    }
+    Ok(false)
+}
+
+/// Commits the index as the result of `instruction`. Picked commits keep
+/// their author and message (a cherry-pick notes where it came from); a
+/// squash or fixup replaces HEAD with a commit combining both changes.
+fn commit_instruction(repo: &Repository, command: &str, instruction: &Instruction) -> Result<()> {
+    let index = repo.get_index()?;
+    if index.iter().any(|e| e.stage > 0) {
+        anyhow::bail!("You have unmerged paths (fix conflicts and 'vcs add' them)");
+    }
+    let head = repo
+        .get_head()?
+        .context("HEAD does not point to a commit")?;
+    let tree_hash = repo.write_object(&GitObject::Tree(create_tree_from_index(repo, &index)?))?;
+    let original = crate::history::read_commit(repo, &instruction.target)?;
+    let folding = matches!(instruction.action, Action::Squash | Action::Fixup);
+    if !folding && crate::worktree::commit_tree(repo, &head)? == tree_hash {
+        println!(
+            "Skipping {}: its changes are already in HEAD",
+            &instruction.target[..8]
+        );
+        return Ok(());
+    }
+
+    let commit = match instruction.action {
+        Action::Revert => {
+            let subject = original.message.lines().next().unwrap_or("");
+            let message = format!(
+                "Revert \"{}\"\n\nThis reverts commit {}.",
+                subject, instruction.target
+            );
+            new_commit(repo, tree_hash, vec![head], &message)?
+        }
+        Action::Squash | Action::Fixup => {
+            let previous = crate::history::read_commit(repo, &head)?;
+            let message = if instruction.action == Action::Squash {
+                let combined = format!(
+                    "{}\n\n{}\n\n# This is a combination of two commits.\n\
+                     # Lines starting with '#' are ignored; an empty message aborts.\n",
+                    previous.message.trim_end(),
+                    original.message.trim_end()
+                );
+                edited_message(repo, &combined)?
+            } else {
+                previous.message.clone()
+            };
+            let mut commit = new_commit(repo, tree_hash, previous.parents, &message)?;
+            commit.author = previous.author;
+            commit
+        }
+        _ => {
+            let message = match instruction.action {
+                Action::Reword => {
+                    let template = format!(
+                        "{}\n\n# Enter the new commit message. Lines starting with '#' are\n\
+                         # ignored; an empty message aborts.\n",
+                        original.message.trim_end()
+                    );
+                    edited_message(repo, &template)?
+                }
+                _ if command == "cherry-pick" => format!(
+                    "{}\n\n(cherry picked from commit {})",
+                    original.message.trim_end(),
+                    instruction.target
+                ),
+                _ => original.message.clone(),
+            };
+            let mut commit = new_commit(repo, tree_hash, vec![head], &message)?;
+            commit.author = original.author;
+            commit
+        }
+    };
+    let summary = commit.message.lines().next().unwrap_or("").to_string();
+    let hash = repo.write_object(&GitObject::Commit(commit))?;
+    let reason = if command == "rebase" {
+        format!("rebase ({}): {}", instruction.action.name(), summary)
+    } else {
+        format!("{}: {}", command, summary)
+    };
+    repo.set_head(&hash, &reason)?;
+    println!("Committed {}: {}", &hash[..8], summary);
+    Ok(())
+}
+
+fn edited_message(repo: &Repository, template: &str) -> Result<String> {
+    let message = crate::editor::edit_text(repo, "COMMIT_EDITMSG", template)?;
+    if message.is_empty() {
+        anyhow::bail!("Aborting commit due to empty commit message");
+    }
+    Ok(message)
+}
+
+/// Replaces HEAD with a commit of the index, keeping HEAD's parents, author and message.
+fn amend_head_tree(repo: &Repository) -> Result<()> {
+    let head = repo
+        .get_head()?
+        .context("HEAD does not point to a commit")?;
+    let index = repo.get_index()?;
+    if index.iter().any(|e| e.stage > 0) {
+        anyhow::bail!("You have unmerged paths (fix conflicts and 'vcs add' them)");
+    }
+    let tree_hash = repo.write_object(&GitObject::Tree(create_tree_from_index(repo, &index)?))?;
+    let previous = crate::history::read_commit(repo, &head)?;
+    if previous.tree == tree_hash {
+        return Ok(());
+    }
+    let summary = previous.message.lines().next().unwrap_or("").to_string();
+    let mut commit = new_commit(repo, tree_hash, previous.parents, &previous.message)?;
+    commit.author = previous.author;
+    let hash = repo.write_object(&GitObject::Commit(commit))?;
+    repo.set_head(&hash, &format!("commit (amend): {}", summary))?;
+    println!("Amended {}: {}", &hash[..8], summary);
+    Ok(())
+}
+
+/// Points the rebased branch at the result and checks it out again.
+fn finish_rebase(repo: &Repository, sequencer: &Sequencer) -> Result<()> {
+    let head = repo
+        .get_head()?
+        .context("HEAD does not point to a commit")?;
+    match sequencer.value("head-name")? {
+        Some(ref_name) => {
+            let onto = sequencer.value("onto")?.unwrap_or_default();
+            repo.write_ref(
+                &ref_name,
+                &head,
+                &format!("rebase (finish): {} onto {}", ref_name, onto),
+            )?;
+            repo.set_symbolic_head(
+                &ref_name,
+                &format!("rebase (finish): returning to {}", ref_name),
+            )?;
+            println!("Successfully rebased and updated {}.", ref_name);
+        }
+        None => println!("Successfully rebased; HEAD is at {}", &head[..8]),
+    }
+    Ok(())
+}
+
+/// Returns HEAD, the index and the working tree to where they were before the sequence.
+fn abort_sequence(repo: &Repository, sequencer: &Sequencer) -> Result<()> {
+    let command = sequencer.command()?;
+    let orig_head = sequencer.orig_head()?;
+    let head = repo.get_head()?;
+    crate::worktree::switch_tree(
+        repo,
+        &tree_entries_of(repo, head.as_deref())?,
+        &tree_entries_of(repo, Some(&orig_head))?,
+        true,
+    )?;
+    let reason = format!("{} (abort)", command);
+    match sequencer.value("head-name")? {
+        Some(ref_name) => repo.set_symbolic_head(&ref_name, &reason)?,
+        None => repo.set_head(&orig_head, &reason)?,
+    }
+    sequencer.finish()?;
+    println!("{} aborted; HEAD is back at {}", command, &orig_head[..8]);
+    Ok(())
+}
+
+/// Ref holding the latest stash entry; older entries live in its reflog.
+const STASH_REF: &str = "refs/stash";
+
+/// Finds stash entry `stash` (`stash@{n}` or `n`, newest first), returning its
+/// position and commit.
+fn find_stash(repo: &Repository, stash: Option<&str>) -> Result<(usize, String)> {
+    let n = match stash {
+        None => 0,
+        Some(s) => s
+            .strip_prefix("stash@{")
+            .and_then(|s| s.strip_suffix('}'))
+            .unwrap_or(s)
+            .parse()
+            .with_context(|| format!("Invalid stash reference '{}'", s))?,
+    };
+    let entries = crate::reflog::read(repo, STASH_REF)?;
+    if entries.is_empty() {
+        anyhow::bail!("No stash entries found");
+    }
+    let entry = entries
+        .iter()
+        .rev()
+        .nth(n)
+        .with_context(|| format!("stash@{{{}}} does not exist", n))?;
+    Ok((n, entry.new.clone()))
+}
+
+/// Saves local changes to the index and tracked files as a stash entry, then
+/// resets the working tree and index to HEAD.
+///
+/// The entry is a commit of the working tree whose parents are HEAD and a
+/// commit of the index. Untracked files are left alone.
+pub fn stash_push(message: Option<String>) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let head = repo
+        .get_head()?
+        .context("Cannot stash: there are no commits yet")?;
+    let index = repo.get_index()?;
+    if index.iter().any(|e| e.stage > 0) {
+        anyhow::bail!("Cannot stash: you have unmerged paths");
+    }
+
+    let mut work = Vec::new();
+    for entry in &index {
+        let full_path = repo.worktree.join(&entry.path);
+        let Some(hash) = crate::worktree::worktree_hash(&repo, &entry.path)? else {
+            continue;
+        };
+        if hash != entry.hash {
+            let data = crate::worktree::read_worktree_blob(&repo, &full_path)?;
+            repo.write_object(&GitObject::Blob(data))?;
+        }
+        work.push(IndexEntry {
+            hash,
+            mode: crate::worktree::file_mode(&repo, &full_path)?,
+            ..entry.clone()
+        });
+    }
+
+    let head_tree = crate::worktree::commit_tree(&repo, &head)?;
+    let index_tree = repo.write_object(&GitObject::Tree(create_tree_from_index(&repo, &index)?))?;
+    let work_tree = repo.write_object(&GitObject::Tree(create_tree_from_index(&repo, &work)?))?;
+    if index_tree == head_tree && work_tree == head_tree {
+        println!("No local changes to save");
+        return Ok(());
+    }
+
+    let branch = repo
+        .current_branch()?
+        .unwrap_or_else(|| "(no branch)".to_string());
+    let subject = crate::history::read_commit(&repo, &head)?
+        .message
+        .lines()
+        .next()
+        .unwrap_or("")
+        .to_string();
+    let base = format!("{}: {} {}", branch, &head[..8], subject);
+    let index_commit = new_commit(
+        &repo,
+        index_tree,
+        vec![head.clone()],
+        &format!("index on {}", base),
+    )?;
+    let index_hash = repo.write_object(&GitObject::Commit(index_commit))?;
+    let message = match message {
+        Some(message) => format!("On {}: {}", branch, message),
+        None => format!("WIP on {}", base),
+    };
+    let stash = new_commit(&repo, work_tree, vec![head.clone(), index_hash], &message)?;
+    let stash_hash = repo.write_object(&GitObject::Commit(stash))?;
+    repo.write_ref(STASH_REF, &stash_hash, &message)?;
+
+    let head_entries = tree_entries_of(&repo, Some(&head))?;
+    crate::worktree::switch_tree(&repo, &head_entries, &head_entries, true)?;
+    println!("Saved working directory and index state {}", message);
+    Ok(())
+}
+
+pub fn stash_list() -> Result<()> {
+    let repo = Repository::get_repo()?;
+    for (i, entry) in crate::reflog::read(&repo, STASH_REF)?
+        .iter()
+        .rev()
+        .enumerate()
+    {
+        println!("{}: {}", format!("stash@{{{}}}", i).yellow(), entry.message);
+    }
+    Ok(())
+}
+
+/// Shows the changes recorded in a stash entry as a diff against the commit it was made on.
+pub fn stash_show(stash: Option<String>) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let (_, hash) = find_stash(&repo, stash.as_deref())?;
+    diff(false, 3, vec![format!("{}^1", hash), hash], Vec::new())
+}
+
+/// Reapplies a stash entry onto HEAD with a three-way merge against the commit
+/// it was made on. Changes come back unstaged, except for new files. With
+/// `pop`, the entry is dropped once it applied without conflicts.
+pub fn stash_apply(stash: Option<String>, pop: bool) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let (n, hash) = find_stash(&repo, stash.as_deref())?;
+    let head = repo
+        .get_head()?
+        .context("Cannot apply a stash: there are no commits yet")?;
+    if repo.get_index()?.iter().any(|e| e.stage > 0) {
+        anyhow::bail!("Cannot apply a stash: you have unmerged paths");
+    }
+
+    let base = crate::history::read_commit(&repo, &hash)?
+        .parents
+        .first()
+        .cloned()
+        .context("Stash entry has no base commit")?;
+    let our_entries = tree_entries_of(&repo, Some(&head))?;
+    let labels = crate::merge::ConflictLabels {
+        ours: "Updated upstream",
+        theirs: "Stashed changes",
+    };
+    let merged = crate::merge::merge_trees(
+        &repo,
+        &tree_entries_of(&repo, Some(&base))?,
+        &our_entries,
+        &tree_entries_of(&repo, Some(&hash))?,
+        &labels,
+    )?;
+    crate::worktree::switch_tree(&repo, &our_entries, &merged.worktree, false)?;
+
+    // Only conflicts and new files are left staged
+    let ours: BTreeMap<&str, &IndexEntry> =
+        our_entries.iter().map(|e| (e.path.as_str(), e)).collect();
+    let result: BTreeMap<&str, &IndexEntry> = merged
+        .worktree
+        .iter()
+        .map(|e| (e.path.as_str(), e))
+        .collect();
+    let mut changed: BTreeSet<&str> = ours
+        .keys()
+        .chain(result.keys())
+        .copied()
+        .filter(|path| {
+            let (a, b) = (ours.get(path), result.get(path));
+            a.map(|e| (&e.hash, &e.mode)) != b.map(|e| (&e.hash, &e.mode))
+        })
+        .collect();
+    changed.extend(merged.conflicts.iter().map(|(path, _)| path.as_str()));
+
+    let mut index = repo.get_index()?;
+    for path in changed {
+        let conflicted = merged.conflicts.iter().any(|(p, _)| p == path);
+        if conflicted {
+            index.retain(|e| e.path != path);
+            index.extend(merged.index.iter().filter(|e| e.path == path).cloned());
+        } else if let Some(entry) = ours.get(path) {
+            index.retain(|e| e.path != path);
+            index.push((*entry).clone());
+        }
+    }
+    repo.write_index(&index)?;
+
+    if !merged.conflicts.is_empty() {
+        for (path, kind) in &merged.conflicts {
+            println!("CONFLICT ({}): {}", kind, path);
+        }
+        anyhow::bail!("Conflicts in stashed changes; the stash entry is kept");
+    }
+    println!("Applied stash@{{{}}}", n);
+    if pop {
+        drop_stash(&repo, n)?;
+    }
+    Ok(())
+}
+
+pub fn stash_drop(stash: Option<String>) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let (n, _) = find_stash(&repo, stash.as_deref())?;
+    drop_stash(&repo, n)
+}
+
+/// Removes entry `n` from the stash log, moving `refs/stash` to the next newest entry.
+fn drop_stash(repo: &Repository, n: usize) -> Result<()> {
+    let mut entries = crate::reflog::read(repo, STASH_REF)?;
+    let dropped = entries.remove(entries.len() - 1 - n);
+    match entries.last() {
+        Some(top) => {
+            repo.write_ref_unlogged(STASH_REF, &top.new)?;
+            crate::reflog::write(repo, STASH_REF, &entries)?;
+        }
+        None => repo.delete_ref(STASH_REF)?,
+    }
+    println!("Dropped stash@{{{}}} ({})", n, &dropped.new[..8]);
+    Ok(())
+}
+
+pub fn migrate_objects() -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let rewritten = repo.compress_loose_objects()?;
+    println!("Compressed {} loose objects", rewritten);
+    Ok(())
+}
+
+pub fn repack() -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+
+    // Walk from the roots first so reachable blobs carry path hints for delta selection
+    let mut objects =
+        crate::history::reachable_objects(&repo, &repo.gc_roots()?, &Default::default())?;
+    let known: std::collections::HashSet<String> = objects.iter().map(|(h, _)| h.clone()).collect();
+    // Recent unreachable loose objects stay loose, so gc can still age them out
+    let recent = repo.recent_loose_objects()?;
+    for hash in repo.all_objects()? {
+        if !known.contains(&hash) && !recent.contains(&hash) {
+            objects.push((hash, String::new()));
+        }
+    }
+
+    match repo.repack(&objects)? {
+        Some(path) => println!("Packed {} objects into {}", objects.len(), path.display()),
+        None => println!("Nothing to pack"),
+    }
+    Ok(())
+}
+
+pub fn gc() -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+
+    let before = repo.all_objects()?.len();
+    let objects = crate::history::reachable_objects(&repo, &repo.gc_roots()?, &Default::default())?;
+    match repo.repack(&objects)? {
+        Some(path) => println!("Packed {} objects into {}", objects.len(), path.display()),
+        None => println!("Nothing to pack"),
+    }
+    // Unreachable loose objects younger than the grace period are kept
+    let after = repo.all_objects()?.len();
+    println!(
+        "Pruned {} unreachable objects",
+        before.saturating_sub(after)
+    );
+    Ok(())
+}
+
+/// Moves local branch `name` to `new_tip` if it is new or a fast-forward, updating the
+/// working tree when it is the checked-out branch. Returns whether the branch moved.
+fn fast_forward_branch(repo: &Repository, name: &str, new_tip: &str, reason: &str) -> Result<bool> {
+    crate::repository::validate_branch_name(name)?;
+    let ref_name = format!("refs/heads/{}", name);
+    let Some(old_tip) = repo.resolve_ref(&ref_name)? else {
+        repo.write_ref(&ref_name, new_tip, reason)?;
+        println!("Created branch '{}' at {}", name, &new_tip[..8]);
+        return Ok(true);
+    };
+    if old_tip == new_tip {
+        return Ok(false);
+    }
+    if !crate::history::is_ancestor(repo, &old_tip, new_tip)? {
+        println!("Skipped branch '{}': local history has diverged", name);
+        return Ok(false);
+    }
+    if repo.current_branch()?.as_deref() == Some(name) {
+        let current = tree_entries_of(repo, Some(&old_tip))?;
+        let target = tree_entries_of(repo, Some(new_tip))?;
+        crate::worktree::switch_tree(repo, &current, &target, false)?;
+    }
+    repo.write_ref(&ref_name, new_tip, reason)?;
+    println!(
+        "Updated branch '{}' {}..{}",
+        name,
+        &old_tip[..8],
+        &new_tip[..8]
+    );
+    Ok(true)
+}
+
+pub fn import_git(path: &str) -> Result<()> {
+    use crate::gitdir::{append_map, read_map, Converter, GitDir};
+
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let git = GitDir::open(Path::new(path))?;
+
+    let known = read_map(&repo)?
+        .into_iter()
+        .map(|(vcs, git)| (git, vcs))
+        .collect();
+    let mut converter = Converter::new(&git, &repo, known);
+    // Decided before any branch is written, since writing the branch HEAD
+    // points at would make the repository look populated
+    let fresh = repo.get_head()?.is_none() && repo.get_index()?.is_empty();
+    let branches = git.branches()?;
+    for (name, tip) in &branches {
+        let new_tip = converter.convert_commit(tip)?;
+        fast_forward_branch(&repo, name, &new_tip, &format!("import-git: {}", path))?;
+    }
+    for (name, target) in git.tags()? {
+        crate::repository::validate_tag_name(&name)?;
+        let new_target = converter.convert_object(&target)?;
+        let ref_name = format!("refs/tags/{}", name);
+        match repo.resolve_ref(&ref_name)? {
+            Some(old) if old == new_target => {}
+            Some(_) => println!("Skipped tag '{}': a different tag already exists", name),
+            None => {
+                repo.write_ref(&ref_name, &new_target, &format!("import-git: {}", path))?;
+                println!("Imported tag '{}'", name);
+            }
+        }
+    }
+    let pairs: Vec<(String, String)> = converter
+        .converted
+        .iter()
+        .map(|(git, vcs)| (vcs.clone(), git.clone()))
+        .collect();
+    append_map(&repo, &pairs)?;
+    println!(
+        "Imported {} new commits from {}",
+        pairs.len(),
+        git.path.display()
+    );
+
+    // A fresh repository starts out on the Git repository's current branch
+    if fresh {
+        let branch = git
+            .head_branch()?
+            .filter(|b| branches.contains_key(b))
+            .or_else(|| branches.keys().next().cloned());
+        if let Some(branch) = branch {
+            repo.set_symbolic_head(&format!("refs/heads/{}", branch), "import-git")?;
+            let head = repo.get_head()?;
+            let target = tree_entries_of(&repo, head.as_deref())?;
+            crate::worktree::switch_tree(&repo, &[], &target, false)?;
+            println!("Switched to branch '{}'", branch);
+        }
+    }
+    Ok(())
+}
+
+pub fn export_git(path: &str) -> Result<()> {
+    use crate::gitdir::{append_map, is_ancestor_in, read_map, Converter, GitDir};
+
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let target = Path::new(path);
+    let git = if target.join(".git").exists() || target.join("HEAD").is_file() {
+        GitDir::open(target)?
+    } else {
+        let branch = repo
+            .current_branch()?
+            .unwrap_or_else(|| "master".to_string());
+        GitDir::init(target, &branch)?
+    };
+
+    let mut converter = Converter::new(&repo, &git, read_map(&repo)?);
+    let existing = git.branches()?;
+    for name in repo.list_branches()? {
+        let Some(tip) = repo.resolve_ref(&format!("refs/heads/{}", name))? else {
+            continue;
+        };
+        let new_tip = converter.convert_commit(&tip)?;
+        match existing.get(&name) {
+            Some(old) if *old == new_tip => {}
+            Some(old) if !is_ancestor_in(&git, old, &new_tip)? => {
+                println!("Skipped branch '{}': Git history has diverged", name);
+            }
+            _ => {
+                git.write_branch(&name, &new_tip)?;
+                println!("Exported branch '{}' at {}", name, &new_tip[..8]);
+            }
+        }
+    }
+    let existing = git.tags()?;
+    for (ref_name, target) in repo.list_refs()? {
+        let Some(name) = ref_name.strip_prefix("refs/tags/") else {
+            continue;
+        };
+        let new_target = converter.convert_object(&target)?;
+        match existing.get(name) {
+            Some(old) if *old == new_target => {}
+            Some(_) => println!(
+                "Skipped tag '{}': a different tag already exists in Git",
+                name
+            ),
+            None => {
+                git.write_tag(name, &new_target)?;
+                println!("Exported tag '{}'", name);
+            }
+        }
+    }
+    append_map(&repo, &converter.converted)?;
+    println!(
+        "Exported {} new commits to {}",
+        converter.converted.len(),
+        git.path.display()
+    );
+    Ok(())
+}
+
+pub fn remote_add(name: &str, url: &str) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    crate::repository::validate_branch_name(name)?;
+    if name.contains('/') {
+        anyhow::bail!("Invalid remote name '{}'", name);
+    }
+    let config = Config::new(&repo);
+    if config.remotes()?.contains_key(name) {
+        anyhow::bail!("Remote '{}' already exists", name);
+    }
+    config.set(&format!("remote.{}.url", name), url, false)?;
+    println!("Added remote '{}' ({})", name, url);
+    Ok(())
+}
+
+pub fn remote_remove(name: &str) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let config = Config::new(&repo);
+    if !config.unset(&format!("remote.{}.url", name), false)? {
+        anyhow::bail!("No such remote '{}'", name);
+    }
+    config.unset(&format!("remote.{}.token", name), false)?;
+    let prefix = format!("refs/remotes/{}/", name);
+    for (ref_name, _) in repo.list_refs()? {
+        if ref_name.starts_with(&prefix) {
+            repo.delete_ref(&ref_name)?;
+        }
+    }
+    println!("Removed remote '{}'", name);
+    Ok(())
+}
+
+pub fn remote_list() -> Result<()> {
+    let repo = Repository::get_repo()?;
+    for (name, url) in Config::new(&repo).remotes()? {
+        println!("{}\t{}", name, url);
+    }
+    Ok(())
+}
+
+fn remote_url(repo: &Repository, name: &str) -> Result<String> {
+    Config::new(repo)
+        .remotes()?
+        .remove(name)
+        .with_context(|| format!("No such remote '{}'", name))
+}
+
+/// Token for authenticating to a remote over HTTP: `remote.<name>.token`, else `http.token`.
+fn remote_token(config: &Config, name: &str) -> Result<Option<String>> {
+    match config.get(&format!("remote.{}.token", name))? {
+        Some(token) => Ok(Some(token)),
+        None => config.get("http.token"),
+    }
+}
+
+/// Tips of every local ref, offered to a remote as commits we already have.
+fn local_haves(repo: &Repository) -> Result<Vec<String>> {
+    let mut haves: Vec<String> = repo.list_refs()?.into_iter().map(|(_, h)| h).collect();
+    haves.extend(repo.get_head()?);
+    haves.sort();
+    haves.dedup();
+    Ok(haves)
+}
+
+/// Downloads new objects from a remote and updates its remote-tracking refs.
+fn fetch_remote(repo: &Repository, name: &str) -> Result<crate::remote::Advertisement> {
+    let url = remote_url(repo, name)?;
+    let token = remote_token(&Config::new(repo), name)?;
+    let mut transport =
+        crate::remote::open_transport(&url, crate::remote::Service::Fetch, token.as_deref())?;
+    let advertisement = transport.advertise()?;
+    if advertisement.format != repo.format {
+        anyhow::bail!(
+            "Remote '{}' uses the {} object format but this repository uses {}",
+            name,
+            advertisement.format.as_str(),
+            repo.format.as_str()
+        );
+    }
+
+    let mut wants = Vec::new();
+    for hash in advertisement.branches.values() {
+        if !repo.has_object(hash)? && !wants.contains(hash) {
+            wants.push(hash.clone());
+        }
+    }
+    if !wants.is_empty() {
+        transport.fetch(repo, &wants, &local_haves(repo)?)?;
+    }
+
+    let prefix = format!("refs/remotes/{}/", name);
+    let mut report = Vec::new();
+    for (branch, hash) in &advertisement.branches {
+        let ref_name = format!("{}{}", prefix, branch);
+        match repo.resolve_ref(&ref_name)? {
+            Some(old) if old == *hash => continue,
+            Some(old) => {
+                let forced = !crate::history::is_ancestor(repo, &old, hash)?;
+                report.push(format!(
+                    " {} {}..{}  {} -> {}/{}{}",
+                    if forced { "+" } else { " " },
+                    &old[..8],
+                    &hash[..8],
+                    branch,
+                    name,
+                    branch,
+                    if forced { "  (forced update)" } else { "" }
+                ));
+            }
+            None => report.push(format!(
+                " * [new branch]      {} -> {}/{}",
+                branch, name, branch
+            )),
+        }
+        repo.write_ref(&ref_name, hash, &format!("fetch {}", name))?;
+    }
+    // Branches deleted on the remote
+    for (ref_name, _) in repo.list_refs()? {
+        if let Some(branch) = ref_name.strip_prefix(&prefix) {
+            if !advertisement.branches.contains_key(branch) {
+                repo.delete_ref(&ref_name)?;
+                report.push(format!(" - [deleted]         {}/{}", name, branch));
+            }
+        }
+    }
+
+    if !report.is_empty() {
+        println!("From {}", crate::remote::display_url(&url));
+        for line in report {
+            println!("{}", line);
+        }
+    }
+    Ok(advertisement)
+}
+
+pub fn fetch(remote: Option<String>) -> Result<()> {
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    fetch_remote(&repo, remote.as_deref().unwrap_or("origin"))?;
+    Ok(())
+}
+
+pub fn serve(addr: &str, path: Option<String>) -> Result<()> {
+    let path = match path {
+        Some(path) => std::path::PathBuf::from(path),
+        None => Repository::get_repo()?.worktree,
+    };
+    crate::http::serve(path, addr)
+}
+
+pub fn clone(url: &str, directory: Option<String>) -> Result<()> {
+    let directory = match directory {
+        Some(d) => d,
+        None => url
+            .trim_end_matches('/')
+            .rsplit(['/', '\\', ':'])
+            .next()
+            .filter(|n| !n.is_empty())
+            .context("Cannot derive a directory name from the URL; please specify one")?
+            .to_string(),
+    };
+    let target = std::env::current_dir()?.join(&directory);
+    if target.exists() && target.read_dir()?.next().is_some() {
+        anyhow::bail!(
+            "Destination path '{}' already exists and is not an empty directory",
+            directory
+        );
+    }
+    // Relative paths must keep working from inside the new repository
+    let url = if !url.contains("://") && Path::new(url).exists() {
+        std::fs::canonicalize(url)?.to_string_lossy().into_owned()
+    } else {
+        url.to_string()
+    };
+
+    let token = Config::global_only().get("http.token")?;
+    let format =
+        crate::remote::open_transport(&url, crate::remote::Service::Fetch, token.as_deref())?
+            .advertise()?
+            .format;
+    println!("Cloning into '{}'...", directory);
+    std::fs::create_dir_all(&target)?;
+    let repo = Repository::create_at(target, format)?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    Config::new(&repo).set("remote.origin.url", &url, false)?;
+
+    let advertisement = fetch_remote(&repo, "origin")?;
+    // Fall back to the first branch when the remote's HEAD is not among its branches
+    let branch = advertisement
+        .head
+        .clone()
+        .filter(|head| advertisement.branches.contains_key(head))
+        .or_else(|| advertisement.branches.keys().next().cloned());
+    let Some(branch) = branch else {
+        println!("warning: You appear to have cloned an empty repository.");
+        return Ok(());
+    };
+    let tip = advertisement
+        .branches
+        .get(&branch)
+        .with_context(|| format!("Remote did not advertise branch '{}'", branch))?;
+    let reason = format!("clone: from {}", crate::remote::display_url(&url));
+    repo.write_ref(&format!("refs/heads/{}", branch), tip, &reason)?;
+    repo.set_symbolic_head(&format!("refs/heads/{}", branch), &reason)?;
+    let target_entries = tree_entries_of(&repo, Some(tip))?;
+    crate::worktree::switch_tree(&repo, &[], &target_entries, false)?;
+    println!("Checked out branch '{}'", branch);
+    Ok(())
+}
+
+pub fn push(remote: Option<String>, branch: Option<String>, force: bool) -> Result<()> {
+    use crate::remote::RefUpdate;
+
+    let repo = Repository::get_repo()?;
+    let _lock = crate::lock::RepoLock::new(&repo)?;
+    let name = remote.unwrap_or_else(|| "origin".to_string());
+    let url = remote_url(&repo, &name)?;
+    let branch = match branch {
+        Some(b) => b,
+        None => repo
+            .current_branch()?
+            .context("You are not currently on a branch; specify the branch to push")?,
+    };
+    let new = repo
+        .resolve_ref(&format!("refs/heads/{}", branch))?
+        .with_context(|| format!("Branch '{}' has no commits", branch))?;
+
+    let token = remote_token(&Config::new(&repo), &name)?;
+    let mut transport =
+        crate::remote::open_transport(&url, crate::remote::Service::Push, token.as_deref())?;
+    let advertisement = transport.advertise()?;
+    if advertisement.format != repo.format {
+        anyhow::bail!(
+            "Remote '{}' uses the {} object format but this repository uses {}",
+            name,
+            advertisement.format.as_str(),
+            repo.format.as_str()
+        );
+    }
+    let old = advertisement.branches.get(&branch).cloned();
+    if old.as_deref() == Some(new.as_str()) {
+        println!("Everything up-to-date");
+        return Ok(());
+    }
+    if let Some(old) = &old {
+        let known = repo.has_object(old)? && crate::history::is_ancestor(&repo, old, &new)?;
+        if !force && !known {
+            println!("To {}", crate::remote::display_url(&url));
+            println!(
+                " ! [rejected]        {} -> {} (non-fast-forward)",
+                branch, branch
+            );
+            anyhow::bail!(
+                "Updates were rejected because the remote contains work that you do not have \
+                 locally; pull first or use --force"
+            );
+        }
+    }
+
+    let haves: Vec<String> = advertisement.branches.values().cloned().collect();
+    let updates = [RefUpdate {
+        branch: branch.clone(),
+        new: new.clone(),
+        force,
+    }];
+    let statuses = transport.push(&repo, &updates, &haves)?;
+
+    println!("To {}", crate::remote::display_url(&url));
+    let mut failed = false;
+    for (pushed, status) in statuses {
+        match status {
+            Ok(()) => {
+                let range = match &old {
+                    Some(old) => format!("{}..{}", &old[..8], &new[..8]),
+                    None => "* [new branch]".to_string(),
+                };
+                println!(" {:<18} {} -> {}", range, pushed, pushed);
+                repo.write_ref(
+                    &format!("refs/remotes/{}/{}", name, pushed),
+                    &new,
+                    "update by push",
+                )?;
+            }
+            Err(reason) => {
+                failed = true;
+                println!(" ! [rejected]        {} -> {} ({})", pushed, pushed, reason);
+            }
+        }
+    }
+    if failed {
+        anyhow::bail!("Failed to push some refs to '{}'", url);
+    }
+    Ok(())
+}
+
+pub fn pull(remote: Option<String>, branch: Option<String>) -> Result<()> {
+    let name = remote.unwrap_or_else(|| "origin".to_string());
+    let (tracking, message) = {
+        let repo = Repository::get_repo()?;
+        let _lock = crate::lock::RepoLock::new(&repo)?;
+        fetch_remote(&repo, &name)?;
+        let branch = match branch {
+            Some(b) => b,
+            None => repo
+                .current_branch()?
+                .context("You are not currently on a branch; specify the branch to pull")?,
+        };
+        let tracking = format!("refs/remotes/{}/{}", name, branch);
+        if repo.resolve_ref(&tracking)?.is_none() {
+            anyhow::bail!("Remote '{}' has no branch '{}'", name, branch);
+        }
+        let url = remote_url(&repo, &name)?;
+        (
+            tracking,
+            format!(
+                "Merge branch '{}' of {}",
+                branch,
+                crate::remote::display_url(&url)
+            ),
+        )
+    };
+    merge(Some(tracking), Some(message), false, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Mutex;
+
+    /// Commands find their repository through the current directory, which
+    /// every test thread shares.
+    static CWD: Mutex<()> = Mutex::new(());
+
+    /// Runs `f` in a fresh repository with an identity configured and the
+    /// editor set to leave files as they are.
+    fn in_repo(f: impl FnOnce(&Repository)) {
+        let _guard = CWD.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let previous = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+        std::env::set_var("VCS_EDITOR", "true");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let repo = Repository::create(ObjectFormat::Vcs).unwrap();
+            let config = Config::new(&repo);
+            config.set("user.name", "Test", false).unwrap();
+            config.set("user.email", "test@example.com", false).unwrap();
+            f(&repo)
+        }));
+        std::env::set_current_dir(previous).unwrap();
+        if let Err(panic) = result {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    fn write(repo: &Repository, path: &str, content: &str) {
+        fs::write(repo.worktree.join(path), content).unwrap();
+    }
+
+    fn read(repo: &Repository, path: &str) -> String {
+        fs::read_to_string(repo.worktree.join(path)).unwrap()
+    }
+
+    /// Stages everything and commits it, returning the new HEAD.
+    fn commit_all(repo: &Repository, message: &str) -> String {
+        add(vec![".".to_string()]).unwrap();
+        commit(Some(message.to_string()), None, false, None, None).unwrap();
+        repo.get_head().unwrap().unwrap()
+    }
+
+    fn head_commit(repo: &Repository) -> Commit {
+        crate::history::read_commit(repo, &repo.get_head().unwrap().unwrap()).unwrap()
+    }
+
+    fn stash_count(repo: &Repository) -> usize {
+        crate::reflog::read(repo, STASH_REF).unwrap().len()
+    }
+
+    fn reset_hard(target: &str) {
+        reset(Some(ResetMode::Hard), vec![target.to_string()], Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn stash_push_and_pop_restore_local_changes() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            let head = commit_all(repo, "first");
+            write(repo, "a.txt", "two\n");
+            write(repo, "b.txt", "new\n");
+            add(vec!["b.txt".to_string()]).unwrap();
+
+            stash_push(None).unwrap();
+            assert_eq!(read(repo, "a.txt"), "one\n");
+            assert!(!repo.worktree.join("b.txt").exists());
+            assert_eq!(repo.get_head().unwrap(), Some(head.clone()));
+            assert_eq!(stash_count(repo), 1);
+            let (_, stash) = find_stash(repo, None).unwrap();
+            assert_eq!(
+                crate::history::read_commit(repo, &stash).unwrap().parents[0],
+                head
+            );
+
+            stash_apply(None, true).unwrap();
+            assert_eq!(read(repo, "a.txt"), "two\n");
+            assert_eq!(read(repo, "b.txt"), "new\n");
+            // The new file comes back staged, the edit unstaged
+            let index = repo.get_index().unwrap();
+            assert!(index.iter().any(|e| e.path == "b.txt"));
+            assert_eq!(stash_count(repo), 0);
+        });
+    }
+
+    #[test]
+    fn stash_entries_are_listed_newest_first() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            commit_all(repo, "first");
+            write(repo, "a.txt", "first stash\n");
+            stash_push(Some("older".to_string())).unwrap();
+            write(repo, "a.txt", "second stash\n");
+            stash_push(Some("newer".to_string())).unwrap();
+            assert!(find_stash(repo, Some("stash@{2}")).is_err());
+
+            stash_drop(Some("stash@{0}".to_string())).unwrap();
+            assert_eq!(stash_count(repo), 1);
+            stash_apply(Some("0".to_string()), false).unwrap();
+            assert_eq!(read(repo, "a.txt"), "first stash\n");
+            assert_eq!(stash_count(repo), 1);
+        });
+    }
+
+    #[test]
+    fn stash_apply_with_conflicts_keeps_the_entry() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "base\n");
+            commit_all(repo, "first");
+            write(repo, "a.txt", "stashed\n");
+            stash_push(None).unwrap();
+            write(repo, "a.txt", "upstream\n");
+            commit_all(repo, "second");
+
+            assert!(stash_apply(None, true).is_err());
+            assert_eq!(
+                read(repo, "a.txt"),
+                "<<<<<<< Updated upstream\nupstream\n=======\nstashed\n>>>>>>> Stashed changes\n"
+            );
+            let index = repo.get_index().unwrap();
+            assert!(index.iter().any(|e| e.path == "a.txt" && e.stage > 0));
+            assert_eq!(stash_count(repo), 1);
+            // A second attempt is refused until the conflict is resolved
+            assert!(stash_apply(None, false).is_err());
+        });
+    }
+
+    /// Builds `base` with two children: `theirs`, left on a side commit, and
+    /// `ours`, which HEAD ends up at. Both rewrite `a.txt`.
+    fn diverge(repo: &Repository, ours: &str, theirs: &str) -> (String, String) {
+        write(repo, "a.txt", "base\n");
+        let base = commit_all(repo, "base");
+        write(repo, "a.txt", theirs);
+        let side = commit_all(repo, "theirs");
+        reset_hard(&base);
+        write(repo, "a.txt", ours);
+        let head = commit_all(repo, "ours");
+        (head, side)
+    }
+
+    /// Backdates a loose object past the prune grace period.
+    fn age_object(repo: &Repository, hash: &str) {
+        let old = std::time::SystemTime::now() - crate::repository::PRUNE_GRACE * 2;
+        fs::File::options()
+            .write(true)
+            .open(repo.object_path(hash))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+    }
+
+    #[test]
+    fn gc_prunes_only_unreachable_objects_past_the_grace_period() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            commit_all(repo, "first");
+            let fresh = repo
+                .write_object(&GitObject::Blob(b"fresh".to_vec()))
+                .unwrap();
+            let stale = repo
+                .write_object(&GitObject::Blob(b"stale".to_vec()))
+                .unwrap();
+            age_object(repo, &stale);
+
+            repack().unwrap();
+            assert_eq!(repo.loose_objects().unwrap(), vec![fresh.clone()]);
+
+            let newer = repo
+                .write_object(&GitObject::Blob(b"newer".to_vec()))
+                .unwrap();
+            gc().unwrap();
+            // A fresh handle, as `repo` still has the old packs cached
+            let repo = Repository::get_repo().unwrap();
+            assert!(repo.has_object(&fresh).unwrap());
+            assert!(repo.has_object(&newer).unwrap());
+            assert!(!repo.has_object(&stale).unwrap());
+            assert!(repo.has_object(&repo.get_head().unwrap().unwrap()).unwrap());
+        });
+    }
+
+    #[test]
+    fn git_export_and_import_carry_tags() {
+        use crate::gitdir::ObjectStore;
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            let head = commit_all(repo, "first");
+            tag(Some("light".into()), None, None, false, false, false, false).unwrap();
+            tag(
+                Some("v1".into()),
+                None,
+                Some("release".into()),
+                true,
+                false,
+                false,
+                false,
+            )
+            .unwrap();
+            let annotated = repo.resolve_ref("refs/tags/v1").unwrap().unwrap();
+
+            let git_dir = tempfile::tempdir().unwrap();
+            let git_path = git_dir.path().to_str().unwrap();
+            export_git(git_path).unwrap();
+            let git = crate::gitdir::GitDir::open(git_dir.path()).unwrap();
+            let tags = git.tags().unwrap();
+            assert_eq!(tags.len(), 2);
+            assert!(matches!(git.read(&tags["v1"]).unwrap(), GitObject::Tag(_)));
+
+            repo.delete_ref("refs/tags/light").unwrap();
+            repo.delete_ref("refs/tags/v1").unwrap();
+            import_git(git_path).unwrap();
+            assert_eq!(repo.resolve_ref("refs/tags/light").unwrap(), Some(head));
+            assert_eq!(repo.resolve_ref("refs/tags/v1").unwrap(), Some(annotated));
+
+            // A ref holding something other than a hash is an error, not a panic
+            fs::write(git_dir.path().join(".git/refs/tags/bad"), "x\n").unwrap();
+            assert!(import_git(git_path).is_err());
+        });
+    }
+
+    #[test]
+    fn cherry_pick_applies_a_commit_on_top_of_head() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            let base = commit_all(repo, "base");
+            write(repo, "b.txt", "two\n");
+            let picked = commit_all(repo, "add b");
+            reset_hard(&base);
+            write(repo, "c.txt", "three\n");
+            let head = commit_all(repo, "add c");
+
+            cherry_pick(vec![picked.clone()], false, false).unwrap();
+            let result = head_commit(repo);
+            assert_eq!(result.parents, vec![head]);
+            assert_eq!(
+                result.message,
+                format!("add b\n\n(cherry picked from commit {})", picked)
+            );
+            assert_eq!(read(repo, "b.txt"), "two\n");
+            assert_eq!(read(repo, "c.txt"), "three\n");
+            assert!(!Sequencer::new(repo).in_progress());
+        });
+    }
+
+    #[test]
+    fn cherry_pick_continues_after_a_resolved_conflict() {
+        in_repo(|repo| {
+            let (head, side) = diverge(repo, "ours\n", "theirs\n");
+            assert!(cherry_pick(vec![side.clone()], false, false).is_err());
+            assert!(read(repo, "a.txt").contains("<<<<<<< HEAD\nours\n=======\ntheirs\n"));
+            assert!(Sequencer::new(repo).in_progress());
+            // Nothing new can start, and continuing needs the conflict resolved
+            assert!(cherry_pick(vec![side.clone()], false, false).is_err());
+            assert!(cherry_pick(Vec::new(), true, false).is_err());
+
+            write(repo, "a.txt", "both\n");
+            add(vec!["a.txt".to_string()]).unwrap();
+            cherry_pick(Vec::new(), true, false).unwrap();
+            let result = head_commit(repo);
+            assert_eq!(result.parents, vec![head]);
+            assert!(result.message.starts_with("theirs\n"));
+            assert_eq!(read(repo, "a.txt"), "both\n");
+            assert!(!Sequencer::new(repo).in_progress());
+            assert!(cherry_pick(Vec::new(), true, false).is_err());
+        });
+    }
+
+    #[test]
+    fn cherry_pick_abort_restores_head() {
+        in_repo(|repo| {
+            let (head, side) = diverge(repo, "ours\n", "theirs\n");
+            write(repo, "b.txt", "clean\n");
+            let head_b = commit_all(repo, "add b");
+            // The second pick is never reached
+            assert!(cherry_pick(vec![side.clone(), head.clone()], false, false).is_err());
+
+            cherry_pick(Vec::new(), false, true).unwrap();
+            assert_eq!(repo.get_head().unwrap(), Some(head_b));
+            assert_eq!(repo.current_branch().unwrap().as_deref(), Some("master"));
+            assert_eq!(read(repo, "a.txt"), "ours\n");
+            assert!(repo.get_index().unwrap().iter().all(|e| e.stage == 0));
+            assert!(!Sequencer::new(repo).in_progress());
+        });
+    }
+
+    #[test]
+    fn revert_undoes_a_commit() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            commit_all(repo, "base");
+            write(repo, "a.txt", "two\n");
+            let changed = commit_all(repo, "change a");
+            write(repo, "b.txt", "b\n");
+            commit_all(repo, "add b");
+
+            revert(vec![changed.clone()], false, false).unwrap();
+            assert_eq!(read(repo, "a.txt"), "one\n");
+            assert_eq!(read(repo, "b.txt"), "b\n");
+            assert_eq!(
+                head_commit(repo).message,
+                format!("Revert \"change a\"\n\nThis reverts commit {}.", changed)
+            );
+        });
+    }
+
+    /// Points the editor at a script that replaces a rebase todo list with
+    /// `todo` and leaves other files alone.
+    fn edit_todo_with(repo: &Repository, todo: &str) {
+        let todo_path = repo.vcs_dir.join("test-todo");
+        fs::write(&todo_path, todo).unwrap();
+        let script = repo.vcs_dir.join("test-editor.sh");
+        fs::write(
+            &script,
+            format!(
+                "case \"$1\" in *REBASE_TODO) cp '{}' \"$1\";; esac\n",
+                todo_path.display()
+            ),
+        )
+        .unwrap();
+        std::env::set_var("VCS_EDITOR", format!("sh '{}'", script.display()));
+    }
+
+    #[test]
+    fn rebase_replays_commits_onto_upstream() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "base\n");
+            let base = commit_all(repo, "base");
+            write(repo, "b.txt", "topic\n");
+            let topic = commit_all(repo, "topic");
+            reset_hard(&base);
+            write(repo, "c.txt", "upstream\n");
+            let upstream = commit_all(repo, "upstream");
+            repo.write_ref("refs/heads/master", &topic, "test").unwrap();
+            reset_hard(&topic);
+
+            rebase(Some(upstream.clone()), None, false, false, false, false).unwrap();
+            let result = head_commit(repo);
+            assert_eq!(result.parents, vec![upstream]);
+            assert_eq!(result.message, "topic");
+            assert_eq!(read(repo, "b.txt"), "topic\n");
+            assert_eq!(read(repo, "c.txt"), "upstream\n");
+            assert_eq!(repo.current_branch().unwrap().as_deref(), Some("master"));
+            assert!(!Sequencer::new(repo).in_progress());
+        });
+    }
+
+    #[test]
+    fn interactive_rebase_squashes_fixes_up_and_drops() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "base\n");
+            let base = commit_all(repo, "base");
+            let mut commits = Vec::new();
+            for name in ["one", "two", "three", "four"] {
+                write(repo, &format!("{}.txt", name), name);
+                commits.push(commit_all(repo, name));
+            }
+            edit_todo_with(
+                repo,
+                &format!(
+                    "pick {}\nsquash {}\n# a comment\nfixup {}\ndrop {}\n",
+                    &commits[0][..8],
+                    &commits[1][..8],
+                    &commits[2][..8],
+                    &commits[3][..8]
+                ),
+            );
+
+            rebase(Some(base.clone()), None, true, false, false, false).unwrap();
+            let result = head_commit(repo);
+            assert_eq!(result.parents, vec![base]);
+            assert_eq!(result.message, "one\n\ntwo");
+            for (name, kept) in [
+                ("one", true),
+                ("two", true),
+                ("three", true),
+                ("four", false),
+            ] {
+                assert_eq!(repo.worktree.join(format!("{}.txt", name)).exists(), kept);
+            }
+            assert_eq!(
+                repo.resolve_ref("refs/heads/master").unwrap(),
+                repo.get_head().unwrap()
+            );
+            assert!(!Sequencer::new(repo).in_progress());
+        });
+    }
+
+    #[test]
+    fn commits_need_an_identity_unless_defaults_are_allowed() {
+        in_repo(|repo| {
+            let config = Config::new(repo);
+            config.unset("user.name", false).unwrap();
+            config.unset("user.email", false).unwrap();
+            if config.get_user_name().unwrap().is_some() {
+                // A global identity makes the refusal untestable here.
+                return;
+            }
+            write(repo, "a.txt", "one\n");
+            add(vec![".".to_string()]).unwrap();
+
+            let err = commit(Some("first".into()), None, false, None, None).unwrap_err();
+            assert!(
+                err.to_string().contains("Author identity unknown"),
+                "{}",
+                err
+            );
+            assert!(repo.get_head().unwrap().is_none());
+
+            config
+                .set(crate::config::DEFAULT_IDENTITY_KEY, "true", false)
+                .unwrap();
+            commit(Some("first".into()), None, false, None, None).unwrap();
+            let author = head_commit(repo).author;
+            assert_eq!(author.email, crate::config::default_user_email());
+        });
+    }
+
+    #[test]
+    fn branches_must_point_at_commits() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            let head = commit_all(repo, "first");
+            let tree = head_commit(repo).tree;
+
+            assert!(branch(Some("bad".into()), Some(tree), false, false, false).is_err());
+            assert_eq!(repo.resolve_ref("refs/heads/bad").unwrap(), None);
+            branch(Some("good".into()), None, false, false, false).unwrap();
+            assert_eq!(repo.resolve_ref("refs/heads/good").unwrap(), Some(head));
+        });
+    }
+
+    #[test]
+    fn renames_onto_a_conflicting_path_are_refused() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            let head = commit_all(repo, "first");
+            branch(Some("bad".into()), None, false, false, false).unwrap();
+
+            let err =
+                branch(Some("bad".into()), Some("bad/x".into()), false, false, true).unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("conflicts with an existing branch"));
+            assert_eq!(repo.resolve_ref("refs/heads/bad").unwrap(), Some(head));
+            assert_eq!(
+                crate::reflog::read(repo, "refs/heads/bad").unwrap().len(),
+                1
+            );
+
+            branch(Some("bad".into()), Some("good".into()), false, false, true).unwrap();
+            assert_eq!(repo.resolve_ref("refs/heads/bad").unwrap(), None);
+            assert_eq!(
+                crate::reflog::read(repo, "refs/heads/good").unwrap().len(),
+                2
+            );
+        });
+    }
+
+    #[test]
+    fn failed_branch_checkouts_leave_no_branch_behind() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "one\n");
+            let first = commit_all(repo, "first");
+            write(repo, "a.txt", "two\n");
+            commit_all(repo, "second");
+            let tree = head_commit(repo).tree;
+
+            assert!(checkout(Some(tree.clone()), Some("bad1".into()), false).is_err());
+            assert!(switch(Some(tree), Some("bad2".into()), false, false).is_err());
+
+            write(repo, "a.txt", "local\n");
+            assert!(checkout(Some(first.clone()), Some("bad3".into()), false).is_err());
+            assert!(switch(Some(first), Some("bad4".into()), false, false).is_err());
+
+            for name in ["bad1", "bad2", "bad3", "bad4"] {
+                let ref_name = format!("refs/heads/{}", name);
+                assert_eq!(repo.resolve_ref(&ref_name).unwrap(), None);
+                assert!(crate::reflog::read(repo, &ref_name).unwrap().is_empty());
+            }
+            assert_eq!(repo.current_branch().unwrap().as_deref(), Some("master"));
+            assert_eq!(read(repo, "a.txt"), "local\n");
+        });
+    }
+
+    #[test]
+    fn criss_cross_merges_are_refused() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "base\n");
+            let base = commit_all(repo, "base");
+            write(repo, "a.txt", "ours\n");
+            let ours = commit_all(repo, "ours");
+            reset_hard(&base);
+            write(repo, "b.txt", "theirs\n");
+            let theirs = commit_all(repo, "theirs");
+            merge(Some(ours.clone()), None, false, false).unwrap();
+            let their_merge = repo.get_head().unwrap().unwrap();
+            reset_hard(&ours);
+            merge(Some(theirs), None, false, false).unwrap();
+            let our_merge = repo.get_head().unwrap().unwrap();
+
+            let err = merge(Some(their_merge), None, false, false).unwrap_err();
+            assert!(err.to_string().contains("2 merge bases"), "{}", err);
+            assert_eq!(repo.get_head().unwrap(), Some(our_merge));
+            assert_eq!(repo.merge_head().unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn merge_abort_keeps_unrelated_local_edits() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "base\n");
+            write(repo, "notes.txt", "notes\n");
+            let base = commit_all(repo, "base");
+            write(repo, "a.txt", "theirs\n");
+            write(repo, "new.txt", "new\n");
+            let theirs = commit_all(repo, "theirs");
+            reset_hard(&base);
+            write(repo, "a.txt", "ours\n");
+            let ours = commit_all(repo, "ours");
+
+            assert!(merge(Some(theirs), None, false, false).is_err());
+            assert_eq!(read(repo, "new.txt"), "new\n");
+            write(repo, "notes.txt", "edited while merging\n");
+
+            merge(None, None, false, true).unwrap();
+            assert_eq!(repo.get_head().unwrap(), Some(ours));
+            assert_eq!(read(repo, "a.txt"), "ours\n");
+            assert!(!repo.worktree.join("new.txt").exists());
+            assert_eq!(read(repo, "notes.txt"), "edited while merging\n");
+            assert!(repo.get_index().unwrap().iter().all(|e| e.stage == 0));
+            assert_eq!(repo.merge_head().unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn rebase_stops_on_conflicts_and_aborts() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "base\n");
+            let base = commit_all(repo, "base");
+            write(repo, "a.txt", "upstream\n");
+            let upstream = commit_all(repo, "upstream");
+            reset_hard(&base);
+            write(repo, "a.txt", "topic\n");
+            let topic = commit_all(repo, "topic");
+
+            assert!(rebase(Some(upstream.clone()), None, false, false, false, false).is_err());
+            assert!(Sequencer::new(repo).in_progress());
+            assert_eq!(repo.current_branch().unwrap(), None);
+
+            rebase(None, None, false, false, false, true).unwrap();
+            assert_eq!(repo.current_branch().unwrap().as_deref(), Some("master"));
+            assert_eq!(repo.get_head().unwrap(), Some(topic));
+            assert_eq!(read(repo, "a.txt"), "topic\n");
+            assert!(!Sequencer::new(repo).in_progress());
+        });
+    }
+
+    #[test]
+    fn rebase_skip_drops_the_stopped_commit() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "base\n");
+            let base = commit_all(repo, "base");
+            write(repo, "a.txt", "upstream\n");
+            let upstream = commit_all(repo, "upstream");
+            reset_hard(&base);
+            write(repo, "a.txt", "topic\n");
+            commit_all(repo, "topic");
+            write(repo, "b.txt", "b\n");
+            commit_all(repo, "add b");
+
+            assert!(rebase(Some(upstream.clone()), None, false, false, false, false).is_err());
+            rebase(None, None, false, false, true, false).unwrap();
+            let result = head_commit(repo);
+            assert_eq!(result.parents, vec![upstream]);
+            assert_eq!(result.message, "add b");
+            assert_eq!(read(repo, "a.txt"), "upstream\n");
+            assert_eq!(repo.current_branch().unwrap().as_deref(), Some("master"));
+        });
+    }
+
+    #[test]
+    fn rebase_skip_keeps_unrelated_local_edits() {
+        in_repo(|repo| {
+            write(repo, "a.txt", "base\n");
+            write(repo, "notes.txt", "notes\n");
+            let base = commit_all(repo, "base");
+            write(repo, "a.txt", "upstream\n");
+            let upstream = commit_all(repo, "upstream");
+            reset_hard(&base);
+            write(repo, "a.txt", "topic\n");
+            commit_all(repo, "topic");
+
+            assert!(rebase(Some(upstream.clone()), None, false, false, false, false).is_err());
+            write(repo, "notes.txt", "edited while rebasing\n");
+            rebase(None, None, false, false, true, false).unwrap();
+            assert_eq!(repo.get_head().unwrap(), Some(upstream));
+            assert_eq!(read(repo, "a.txt"), "upstream\n");
+            assert_eq!(read(repo, "notes.txt"), "edited while rebasing\n");
+            assert!(repo.get_index().unwrap().iter().all(|e| e.stage == 0));
+        });
+    }
+}
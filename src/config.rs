@@ -46,6 +46,14 @@ impl Config {
         self.read_config_file(&self.global_config_path, key)
     }
 
+    /// Reads a key from the repository config only, ignoring the global file.
+    pub fn get_local(&self, key: &str) -> Result<Option<String>> {
+        if self.repo_config_path.as_os_str().is_empty() {
+            return Ok(None);
+        }
+        self.read_config_file(&self.repo_config_path, key)
+    }
+
     pub fn set(&self, key: &str, value: &str, global: bool) -> Result<()> {
         let config_path = if global {
             &self.global_config_path
@@ -60,6 +68,38 @@ impl Config {
         Ok(())
     }
 
+    pub fn unset(&self, key: &str, global: bool) -> Result<bool> {
+        let config_path = if global {
+            &self.global_config_path
+        } else {
+            &self.repo_config_path
+        };
+
+        let mut config = self.read_all_config(config_path)?;
+        let removed = config.remove(key).is_some();
+        if removed {
+            self.write_config(config_path, &config)?;
+        }
+        Ok(removed)
+    }
+
+    /// Remotes configured for the repository (`remote.<name>.url`), by name.
+    pub fn remotes(&self) -> Result<std::collections::BTreeMap<String, String>> {
+        let mut remotes = std::collections::BTreeMap::new();
+        if self.repo_config_path.as_os_str().is_empty() {
+            return Ok(remotes);
+        }
+        for (key, value) in self.read_all_config(&self.repo_config_path)? {
+            if let Some(name) = key
+                .strip_prefix("remote.")
+                .and_then(|k| k.strip_suffix(".url"))
+            {
+                remotes.insert(name.to_string(), value);
+            }
+        }
+        Ok(remotes)
+    }
+
     pub fn list(&self) -> Result<HashMap<String, String>> {
         let mut all_config = HashMap::new();
 
@@ -125,17 +165,110 @@ impl Config {
         Ok(())
     }
 
-    pub fn get_user_name(&self) -> String {
+    /// The configured `user.name`, if any.
+    pub fn get_user_name(&self) -> Result<Option<String>> {
         self.get("user.name")
-            .ok()
-            .flatten()
-            .unwrap_or_else(whoami::username)
     }
 
-    pub fn get_user_email(&self) -> String {
+    /// The configured `user.email`, if any.
+    pub fn get_user_email(&self) -> Result<Option<String>> {
         self.get("user.email")
-            .ok()
-            .flatten()
-            .unwrap_or_else(|| "user@example.com".to_string())
+    }
+
+    /// Whether commits may fall back to [`default_user_name`] and
+    /// [`default_user_email`] when no identity is configured.
+    pub fn allows_default_identity(&self) -> Result<bool> {
+        Ok(self.get(DEFAULT_IDENTITY_KEY)?.as_deref() == Some("true"))
+    }
+}
+
+/// Opt-in key letting commits use the login name when no identity is configured.
+pub const DEFAULT_IDENTITY_KEY: &str = "user.allowDefaultIdentity";
+
+/// The login name, used when no `user.name` is configured.
+pub fn default_user_name() -> String {
+    whoami::username()
+}
+
+/// `<login>@localhost`, used when no `user.email` is configured.
+pub fn default_user_email() -> String {
+    format!("{}@localhost", whoami::username())
+}
+
+/// Checks that a value set for `key` is well formed: `user.name` must fit on
+/// a commit header line, `user.email` must look like `name@domain`, and
+/// boolean keys must be `true` or `false`.
+pub fn validate(key: &str, value: &str) -> Result<()> {
+    match key {
+        "user.name" => {
+            if value.trim().is_empty() {
+                anyhow::bail!("user.name must not be empty");
+            }
+            crate::objects::Signature::check_identity_part("name", value)?;
+        }
+        "user.email" => {
+            let valid = match value.split_once('@') {
+                Some((local, domain)) => {
+                    !local.is_empty()
+                        && !domain.is_empty()
+                        && !domain.contains('@')
+                        && !domain.starts_with('.')
+                        && !domain.ends_with('.')
+                        && !value.contains(|c: char| {
+                            c.is_whitespace() || c.is_control() || "<>,;\"".contains(c)
+                        })
+                }
+                None => false,
+            };
+            if !valid {
+                anyhow::bail!("Invalid email address '{}' (expected name@domain)", value);
+            }
+        }
+        DEFAULT_IDENTITY_KEY if value != "true" && value != "false" => {
+            anyhow::bail!("{} must be 'true' or 'false'", key);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_checks_identity_and_boolean_keys() {
+        for email in [
+            "a@example.com",
+            "first.last+tag@mail.example.org",
+            "me@localhost",
+        ] {
+            validate("user.email", email).unwrap();
+        }
+        for email in [
+            "nope",
+            "@example.com",
+            "a@",
+            "a@b@example.com",
+            "a@.example.com",
+            "a@example.",
+            "a b@example.com",
+            "<a@example.com>",
+            "a@exa\nmple.com",
+        ] {
+            assert!(validate("user.email", email).is_err(), "{:?}", email);
+        }
+
+        validate("user.name", "A U Thor").unwrap();
+        assert!(validate("user.name", "").is_err());
+        assert!(validate("user.name", "  ").is_err());
+        assert!(validate("user.name", "A <U> Thor").is_err());
+
+        validate(DEFAULT_IDENTITY_KEY, "true").unwrap();
+        validate(DEFAULT_IDENTITY_KEY, "false").unwrap();
+        assert!(validate(DEFAULT_IDENTITY_KEY, "yes").is_err());
+        assert!(validate(DEFAULT_IDENTITY_KEY, "").is_err());
+
+        validate("core.editor", "").unwrap();
     }
 }
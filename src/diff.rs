@@ -0,0 +1,484 @@
+use colored::*;
+
+/// A single step in an edit script, carrying line indices into the old and new sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Computes a minimal edit script turning `a` into `b` (Myers' O(ND) algorithm,
+/// linear-space variant).
+pub fn diff_slices<T: PartialEq>(a: &[T], b: &[T]) -> Vec<DiffOp> {
+    let mut ops = Vec::with_capacity(a.len().max(b.len()));
+    diff_range(a, b, 0, a.len(), 0, b.len(), &mut ops);
+
+    // Within each run of changes, list deletions before insertions
+    let mut start = 0;
+    while start < ops.len() {
+        if matches!(ops[start], DiffOp::Equal(..)) {
+            start += 1;
+            continue;
+        }
+        let mut end = start;
+        while end < ops.len() && !matches!(ops[end], DiffOp::Equal(..)) {
+            end += 1;
+        }
+        ops[start..end].sort_by_key(|op| matches!(op, DiffOp::Insert(_)));
+        start = end;
+    }
+    ops
+}
+
+fn diff_range<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    mut a_lo: usize,
+    mut a_hi: usize,
+    mut b_lo: usize,
+    mut b_hi: usize,
+    ops: &mut Vec<DiffOp>,
+) {
+    // Common prefix
+    while a_lo < a_hi && b_lo < b_hi && a[a_lo] == b[b_lo] {
+        ops.push(DiffOp::Equal(a_lo, b_lo));
+        a_lo += 1;
+        b_lo += 1;
+    }
+    // Common suffix, emitted after the middle
+    let mut suffix = 0;
+    while a_lo < a_hi && b_lo < b_hi && a[a_hi - 1] == b[b_hi - 1] {
+        a_hi -= 1;
+        b_hi -= 1;
+        suffix += 1;
+    }
+
+    if a_lo == a_hi {
+        ops.extend((b_lo..b_hi).map(DiffOp::Insert));
+    } else if b_lo == b_hi {
+        ops.extend((a_lo..a_hi).map(DiffOp::Delete));
+    } else {
+        match middle_snake(&a[a_lo..a_hi], &b[b_lo..b_hi]) {
+            Some((x, y)) => {
+                diff_range(a, b, a_lo, a_lo + x, b_lo, b_lo + y, ops);
+                diff_range(a, b, a_lo + x, a_hi, b_lo + y, b_hi, ops);
+            }
+            None => {
+                ops.extend((a_lo..a_hi).map(DiffOp::Delete));
+                ops.extend((b_lo..b_hi).map(DiffOp::Insert));
+            }
+        }
+    }
+
+    ops.extend((0..suffix).map(|i| DiffOp::Equal(a_hi + i, b_hi + i)));
+}
+
+/// Finds a point on an optimal edit path by running the search forwards and
+/// backwards until the two frontiers overlap.
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T]) -> Option<(usize, usize)> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d + 1;
+    let len = (2 * max_d + 3) as usize;
+    let mut v1 = vec![-1isize; len];
+    let mut v2 = vec![-1isize; len];
+    v1[(offset + 1) as usize] = 0;
+    v2[(offset + 1) as usize] = 0;
+    let delta = n - m;
+    // With an odd delta the forward pass detects the overlap, otherwise the reverse pass does
+    let front = delta % 2 != 0;
+    let (mut k1_start, mut k1_end, mut k2_start, mut k2_end) = (0, 0, 0, 0);
+
+    for d in 0..max_d {
+        let mut k1 = -d + k1_start;
+        while k1 <= d - k1_end {
+            let k1_off = (offset + k1) as usize;
+            let mut x1 = if k1 == -d || (k1 != d && v1[k1_off - 1] < v1[k1_off + 1]) {
+                v1[k1_off + 1]
+            } else {
+                v1[k1_off - 1] + 1
+            };
+            let mut y1 = x1 - k1;
+            while x1 < n && y1 < m && a[x1 as usize] == b[y1 as usize] {
+                x1 += 1;
+                y1 += 1;
+            }
+            v1[k1_off] = x1;
+            if x1 > n {
+                k1_end += 2;
+            } else if y1 > m {
+                k1_start += 2;
+            } else if front {
+                let k2_off = offset + delta - k1;
+                if k2_off >= 0 && (k2_off as usize) < len && v2[k2_off as usize] != -1 {
+                    let x2 = n - v2[k2_off as usize];
+                    if x1 >= x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+            k1 += 2;
+        }
+
+        let mut k2 = -d + k2_start;
+        while k2 <= d - k2_end {
+            let k2_off = (offset + k2) as usize;
+            let mut x2 = if k2 == -d || (k2 != d && v2[k2_off - 1] < v2[k2_off + 1]) {
+                v2[k2_off + 1]
+            } else {
+                v2[k2_off - 1] + 1
+            };
+            let mut y2 = x2 - k2;
+            while x2 < n && y2 < m && a[(n - x2 - 1) as usize] == b[(m - y2 - 1) as usize] {
+                x2 += 1;
+                y2 += 1;
+            }
+            v2[k2_off] = x2;
+            if x2 > n {
+                k2_end += 2;
+            } else if y2 > m {
+                k2_start += 2;
+            } else if !front {
+                let k1_off = offset + delta - k2;
+                if k1_off >= 0 && (k1_off as usize) < len && v1[k1_off as usize] != -1 {
+                    let x1 = v1[k1_off as usize];
+                    let y1 = offset + x1 - k1_off;
+                    if x1 >= n - x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+            k2 += 2;
+        }
+    }
+    None
+}
+
+/// A contiguous group of changes with surrounding context.
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub ops: Vec<DiffOp>,
+}
+
+/// Groups an edit script into hunks with `context` unchanged lines around each change.
+pub fn hunks(ops: &[DiffOp], context: usize) -> Vec<Hunk> {
+    let is_change = |op: &DiffOp| !matches!(op, DiffOp::Equal(..));
+
+    // Old/new line counts consumed before each op
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for op in ops {
+        positions.push((old_pos, new_pos));
+        match op {
+            DiffOp::Equal(..) => {
+                old_pos += 1;
+                new_pos += 1;
+            }
+            DiffOp::Delete(_) => old_pos += 1,
+            DiffOp::Insert(_) => new_pos += 1,
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < ops.len() {
+        if !is_change(&ops[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i.saturating_sub(context);
+        let mut end = i;
+        loop {
+            let mut next = end + 1;
+            while next < ops.len() && !is_change(&ops[next]) {
+                next += 1;
+            }
+            if next < ops.len() && next - end - 1 <= 2 * context {
+                end = next;
+            } else {
+                break;
+            }
+        }
+        let stop = (end + context + 1).min(ops.len());
+
+        let slice = &ops[start..stop];
+        let old_len = slice
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Insert(_)))
+            .count();
+        let new_len = slice
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Delete(_)))
+            .count();
+        let (old_before, new_before) = positions[start];
+        result.push(Hunk {
+            // An empty range is reported at the line before it, as diff(1) does
+            old_start: if old_len == 0 {
+                old_before
+            } else {
+                old_before + 1
+            },
+            old_len,
+            new_start: if new_len == 0 {
+                new_before
+            } else {
+                new_before + 1
+            },
+            new_len,
+            ops: slice.to_vec(),
+        });
+        i = stop;
+    }
+    result
+}
+
+/// Heuristic used throughout: content containing a null byte is treated as binary.
+pub fn is_binary(data: &[u8]) -> bool {
+    data.contains(&0)
+}
+
+/// One side of a file comparison; `None` content means the file does not exist on that side.
+pub struct FileVersion<'a> {
+    pub content: Option<&'a [u8]>,
+    pub hash: &'a str,
+    pub mode: &'a str,
+}
+
+/// Prints a unified diff for `path` between two versions of a file.
+pub fn print_file_diff(path: &str, old: &FileVersion, new: &FileVersion, context: usize) {
+    println!("{}", format!("diff --vcs a/{} b/{}", path, path).bold());
+    match (old.content.is_some(), new.content.is_some()) {
+        (false, true) => println!("{}", format!("new file mode {}", new.mode).bold()),
+        (true, false) => println!("{}", format!("deleted file mode {}", old.mode).bold()),
+        _ if old.mode != new.mode => {
+            println!("{}", format!("old mode {}", old.mode).bold());
+            println!("{}", format!("new mode {}", new.mode).bold());
+        }
+        _ => {}
+    }
+    let short = |h: &str| h[..h.len().min(7)].to_string();
+    let null_hash = "0000000";
+    println!(
+        "{}",
+        format!(
+            "index {}..{}",
+            if old.content.is_some() {
+                short(old.hash)
+            } else {
+                null_hash.to_string()
+            },
+            if new.content.is_some() {
+                short(new.hash)
+            } else {
+                null_hash.to_string()
+            },
+        )
+        .bold()
+    );
+
+    let old_data = old.content.unwrap_or_default();
+    let new_data = new.content.unwrap_or_default();
+    let old_name = if old.content.is_some() {
+        format!("a/{}", path)
+    } else {
+        "/dev/null".to_string()
+    };
+    let new_name = if new.content.is_some() {
+        format!("b/{}", path)
+    } else {
+        "/dev/null".to_string()
+    };
+
+    if is_binary(old_data) || is_binary(new_data) {
+        println!("Binary files {} and {} differ", old_name, new_name);
+        return;
+    }
+    if old_data == new_data {
+        return;
+    }
+
+    println!("{}", format!("--- {}", old_name).bold());
+    println!("{}", format!("+++ {}", new_name).bold());
+
+    let old_text = String::from_utf8_lossy(old_data);
+    let new_text = String::from_utf8_lossy(new_data);
+    let old_lines: Vec<&str> = old_text.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new_text.split_inclusive('\n').collect();
+    let ops = diff_slices(&old_lines, &new_lines);
+
+    for hunk in hunks(&ops, context) {
+        let range = |start: usize, len: usize| {
+            if len == 1 {
+                start.to_string()
+            } else {
+                format!("{},{}", start, len)
+            }
+        };
+        println!(
+            "{}",
+            format!(
+                "@@ -{} +{} @@",
+                range(hunk.old_start, hunk.old_len),
+                range(hunk.new_start, hunk.new_len)
+            )
+            .cyan()
+        );
+        for op in &hunk.ops {
+            let (prefix, line) = match *op {
+                DiffOp::Equal(i, _) => (' ', old_lines[i]),
+                DiffOp::Delete(i) => ('-', old_lines[i]),
+                DiffOp::Insert(j) => ('+', new_lines[j]),
+            };
+            let text = format!("{}{}", prefix, line.strip_suffix('\n').unwrap_or(line));
+            match prefix {
+                '-' => println!("{}", text.red()),
+                '+' => println!("{}", text.green()),
+                _ => println!("{}", text),
+            }
+            if !line.ends_with('\n') {
+                println!("\\ No newline at end of file");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rebuilds `b` from an edit script, checking that it walks both sequences in order.
+    fn apply<T: PartialEq + Clone + std::fmt::Debug>(a: &[T], b: &[T], ops: &[DiffOp]) -> Vec<T> {
+        let (mut i, mut j) = (0, 0);
+        let mut out = Vec::new();
+        for op in ops {
+            match *op {
+                DiffOp::Equal(x, y) => {
+                    assert_eq!((x, y), (i, j));
+                    assert_eq!(a[x], b[y]);
+                    out.push(b[y].clone());
+                    i += 1;
+                    j += 1;
+                }
+                DiffOp::Delete(x) => {
+                    assert_eq!(x, i);
+                    i += 1;
+                }
+                DiffOp::Insert(y) => {
+                    assert_eq!(y, j);
+                    out.push(b[y].clone());
+                    j += 1;
+                }
+            }
+        }
+        assert_eq!((i, j), (a.len(), b.len()));
+        out
+    }
+
+    fn lcs_len<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+        let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                table[i][j] = if a[i] == b[j] {
+                    table[i + 1][j + 1] + 1
+                } else {
+                    table[i + 1][j].max(table[i][j + 1])
+                };
+            }
+        }
+        table[0][0]
+    }
+
+    fn edits(ops: &[DiffOp]) -> usize {
+        ops.iter()
+            .filter(|op| !matches!(op, DiffOp::Equal(..)))
+            .count()
+    }
+
+    #[test]
+    fn empty_inputs() {
+        assert!(diff_slices::<u8>(&[], &[]).is_empty());
+        assert_eq!(
+            diff_slices(&[1, 2], &[]),
+            vec![DiffOp::Delete(0), DiffOp::Delete(1)]
+        );
+        assert_eq!(
+            diff_slices(&[], &[1, 2]),
+            vec![DiffOp::Insert(0), DiffOp::Insert(1)]
+        );
+    }
+
+    #[test]
+    fn identical_inputs_are_all_equal() {
+        let a = ["x", "y", "z"];
+        assert_eq!(
+            diff_slices(&a, &a),
+            vec![
+                DiffOp::Equal(0, 0),
+                DiffOp::Equal(1, 1),
+                DiffOp::Equal(2, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn deletions_come_before_insertions() {
+        let ops = diff_slices(&["a", "b", "c"], &["a", "x", "c"]);
+        assert_eq!(
+            ops,
+            vec![
+                DiffOp::Equal(0, 0),
+                DiffOp::Delete(1),
+                DiffOp::Insert(1),
+                DiffOp::Equal(2, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn scripts_are_valid_and_minimal() {
+        // Small alphabet so that the sequences share plenty of lines
+        let mut seed = 0x2545_f491_u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for _ in 0..500 {
+            let a: Vec<u32> = (0..next() % 20).map(|_| next() % 4).collect();
+            let b: Vec<u32> = (0..next() % 20).map(|_| next() % 4).collect();
+            let ops = diff_slices(&a, &b);
+            assert_eq!(apply(&a, &b, &ops), b);
+            assert_eq!(edits(&ops), a.len() + b.len() - 2 * lcs_len(&a, &b));
+        }
+    }
+
+    #[test]
+    fn hunks_merge_nearby_changes_and_count_lines() {
+        let a: Vec<u32> = (1..=20).collect();
+        let mut b = a.clone();
+        b[2] = 100; // line 3
+        b[5] = 101; // line 6, within 2 * context of line 3
+        b.remove(17); // line 18, far away
+        let found = hunks(&diff_slices(&a, &b), 3);
+        assert_eq!(found.len(), 2);
+        let spans: Vec<_> = found
+            .iter()
+            .map(|h| (h.old_start, h.old_len, h.new_start, h.new_len))
+            .collect();
+        assert_eq!(spans, vec![(1, 9, 1, 9), (15, 6, 15, 5)]);
+    }
+
+    #[test]
+    fn hunk_for_insertion_into_empty_file_starts_at_zero() {
+        let found = hunks(&diff_slices(&[], &["a"]), 3);
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].old_start, found[0].old_len), (0, 0));
+        assert_eq!((found[0].new_start, found[0].new_len), (1, 1));
+    }
+}
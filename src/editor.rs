@@ -0,0 +1,62 @@
+use crate::config::Config;
+use crate::repository::Repository;
+use anyhow::{Context, Result};
+use std::fs;
+use std::process::Command;
+
+/// The editor to launch: `$VCS_EDITOR`, then `core.editor`, then `$EDITOR`,
+/// falling back to `vi` (`notepad` on Windows).
+pub fn editor_command(repo: &Repository) -> Result<String> {
+    if let Ok(editor) = std::env::var("VCS_EDITOR") {
+        return Ok(editor);
+    }
+    if let Some(editor) = Config::new(repo).get("core.editor")? {
+        return Ok(editor);
+    }
+    if let Ok(editor) = std::env::var("EDITOR") {
+        return Ok(editor);
+    }
+    Ok(if cfg!(windows) { "notepad" } else { "vi" }.to_string())
+}
+
+/// Writes `initial` to `.vcs/<file_name>`, opens it in the editor and returns
+/// the saved text with `#` comment lines and trailing blank lines removed.
+pub fn edit_text(repo: &Repository, file_name: &str, initial: &str) -> Result<String> {
+    let path = repo.vcs_dir.join(file_name);
+    fs::write(&path, initial).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    let editor = editor_command(repo)?;
+    // The editor setting may carry arguments (e.g. "code --wait")
+    let status = if cfg!(windows) {
+        Command::new("cmd")
+            .arg("/C")
+            .arg(format!("{} \"{}\"", editor, path.display()))
+            .status()
+    } else {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", editor))
+            .arg(&editor)
+            .arg(&path)
+            .status()
+    }
+    .with_context(|| format!("Failed to run editor '{}'", editor))?;
+    if !status.success() {
+        anyhow::bail!("Editor '{}' exited with {}", editor, status);
+    }
+
+    let text =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut lines: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(str::trim_end)
+        .collect();
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    while lines.first().is_some_and(|l| l.is_empty()) {
+        lines.remove(0);
+    }
+    Ok(lines.join("\n"))
+}
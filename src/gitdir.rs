@@ -0,0 +1,469 @@
+use crate::objects::{sort_tree_entries, GitObject, ObjectFormat, TreeEntry};
+use crate::pack::PackFile;
+use crate::repository::Repository;
+use crate::utils;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// File in `.vcs` pairing each converted commit with its Git counterpart (`<vcs> <git>` per line).
+const MAP_FILE: &str = "git-map";
+
+/// Anything objects can be copied between: a `.vcs` repository or a `.git` directory.
+pub trait ObjectStore {
+    fn read_raw(&self, hash: &str) -> Result<Vec<u8>>;
+    fn has(&self, hash: &str) -> Result<bool>;
+    fn write_raw(&self, data: &[u8]) -> Result<String>;
+    fn format(&self) -> ObjectFormat;
+
+    fn read(&self, hash: &str) -> Result<GitObject> {
+        GitObject::deserialize(&self.read_raw(hash)?)
+    }
+}
+
+impl ObjectStore for Repository {
+    fn read_raw(&self, hash: &str) -> Result<Vec<u8>> {
+        self.read_raw_object(hash)
+    }
+
+    fn has(&self, hash: &str) -> Result<bool> {
+        self.has_object(hash)
+    }
+
+    fn write_raw(&self, data: &[u8]) -> Result<String> {
+        self.write_raw_object(data)
+    }
+
+    fn format(&self) -> ObjectFormat {
+        self.format
+    }
+}
+
+/// A Git repository's `.git` directory: loose objects, packs and refs.
+pub struct GitDir {
+    pub path: PathBuf,
+    packs: Vec<PackFile>,
+}
+
+impl GitDir {
+    /// Opens a Git repository given its worktree or its (possibly bare) git directory.
+    pub fn open(path: &Path) -> Result<Self> {
+        let dot_git = path.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            // Linked worktrees and submodules point at their git directory
+            let content = fs::read_to_string(&dot_git)?;
+            let target = content
+                .trim()
+                .strip_prefix("gitdir: ")
+                .context("Invalid .git file")?;
+            path.join(target)
+        } else if path.join("HEAD").is_file() && path.join("objects").is_dir() {
+            path.to_path_buf()
+        } else {
+            anyhow::bail!("Not a Git repository: {}", path.display());
+        };
+        let packs = PackFile::load_dir(&git_dir.join("objects").join("pack"))?;
+        Ok(GitDir {
+            path: git_dir,
+            packs,
+        })
+    }
+
+    /// Creates an empty Git repository at `path/.git` whose HEAD points at `branch`.
+    pub fn init(path: &Path, branch: &str) -> Result<Self> {
+        let git_dir = path.join(".git");
+        fs::create_dir_all(git_dir.join("objects").join("pack"))?;
+        fs::create_dir_all(git_dir.join("refs").join("heads"))?;
+        fs::create_dir_all(git_dir.join("refs").join("tags"))?;
+        fs::write(
+            git_dir.join("HEAD"),
+            format!("ref: refs/heads/{}\n", branch),
+        )?;
+        fs::write(
+            git_dir.join("config"),
+            "[core]\n\trepositoryformatversion = 0\n\tfilemode = true\n\tbare = false\n",
+        )?;
+        GitDir::open(path)
+    }
+
+    /// Where a loose object would be stored; refs read from the Git repository
+    /// are not trusted to hold a well-formed hash.
+    fn object_path(&self, hash: &str) -> Result<PathBuf> {
+        if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid object hash '{}' in {}", hash, self.path.display());
+        }
+        Ok(self.path.join("objects").join(&hash[..2]).join(&hash[2..]))
+    }
+
+    /// Branches under `refs/heads`, from loose ref files and `packed-refs`.
+    pub fn branches(&self) -> Result<BTreeMap<String, String>> {
+        self.refs("heads")
+    }
+
+    /// Tags under `refs/tags`, from loose ref files and `packed-refs`.
+    pub fn tags(&self) -> Result<BTreeMap<String, String>> {
+        self.refs("tags")
+    }
+
+    /// Refs under `refs/<kind>`, keyed by their name below it.
+    fn refs(&self, kind: &str) -> Result<BTreeMap<String, String>> {
+        let mut refs = BTreeMap::new();
+        let prefix = format!("refs/{}/", kind);
+        let packed = self.path.join("packed-refs");
+        if packed.is_file() {
+            for line in fs::read_to_string(&packed)?.lines() {
+                // Skip the header and `^<hash>` lines that peel annotated tags
+                if line.starts_with('#') || line.starts_with('^') {
+                    continue;
+                }
+                if let Some((hash, name)) = line.split_once(' ') {
+                    if let Some(short) = name.strip_prefix(&prefix) {
+                        refs.insert(short.to_string(), hash.to_string());
+                    }
+                }
+            }
+        }
+        let dir = self.path.join("refs").join(kind);
+        if dir.is_dir() {
+            for entry in walkdir::WalkDir::new(&dir) {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    let name = entry
+                        .path()
+                        .strip_prefix(&dir)?
+                        .to_string_lossy()
+                        .replace('\\', "/");
+                    let hash = fs::read_to_string(entry.path())?.trim().to_string();
+                    refs.insert(name, hash);
+                }
+            }
+        }
+        Ok(refs)
+    }
+
+    /// The branch HEAD points at, if it is symbolic.
+    pub fn head_branch(&self) -> Result<Option<String>> {
+        let head = fs::read_to_string(self.path.join("HEAD"))?;
+        Ok(head
+            .trim()
+            .strip_prefix("ref: refs/heads/")
+            .map(|b| b.to_string()))
+    }
+
+    pub fn write_branch(&self, name: &str, hash: &str) -> Result<()> {
+        self.write_ref("heads", name, hash)
+    }
+
+    pub fn write_tag(&self, name: &str, hash: &str) -> Result<()> {
+        self.write_ref("tags", name, hash)
+    }
+
+    fn write_ref(&self, kind: &str, name: &str, hash: &str) -> Result<()> {
+        let ref_file = self.path.join("refs").join(kind).join(name);
+        if let Some(parent) = ref_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&ref_file, format!("{}\n", hash).as_bytes())
+    }
+}
+
+impl ObjectStore for GitDir {
+    fn read_raw(&self, hash: &str) -> Result<Vec<u8>> {
+        if let Ok(data) = fs::read(self.object_path(hash)?) {
+            return utils::decompress(&data).with_context(|| format!("Object {} is corrupt", hash));
+        }
+        for pack_file in &self.packs {
+            if let Some(offset) = pack_file.find(hash) {
+                let (obj_type, payload) = pack_file.read_at(offset, &|base| self.read_raw(base))?;
+                return Ok(crate::pack::join_raw(obj_type, &payload));
+            }
+        }
+        anyhow::bail!("Object {} not found in {}", hash, self.path.display())
+    }
+
+    fn has(&self, hash: &str) -> Result<bool> {
+        Ok(self.object_path(hash)?.is_file() || self.packs.iter().any(|p| p.find(hash).is_some()))
+    }
+
+    fn write_raw(&self, data: &[u8]) -> Result<String> {
+        let hash = utils::hash_object(data);
+        let path = self.object_path(&hash)?;
+        if !path.exists() {
+            let parent = path.parent().context("Object path has no parent")?;
+            fs::create_dir_all(parent)?;
+            write_atomic(&path, &utils::compress(data)?)?;
+        }
+        Ok(hash)
+    }
+
+    fn format(&self) -> ObjectFormat {
+        ObjectFormat::Git
+    }
+}
+
+fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let parent = path.parent().context("Path has no parent")?;
+    let mut temp = tempfile::NamedTempFile::new_in(parent)?;
+    temp.write_all(content)?;
+    temp.flush()?;
+    temp.persist(path)
+        .with_context(|| format!("Failed to persist {}", path.display()))?;
+    Ok(())
+}
+
+/// Mode Git expects for an entry: permission bits collapse to `100644`/`100755`.
+fn git_mode(mode: &str) -> String {
+    match mode {
+        "40000" | "120000" | "160000" | "100644" | "100755" => mode.to_string(),
+        _ => {
+            let bits = u32::from_str_radix(mode, 8).unwrap_or(0o100644);
+            if bits & 0o111 != 0 {
+                "100755".to_string()
+            } else {
+                "100644".to_string()
+            }
+        }
+    }
+}
+
+/// Copies commit graphs between object stores, rewriting trees and commits
+/// when the two stores use different object formats.
+pub struct Converter<'a> {
+    src: &'a dyn ObjectStore,
+    dst: &'a dyn ObjectStore,
+    /// Source commit hash to destination commit hash.
+    pub commits: HashMap<String, String>,
+    /// Possible destination hashes from the mapping file, one per repository converted to.
+    candidates: HashMap<String, Vec<String>>,
+    /// Mapped commits confirmed to exist in the destination.
+    present: HashSet<String>,
+    /// Source tree or blob hash to destination hash.
+    objects: HashMap<String, String>,
+    /// Commits converted during this run, in the order they were written.
+    pub converted: Vec<(String, String)>,
+}
+
+impl<'a> Converter<'a> {
+    pub fn new(
+        src: &'a dyn ObjectStore,
+        dst: &'a dyn ObjectStore,
+        known: Vec<(String, String)>,
+    ) -> Self {
+        let mut candidates: HashMap<String, Vec<String>> = HashMap::new();
+        for (from, to) in known {
+            candidates.entry(from).or_default().push(to);
+        }
+        Converter {
+            src,
+            dst,
+            commits: HashMap::new(),
+            candidates,
+            present: HashSet::new(),
+            objects: HashMap::new(),
+            converted: Vec::new(),
+        }
+    }
+
+    fn same_format(&self) -> bool {
+        self.src.format() == self.dst.format()
+    }
+
+    /// Converts `tip` and all of its ancestors, returning the destination hash of `tip`.
+    pub fn convert_commit(&mut self, tip: &str) -> Result<String> {
+        // Parents are written before children so a present commit implies a complete history
+        let mut stack = vec![(tip.to_string(), false)];
+        while let Some((hash, parents_done)) = stack.pop() {
+            if self.is_done(&hash)? {
+                continue;
+            }
+            let GitObject::Commit(mut commit) = self.src.read(&hash)? else {
+                anyhow::bail!("{} is not a commit", hash);
+            };
+            if !parents_done {
+                stack.push((hash, true));
+                for parent in &commit.parents {
+                    if !self.is_done(parent)? {
+                        stack.push((parent.clone(), false));
+                    }
+                }
+                continue;
+            }
+
+            let new_hash = if self.same_format() {
+                self.convert_tree(&commit.tree)?;
+                self.dst.write_raw(&self.src.read_raw(&hash)?)?
+            } else {
+                commit.tree = self.convert_tree(&commit.tree)?;
+                commit.parents = commit
+                    .parents
+                    .iter()
+                    .map(|p| self.commits[p].clone())
+                    .collect();
+                let data = GitObject::Commit(commit).serialize()?;
+                self.dst.write_raw(&data)?
+            };
+            self.converted.push((hash.clone(), new_hash.clone()));
+            self.present.insert(hash.clone());
+            self.commits.insert(hash, new_hash);
+        }
+        Ok(self.commits[tip].clone())
+    }
+
+    /// Converts whatever `hash` names, following a tag to the object it tags,
+    /// and returns the destination hash.
+    pub fn convert_object(&mut self, hash: &str) -> Result<String> {
+        let mut tag = match self.src.read(hash)? {
+            GitObject::Commit(_) => return self.convert_commit(hash),
+            GitObject::Tree(_) => return self.convert_tree(hash),
+            GitObject::Blob(_) => return self.convert_blob(hash, "100644"),
+            GitObject::Tag(tag) => tag,
+        };
+        if let Some(done) = self.objects.get(hash) {
+            return Ok(done.clone());
+        }
+        let target = self.convert_object(&tag.object)?;
+        let new_hash = if self.same_format() {
+            self.dst.write_raw(&self.src.read_raw(hash)?)?
+        } else {
+            tag.object = target;
+            self.dst.write_raw(&GitObject::Tag(tag).serialize()?)?
+        };
+        self.objects.insert(hash.to_string(), new_hash.clone());
+        Ok(new_hash)
+    }
+
+    /// Whether `hash` already has a counterpart in the destination. The mapping
+    /// file also names commits converted for other repositories, so a candidate
+    /// is only trusted once the destination is seen to hold it.
+    fn is_done(&mut self, hash: &str) -> Result<bool> {
+        if self.present.contains(hash) {
+            return Ok(true);
+        }
+        let mut candidates = self.candidates.remove(hash).unwrap_or_default();
+        if self.same_format() {
+            candidates.push(hash.to_string());
+        }
+        for candidate in candidates {
+            if self.dst.has(&candidate)? {
+                self.commits.insert(hash.to_string(), candidate);
+                self.present.insert(hash.to_string());
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn convert_blob(&mut self, hash: &str, mode: &str) -> Result<String> {
+        if let Some(done) = self.objects.get(hash) {
+            return Ok(done.clone());
+        }
+        let new_hash =
+            if self.dst.format() == ObjectFormat::Vcs && !self.same_format() && mode != "120000" {
+                // The original format stores text with normalized line endings, as `add` would
+                let GitObject::Blob(data) = self.src.read(hash)? else {
+                    anyhow::bail!("{} is not a blob", hash);
+                };
+                let data = crate::worktree::normalize_line_endings(data);
+                self.dst.write_raw(&GitObject::Blob(data).serialize()?)?
+            } else if self.dst.has(hash)? {
+                hash.to_string()
+            } else {
+                self.dst.write_raw(&self.src.read_raw(hash)?)?
+            };
+        self.objects.insert(hash.to_string(), new_hash.clone());
+        Ok(new_hash)
+    }
+
+    fn convert_tree(&mut self, hash: &str) -> Result<String> {
+        if let Some(done) = self.objects.get(hash) {
+            return Ok(done.clone());
+        }
+        if self.same_format() && self.dst.has(hash)? {
+            return Ok(hash.to_string());
+        }
+        let GitObject::Tree(entries) = self.src.read(hash)? else {
+            anyhow::bail!("{} is not a tree", hash);
+        };
+
+        let mut converted = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let new_hash = match entry.mode.as_str() {
+                "40000" => self.convert_tree(&entry.hash)?,
+                // Submodule commits live in another repository
+                "160000" => entry.hash.clone(),
+                _ => self.convert_blob(&entry.hash, &entry.mode)?,
+            };
+            let mode = match self.dst.format() {
+                ObjectFormat::Git => git_mode(&entry.mode),
+                ObjectFormat::Vcs => entry.mode.clone(),
+            };
+            converted.push(TreeEntry {
+                mode,
+                name: entry.name,
+                hash: new_hash,
+            });
+        }
+
+        let new_hash = if self.same_format() {
+            self.dst.write_raw(&self.src.read_raw(hash)?)?
+        } else {
+            sort_tree_entries(&mut converted, self.dst.format());
+            let data = GitObject::Tree(converted).serialize()?;
+            self.dst.write_raw(&data)?
+        };
+        self.objects.insert(hash.to_string(), new_hash.clone());
+        Ok(new_hash)
+    }
+}
+
+/// Whether `ancestor` is reachable from `descendant` in `store`.
+pub fn is_ancestor_in(store: &dyn ObjectStore, ancestor: &str, descendant: &str) -> Result<bool> {
+    let mut queue = VecDeque::from([descendant.to_string()]);
+    let mut seen = HashSet::new();
+    while let Some(hash) = queue.pop_front() {
+        if hash == ancestor {
+            return Ok(true);
+        }
+        if !seen.insert(hash.clone()) {
+            continue;
+        }
+        if let GitObject::Commit(commit) = store.read(&hash)? {
+            queue.extend(commit.parents);
+        }
+    }
+    Ok(false)
+}
+
+/// Reads the commit mapping as `(vcs, git)` pairs.
+pub fn read_map(repo: &Repository) -> Result<Vec<(String, String)>> {
+    let path = repo.vcs_dir.join(MAP_FILE);
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let mut pairs = Vec::new();
+    for line in fs::read_to_string(&path)?.lines() {
+        if let Some((vcs, git)) = line.split_once(' ') {
+            pairs.push((vcs.to_string(), git.to_string()));
+        }
+    }
+    Ok(pairs)
+}
+
+/// Appends `(vcs, git)` pairs to the commit mapping.
+pub fn append_map(repo: &Repository, pairs: &[(String, String)]) -> Result<()> {
+    if pairs.is_empty() {
+        return Ok(());
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(repo.vcs_dir.join(MAP_FILE))?;
+    for (vcs, git) in pairs {
+        writeln!(file, "{} {}", vcs, git)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,419 @@
+use crate::objects::{Commit, GitObject};
+use crate::repository::Repository;
+use anyhow::Result;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Walks the commit graph from a set of starting points, newest commit first.
+///
+/// Every commit is yielded once even when it is reachable through several
+/// parents, so merge histories are traversed as a DAG.
+pub struct RevWalk<'a> {
+    repo: &'a Repository,
+    /// Ordered by commit time, then by discovery order for commits made in the same second.
+    queue: BinaryHeap<(i64, Reverse<u64>, String)>,
+    /// Commits read when queued, handed out when popped.
+    queued: HashMap<String, Commit>,
+    seen: HashSet<String>,
+    discovered: u64,
+}
+
+impl<'a> RevWalk<'a> {
+    pub fn new(repo: &'a Repository, starts: &[String]) -> Result<Self> {
+        let mut walk = RevWalk {
+            repo,
+            queue: BinaryHeap::new(),
+            queued: HashMap::new(),
+            seen: HashSet::new(),
+            discovered: 0,
+        };
+        for start in starts {
+            walk.push(start)?;
+        }
+        Ok(walk)
+    }
+
+    fn push(&mut self, hash: &str) -> Result<()> {
+        if self.seen.insert(hash.to_string()) {
+            let commit = read_commit(self.repo, hash)?;
+            self.discovered += 1;
+            self.queue.push((
+                commit.committer.time,
+                Reverse(self.discovered),
+                hash.to_string(),
+            ));
+            self.queued.insert(hash.to_string(), commit);
+        }
+        Ok(())
+    }
+
+    fn next_commit(&mut self) -> Result<Option<(String, Commit)>> {
+        let Some((_, _, hash)) = self.queue.pop() else {
+            return Ok(None);
+        };
+        let commit = self
+            .queued
+            .remove(&hash)
+            .expect("queued commits are kept until popped");
+        for parent in &commit.parents {
+            self.push(parent)?;
+        }
+        Ok(Some((hash, commit)))
+    }
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<(String, Commit)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_commit().transpose()
+    }
+}
+
+pub fn read_commit(repo: &Repository, hash: &str) -> Result<Commit> {
+    match repo.read_object(hash)? {
+        GitObject::Commit(commit) => Ok(commit),
+        _ => anyhow::bail!("{} is not a commit", hash),
+    }
+}
+
+/// Returns every commit reachable from `starts`, including the starts themselves.
+pub fn reachable(repo: &Repository, starts: &[String]) -> Result<HashSet<String>> {
+    RevWalk::new(repo, starts)?
+        .map(|item| item.map(|(hash, _)| hash))
+        .collect()
+}
+
+/// Whether `ancestor` is reachable from `descendant`.
+pub fn is_ancestor(repo: &Repository, ancestor: &str, descendant: &str) -> Result<bool> {
+    if ancestor == descendant {
+        return Ok(true);
+    }
+    Ok(paint_down_to_common(repo, ancestor, descendant)?
+        .iter()
+        .any(|hash| hash == ancestor))
+}
+
+/// Finds the best common ancestors of two commits: common ancestors that are
+/// not themselves ancestors of another common ancestor. Most recent first.
+pub fn merge_bases(repo: &Repository, a: &str, b: &str) -> Result<Vec<String>> {
+    if a == b {
+        return Ok(vec![a.to_string()]);
+    }
+    let common = paint_down_to_common(repo, a, b)?;
+    if common.len() < 2 {
+        return Ok(common);
+    }
+    let mut bases = Vec::new();
+    for (i, candidate) in common.iter().enumerate() {
+        let mut redundant = false;
+        for (j, other) in common.iter().enumerate() {
+            if i != j && is_ancestor(repo, candidate, other)? {
+                redundant = true;
+                break;
+            }
+        }
+        if !redundant {
+            bases.push(candidate.clone());
+        }
+    }
+    Ok(bases)
+}
+
+/// Walks back from `a` and `b` together, newest first, marking which side
+/// reaches each commit. A commit reached from both is a common ancestor and
+/// everything below it is stale; the walk stops as soon as only stale commits
+/// are left, rather than at the root. Returns the common ancestors found,
+/// newest first; some may be ancestors of others.
+fn paint_down_to_common(repo: &Repository, a: &str, b: &str) -> Result<Vec<String>> {
+    const SIDE_A: u8 = 1;
+    const SIDE_B: u8 = 2;
+    const STALE: u8 = 4;
+
+    // Each commit is read once; its date and parents are kept for revisits
+    let mut commits: HashMap<String, (i64, Vec<String>)> = HashMap::new();
+    let mut flags: HashMap<String, u8> = HashMap::new();
+    let mut queued: HashSet<String> = HashSet::new();
+    let mut queue: BinaryHeap<(i64, Reverse<u64>, String)> = BinaryHeap::new();
+    let mut discovered = 0u64;
+    // Returns whether `hash` was newly queued
+    let mut enqueue = |hash: &str,
+                       queue: &mut BinaryHeap<(i64, Reverse<u64>, String)>,
+                       queued: &mut HashSet<String>,
+                       commits: &mut HashMap<String, (i64, Vec<String>)>|
+     -> Result<bool> {
+        if !queued.insert(hash.to_string()) {
+            return Ok(false);
+        }
+        if !commits.contains_key(hash) {
+            let commit = read_commit(repo, hash)?;
+            commits.insert(hash.to_string(), (commit.committer.time, commit.parents));
+        }
+        discovered += 1;
+        queue.push((commits[hash].0, Reverse(discovered), hash.to_string()));
+        Ok(true)
+    };
+
+    flags.insert(a.to_string(), SIDE_A);
+    flags.insert(b.to_string(), SIDE_B);
+    enqueue(a, &mut queue, &mut queued, &mut commits)?;
+    enqueue(b, &mut queue, &mut queued, &mut commits)?;
+    // Queued commits not yet marked stale; the walk ends when none are left
+    let mut active = 2usize;
+
+    let mut common = Vec::new();
+    while active > 0 {
+        let Some((_, _, hash)) = queue.pop() else {
+            break;
+        };
+        queued.remove(&hash);
+        let mut flag = flags[&hash];
+        if flag & STALE == 0 {
+            active -= 1;
+        }
+        if flag & (SIDE_A | SIDE_B) == SIDE_A | SIDE_B {
+            if flag & STALE == 0 {
+                common.push(hash.clone());
+            }
+            flag |= STALE;
+            flags.insert(hash.clone(), flag);
+        }
+        for parent in commits[&hash].1.clone() {
+            let parent_flag = flags.entry(parent.clone()).or_insert(0);
+            if *parent_flag & flag == flag {
+                continue;
+            }
+            let was_stale = *parent_flag & STALE != 0;
+            // Queued again (if already visited) so the new flags reach its parents
+            *parent_flag |= flag;
+            let stale = *parent_flag & STALE != 0;
+            if enqueue(&parent, &mut queue, &mut queued, &mut commits)? {
+                if !stale {
+                    active += 1;
+                }
+            } else if stale && !was_stale {
+                active -= 1;
+            }
+        }
+    }
+    Ok(common)
+}
+
+/// Collects every object reachable from `roots` (tags, commits, trees and blobs),
+/// paired with a path hint for delta selection. Commits in `exclude` and
+/// everything reachable from them are skipped.
+pub fn reachable_objects(
+    repo: &Repository,
+    roots: &[String],
+    exclude: &HashSet<String>,
+) -> Result<Vec<(String, String)>> {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut stack: Vec<(String, String)> =
+        roots.iter().map(|r| (r.clone(), String::new())).collect();
+    let mut objects = Vec::new();
+
+    while let Some((hash, name)) = stack.pop() {
+        if exclude.contains(&hash) || !seen.insert(hash.clone()) {
+            continue;
+        }
+        match repo.read_object(&hash)? {
+            GitObject::Commit(commit) => {
+                stack.push((commit.tree, String::new()));
+                stack.extend(commit.parents.into_iter().map(|p| (p, String::new())));
+            }
+            GitObject::Tree(entries) => {
+                for entry in entries {
+                    stack.push((entry.hash, entry.name));
+                }
+            }
+            GitObject::Tag(tag) => stack.push((tag.object, String::new())),
+            GitObject::Blob(_) => {}
+        }
+        objects.push((hash, name));
+    }
+    Ok(objects)
+}
+
+/// Objects a peer holding `haves` needs to receive to have everything reachable from `wants`.
+///
+/// Haves this repository does not know are ignored. The wants and haves are
+/// walked back together only until every queued commit is reachable from a
+/// have; that frontier bounds the commits sent, and the trees of the frontier
+/// commits below them are skipped, so unchanged files are not sent again.
+pub fn missing_objects(
+    repo: &Repository,
+    wants: &[String],
+    haves: &[String],
+) -> Result<Vec<(String, String)>> {
+    const WANT: u8 = 1;
+    const HAVE: u8 = 2;
+
+    let mut flags: HashMap<String, u8> = HashMap::new();
+    for have in haves {
+        if repo.has_object(have)? && matches!(repo.read_object(have)?, GitObject::Commit(_)) {
+            flags.insert(have.clone(), HAVE);
+        }
+    }
+    for want in wants {
+        // Tags are sent as they are; the walk starts from the commit they tag
+        let mut hash = want.clone();
+        while let GitObject::Tag(tag) = repo.read_object(&hash)? {
+            hash = tag.object;
+        }
+        if matches!(repo.read_object(&hash)?, GitObject::Commit(_)) {
+            *flags.entry(hash).or_insert(0) |= WANT;
+        }
+    }
+
+    let mut commits: HashMap<String, Commit> = HashMap::new();
+    let mut queued: HashSet<String> = HashSet::new();
+    let mut queue: BinaryHeap<(i64, Reverse<u64>, String)> = BinaryHeap::new();
+    let mut discovered = 0u64;
+    // Returns whether `hash` was newly queued
+    let mut enqueue = |hash: &str,
+                       queue: &mut BinaryHeap<(i64, Reverse<u64>, String)>,
+                       queued: &mut HashSet<String>,
+                       commits: &mut HashMap<String, Commit>|
+     -> Result<bool> {
+        if !queued.insert(hash.to_string()) {
+            return Ok(false);
+        }
+        if !commits.contains_key(hash) {
+            commits.insert(hash.to_string(), read_commit(repo, hash)?);
+        }
+        discovered += 1;
+        queue.push((
+            commits[hash].committer.time,
+            Reverse(discovered),
+            hash.to_string(),
+        ));
+        Ok(true)
+    };
+
+    // Queued commits not yet known to be reachable from a have
+    let mut active = 0usize;
+    let starts: Vec<(String, u8)> = flags.iter().map(|(h, f)| (h.clone(), *f)).collect();
+    for (hash, flag) in starts {
+        enqueue(&hash, &mut queue, &mut queued, &mut commits)?;
+        if flag & HAVE == 0 {
+            active += 1;
+        }
+    }
+    while active > 0 {
+        let Some((_, _, hash)) = queue.pop() else {
+            break;
+        };
+        queued.remove(&hash);
+        let flag = flags[&hash];
+        if flag & HAVE == 0 {
+            active -= 1;
+        }
+        for parent in commits[&hash].parents.clone() {
+            let parent_flag = flags.entry(parent.clone()).or_insert(0);
+            if *parent_flag & flag == flag {
+                continue;
+            }
+            let was_have = *parent_flag & HAVE != 0;
+            // Queued again (if already visited) so the new flags reach its parents
+            *parent_flag |= flag;
+            let have = *parent_flag & HAVE != 0;
+            if enqueue(&parent, &mut queue, &mut queued, &mut commits)? {
+                if !have {
+                    active += 1;
+                }
+            } else if have && !was_have {
+                active -= 1;
+            }
+        }
+    }
+
+    let mut exclude: HashSet<String> = flags
+        .iter()
+        .filter(|(_, flag)| *flag & HAVE != 0)
+        .map(|(hash, _)| hash.clone())
+        .collect();
+    let mut frontier: Vec<&String> = flags
+        .iter()
+        .filter(|(_, flag)| *flag & HAVE == 0)
+        .flat_map(|(hash, _)| &commits[hash].parents)
+        .filter(|parent| exclude.contains(*parent))
+        .collect();
+    frontier.sort();
+    frontier.dedup();
+    for hash in frontier {
+        for (object, _) in reachable_objects(repo, &[commits[hash].tree.clone()], &exclude)? {
+            exclude.insert(object);
+        }
+    }
+    reachable_objects(repo, wants, &exclude)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{commit_at, repo};
+
+    #[test]
+    fn merge_base_of_forked_history_is_the_fork_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repo(dir.path());
+        let mut fork = commit_at(&repo, &[], &[], 0);
+        for time in 1..20 {
+            fork = commit_at(&repo, &[&fork], &[], time);
+        }
+        let a = commit_at(&repo, &[&fork], &[], 30);
+        let b = commit_at(&repo, &[&fork], &[], 31);
+        let b = commit_at(&repo, &[&b], &[], 32);
+        assert_eq!(merge_bases(&repo, &a, &b).unwrap(), vec![fork.clone()]);
+        assert_eq!(merge_bases(&repo, &fork, &b).unwrap(), vec![fork]);
+    }
+
+    #[test]
+    fn missing_objects_stop_at_the_haves() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repo(dir.path());
+        let mut tip = String::new();
+        let mut history = Vec::new();
+        for time in 0..5 {
+            let changed = format!("version {}", time);
+            let files = [("changed.txt", changed.as_str()), ("same.txt", "unchanged")];
+            let parents: Vec<&str> = history.last().map(String::as_str).into_iter().collect();
+            tip = commit_at(&repo, &parents, &files, time);
+            history.push(tip.clone());
+        }
+        // History below the have is never read, so it need not even be present
+        std::fs::remove_file(repo.object_path(&history[0])).unwrap();
+
+        let have = history[3].clone();
+        let objects = missing_objects(&repo, std::slice::from_ref(&tip), &[have]).unwrap();
+        let hashes: HashSet<String> = objects.into_iter().map(|(h, _)| h).collect();
+        let tree = read_commit(&repo, &tip).unwrap().tree;
+        let GitObject::Tree(entries) = repo.read_object(&tree).unwrap() else {
+            panic!("{} is not a tree", tree);
+        };
+        let changed = entries[0].hash.clone();
+        assert_eq!(hashes, HashSet::from([tip.clone(), tree, changed]));
+
+        assert!(
+            missing_objects(&repo, std::slice::from_ref(&tip), &[tip.clone()])
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn criss_cross_merges_have_two_merge_bases() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repo(dir.path());
+        let root = commit_at(&repo, &[], &[], 0);
+        let a1 = commit_at(&repo, &[&root], &[], 1);
+        let b1 = commit_at(&repo, &[&root], &[], 2);
+        let a2 = commit_at(&repo, &[&a1, &b1], &[], 3);
+        let b2 = commit_at(&repo, &[&b1, &a1], &[], 4);
+        let mut bases = merge_bases(&repo, &a2, &b2).unwrap();
+        bases.sort();
+        let mut expected = vec![a1, b1];
+        expected.sort();
+        assert_eq!(bases, expected);
+    }
+}
@@ -10,11 +10,13 @@ pub struct IgnoreRules {
 impl IgnoreRules {
     pub fn load(repo: &crate::repository::Repository) -> Result<Self> {
         let ignore_path = repo.worktree.join(".vcsignore");
-        let mut patterns = Vec::new();
-
-        // Always ignore .vcs directory
-        patterns.push(Pattern::new(".vcs/**")?);
-        patterns.push(Pattern::new(".vcs")?);
+        // Always ignore .vcs directory, and .git when working alongside a Git checkout
+        let mut patterns = vec![
+            Pattern::new(".vcs/**")?,
+            Pattern::new(".vcs")?,
+            Pattern::new(".git/**")?,
+            Pattern::new(".git")?,
+        ];
 
         if ignore_path.exists() {
             let content = fs::read_to_string(&ignore_path)?;
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use fs2::FileExt;
 use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub struct RepoLock {
     lock_file: File,
@@ -11,30 +11,43 @@ pub struct RepoLock {
 impl RepoLock {
     pub fn new(repo: &crate::repository::Repository) -> Result<Self> {
         let lock_path = repo.vcs_dir.join("index.lock");
-
-        // Try to open or create lock file
-        let lock_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&lock_path)
-            .with_context(|| format!("Failed to open lock file: {}", lock_path.display()))?;
-
-        // Try to acquire exclusive lock (non-blocking)
-        lock_file.try_lock_exclusive().with_context(|| {
-            format!(
-                "Repository is locked. Another VCS process may be running. \
-                    If no other process is running, delete {} and try again.",
-                lock_path.display()
-            )
-        })?;
-
+        let lock_file = acquire(&lock_path)?;
         Ok(RepoLock {
             lock_file,
             lock_path,
         })
     }
+
+    /// Releases the lock while `f` runs, e.g. so that a child process can run
+    /// vcs commands in the repository, and takes it again afterwards.
+    pub fn released<T>(&mut self, f: impl FnOnce() -> T) -> Result<T> {
+        let _ = self.lock_file.unlock();
+        let _ = std::fs::remove_file(&self.lock_path);
+        let result = f();
+        self.lock_file = acquire(&self.lock_path)?;
+        Ok(result)
+    }
+}
+
+fn acquire(lock_path: &Path) -> Result<File> {
+    // Try to open or create lock file
+    let lock_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(lock_path)
+        .with_context(|| format!("Failed to open lock file: {}", lock_path.display()))?;
+
+    // Try to acquire exclusive lock (non-blocking)
+    lock_file.try_lock_exclusive().with_context(|| {
+        format!(
+            "Repository is locked. Another VCS process may be running. \
+                If no other process is running, delete {} and try again.",
+            lock_path.display()
+        )
+    })?;
+    Ok(lock_file)
 }
 
 impl Drop for RepoLock {
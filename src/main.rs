@@ -1,10 +1,25 @@
 mod commands;
 mod config;
+mod diff;
+mod editor;
+mod gitdir;
+mod history;
+mod http;
 mod ignore;
 mod lock;
+mod merge;
 mod objects;
+mod pack;
+mod protocol;
+mod reflog;
+mod remote;
 mod repository;
+mod revision;
+mod sequencer;
+#[cfg(test)]
+pub(crate) mod test_support;
 mod utils;
+mod worktree;
 
 use clap::{Parser, Subcommand};
 use commands::*;
@@ -17,25 +32,117 @@ struct Cli {
     command: Commands,
 }
 
+#[derive(Subcommand)]
+enum RemoteCommands {
+    /// Add a remote
+    Add {
+        /// Name of the remote
+        name: String,
+        /// Path or URL of the remote repository
+        url: String,
+    },
+    /// Remove a remote and its remote-tracking branches
+    Remove {
+        /// Name of the remote
+        name: String,
+    },
+    /// List remotes
+    List,
+}
+
+#[derive(Subcommand)]
+enum StashCommands {
+    /// Save local changes and reset to HEAD (the default)
+    Push {
+        /// Description of the stashed changes
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// List stash entries, newest first
+    List,
+    /// Show the changes in a stash entry
+    Show {
+        /// Entry to show (stash@{n} or n; default the latest)
+        stash: Option<String>,
+    },
+    /// Reapply a stash entry, keeping it
+    Apply {
+        /// Entry to apply (stash@{n} or n; default the latest)
+        stash: Option<String>,
+    },
+    /// Reapply a stash entry and drop it
+    Pop {
+        /// Entry to apply (stash@{n} or n; default the latest)
+        stash: Option<String>,
+    },
+    /// Delete a stash entry
+    Drop {
+        /// Entry to drop (stash@{n} or n; default the latest)
+        stash: Option<String>,
+    },
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize a new repository
-    Init,
+    Init {
+        /// Object layout: `vcs` (default) or `git` for Git-compatible hashes
+        #[arg(long, default_value = "vcs")]
+        object_format: String,
+    },
     /// Add files to the staging area
     Add {
         /// Files or directories to add
         paths: Vec<String>,
     },
+    /// Remove files from the working tree and the index
+    Rm {
+        /// Files or directories to remove
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Only remove from the index, keeping the files on disk
+        #[arg(long)]
+        cached: bool,
+        /// Allow removing directories
+        #[arg(short)]
+        recursive: bool,
+        /// Remove files even if they have uncommitted changes
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Move or rename a file or directory
+    Mv {
+        /// Sources followed by the destination
+        #[arg(num_args = 2.., required = true)]
+        paths: Vec<String>,
+        /// Overwrite an existing destination file
+        #[arg(short, long)]
+        force: bool,
+    },
     /// Commit staged changes
     Commit {
-        /// Commit message
-        #[arg(short, long)]
-        message: String,
+        /// Commit message (opens the editor when neither -m nor -F is given)
+        #[arg(short, long, conflicts_with = "file")]
+        message: Option<String>,
+        /// Read the commit message from a file ("-" for standard input)
+        #[arg(short = 'F', long)]
+        file: Option<String>,
+        /// Replace the tip of the current branch instead of adding a commit
+        #[arg(long)]
+        amend: bool,
+        /// Record a different author ("Name <email>")
+        #[arg(long)]
+        author: Option<String>,
+        /// Record a different author date (e.g. "2024-01-31 12:00:00 +0100")
+        #[arg(long)]
+        date: Option<String>,
     },
     /// Show repository status
     Status,
     /// Show commit history
     Log {
+        /// Revision or range to show, such as `main`, `HEAD~3` or `main..topic` (defaults to HEAD)
+        revision: Option<String>,
         /// One-line format
         #[arg(long)]
         oneline: bool,
@@ -43,9 +150,17 @@ enum Commands {
         #[arg(short = 'n', long)]
         number: Option<usize>,
     },
+    /// Show the history of updates to HEAD or a branch
+    Reflog {
+        /// Ref whose log to show (defaults to HEAD)
+        reference: Option<String>,
+        /// Number of entries to show
+        #[arg(short = 'n', long)]
+        number: Option<usize>,
+    },
     /// Show file contents from repository
     CatFile {
-        /// Object hash
+        /// Object hash or revision
         hash: String,
     },
     /// Configure VCS settings
@@ -61,22 +176,273 @@ enum Commands {
         #[arg(long)]
         list: bool,
     },
-    /// Unstage files from index
+    /// Restore working tree files or index entries
+    Restore {
+        /// Files or directories to restore
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Revision to restore from (default: the index, or HEAD with --staged)
+        #[arg(short, long)]
+        source: Option<String>,
+        /// Restore the index
+        #[arg(short = 'S', long)]
+        staged: bool,
+        /// Restore the working tree (the default unless --staged is given)
+        #[arg(short = 'W', long)]
+        worktree: bool,
+    },
+    /// Move the current branch to a commit, or unstage files
     Reset {
-        /// Files to unstage
+        /// Only move the branch, keeping the index and working tree
+        #[arg(long, conflicts_with_all = ["mixed", "hard"])]
+        soft: bool,
+        /// Also reset the index (the default)
+        #[arg(long, conflicts_with = "hard")]
+        mixed: bool,
+        /// Also reset the working tree, discarding local changes
+        #[arg(long)]
+        hard: bool,
+        /// Commit to reset to (default HEAD), followed by files to unstage
+        args: Vec<String>,
+        /// Files to reset in the index
+        #[arg(last = true)]
         paths: Vec<String>,
     },
+    /// List, create, delete or rename branches
+    Branch {
+        /// Branch to create, delete or rename
+        name: Option<String>,
+        /// Start point for a new branch, or new name when renaming
+        target: Option<String>,
+        /// Delete the named branch (it must be merged into HEAD)
+        #[arg(short, long)]
+        delete: bool,
+        /// Delete the named branch even if it is not merged
+        #[arg(short = 'D')]
+        force_delete: bool,
+        /// Rename a branch (the current one if only a new name is given)
+        #[arg(short = 'm', long = "move")]
+        rename: bool,
+    },
+    /// Create, list or delete tags
+    Tag {
+        /// Tag to create or delete, or a pattern to list with -l
+        name: Option<String>,
+        /// Revision to tag (defaults to HEAD)
+        target: Option<String>,
+        /// Create an annotated tag object (implied by -m)
+        #[arg(short, long)]
+        annotate: bool,
+        /// Message for an annotated tag
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Delete the named tag
+        #[arg(short, long)]
+        delete: bool,
+        /// Replace an existing tag
+        #[arg(short, long)]
+        force: bool,
+        /// List tags, optionally matching a pattern
+        #[arg(short, long)]
+        list: bool,
+    },
+    /// Switch branches or check out a commit into the working tree
+    Checkout {
+        /// Branch or commit to check out (start point with -b)
+        target: Option<String>,
+        /// Create a new branch starting at <target> and switch to it
+        #[arg(short = 'b')]
+        new_branch: Option<String>,
+        /// Discard local changes that would be overwritten
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Show changes between the working tree, index and commits
+    Diff {
+        /// Compare the index against HEAD (or the given revision)
+        #[arg(long, visible_alias = "cached")]
+        staged: bool,
+        /// Number of context lines around each change
+        #[arg(short = 'U', long = "unified", default_value_t = 3)]
+        context: usize,
+        /// Zero, one or two revisions to compare
+        revisions: Vec<String>,
+        /// Limit the diff to these paths
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Join another branch's history into the current branch
+    Merge {
+        /// Branch or commit to merge
+        target: Option<String>,
+        /// Message for the merge commit
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Create a merge commit even when a fast-forward is possible
+        #[arg(long)]
+        no_ff: bool,
+        /// Abandon a conflicted merge and restore the pre-merge state
+        #[arg(long)]
+        abort: bool,
+    },
+    /// Compress loose objects written by older versions in place
+    MigrateObjects,
+    /// Import branches, tags and history from a Git repository
+    ImportGit {
+        /// Path to the Git repository (worktree or .git directory)
+        path: String,
+    },
+    /// Export branches, tags and history to a Git repository, creating it if needed
+    ExportGit {
+        /// Path to the Git repository (worktree or .git directory)
+        path: String,
+    },
+    /// Apply the changes introduced by existing commits
+    CherryPick {
+        /// Commits or ranges (A..B) to apply, oldest first
+        revisions: Vec<String>,
+        /// Resume after resolving conflicts
+        #[arg(long = "continue", conflicts_with = "abort")]
+        cont: bool,
+        /// Give up and restore HEAD from before the cherry-pick
+        #[arg(long)]
+        abort: bool,
+    },
+    /// Create commits that undo the changes of existing commits
+    Revert {
+        /// Commits or ranges (A..B) to revert, newest first
+        revisions: Vec<String>,
+        /// Resume after resolving conflicts
+        #[arg(long = "continue", conflicts_with = "abort")]
+        cont: bool,
+        /// Give up and restore HEAD from before the revert
+        #[arg(long)]
+        abort: bool,
+    },
+    /// Replay the current branch's commits on top of another commit
+    Rebase {
+        /// Branch or commit to rebase onto; its commits are not replayed
+        upstream: Option<String>,
+        /// Replay onto this commit instead of the upstream
+        #[arg(long)]
+        onto: Option<String>,
+        /// Edit the list of commits to replay first
+        #[arg(short, long)]
+        interactive: bool,
+        /// Resume after resolving conflicts or editing a commit
+        #[arg(long = "continue", conflicts_with_all = ["skip", "abort"])]
+        cont: bool,
+        /// Leave out the commit that stopped the rebase and go on
+        #[arg(long, conflicts_with = "abort")]
+        skip: bool,
+        /// Give up and return to the original branch
+        #[arg(long)]
+        abort: bool,
+    },
+    /// Set aside uncommitted changes and reapply them later
+    Stash {
+        #[command(subcommand)]
+        command: Option<StashCommands>,
+    },
+    /// Manage remote repositories
+    Remote {
+        #[command(subcommand)]
+        command: Option<RemoteCommands>,
+    },
+    /// Copy a repository into a new directory
+    Clone {
+        /// Path or URL of the repository to clone
+        url: String,
+        /// Directory to clone into (defaults to the repository's name)
+        directory: Option<String>,
+    },
+    /// Download branches and objects from a remote
+    Fetch {
+        /// Remote to fetch from (defaults to origin)
+        remote: Option<String>,
+    },
+    /// Upload a branch to a remote
+    Push {
+        /// Remote to push to (defaults to origin)
+        remote: Option<String>,
+        /// Branch to push (defaults to the current branch)
+        branch: Option<String>,
+        /// Overwrite the remote branch even if it is not a fast-forward
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Fetch from a remote and merge its branch into the current branch
+    Pull {
+        /// Remote to pull from (defaults to origin)
+        remote: Option<String>,
+        /// Remote branch to merge (defaults to the current branch's name)
+        branch: Option<String>,
+    },
+    /// Pack every object into a single delta-compressed packfile
+    Repack,
+    /// Pack reachable objects and prune unreachable ones older than two weeks
+    Gc,
+    /// Serve a repository to clients over HTTP
+    Serve {
+        /// Address to listen on, such as 127.0.0.1:8080
+        #[arg(long)]
+        http: String,
+        /// Repository to serve (defaults to the current one)
+        path: Option<String>,
+    },
+    /// Serve fetches from a repository over stdin/stdout (run by the pipe transport)
+    UploadPack {
+        /// Path to the repository
+        path: String,
+    },
+    /// Accept pushes into a repository over stdin/stdout (run by the pipe transport)
+    ReceivePack {
+        /// Path to the repository
+        path: String,
+    },
+    /// Switch to another branch
+    Switch {
+        /// Branch to switch to (or start point with -c)
+        target: Option<String>,
+        /// Create a new branch and switch to it
+        #[arg(short, long)]
+        create: Option<String>,
+        /// Check out a commit with a detached HEAD
+        #[arg(long)]
+        detach: bool,
+        /// Discard local changes that would be overwritten
+        #[arg(short, long)]
+        force: bool,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
 
     let result = match cli.command {
-        Commands::Init => init(),
+        Commands::Init { object_format } => init(&object_format),
         Commands::Add { paths } => add(paths),
-        Commands::Commit { message } => commit(&message),
+        Commands::Rm {
+            paths,
+            cached,
+            recursive,
+            force,
+        } => rm(paths, cached, recursive, force),
+        Commands::Mv { paths, force } => mv(paths, force),
+        Commands::Commit {
+            message,
+            file,
+            amend,
+            author,
+            date,
+        } => commit(message, file, amend, author, date),
         Commands::Status => status(),
-        Commands::Log { oneline, number } => log(oneline, number),
+        Commands::Log {
+            revision,
+            oneline,
+            number,
+        } => log(revision, oneline, number),
+        Commands::Reflog { reference, number } => reflog(reference, number),
         Commands::CatFile { hash } => cat_file(&hash),
         Commands::Config {
             key,
@@ -84,7 +450,114 @@ fn main() {
             global,
             list,
         } => config(key, value, global, list),
-        Commands::Reset { paths } => reset(paths),
+        Commands::Restore {
+            paths,
+            source,
+            staged,
+            worktree,
+        } => restore(paths, source, staged, worktree),
+        Commands::Reset {
+            soft,
+            mixed,
+            hard,
+            args,
+            paths,
+        } => {
+            let mode = match (soft, mixed, hard) {
+                (true, _, _) => Some(ResetMode::Soft),
+                (_, true, _) => Some(ResetMode::Mixed),
+                (_, _, true) => Some(ResetMode::Hard),
+                _ => None,
+            };
+            reset(mode, args, paths)
+        }
+        Commands::Branch {
+            name,
+            target,
+            delete,
+            force_delete,
+            rename,
+        } => branch(name, target, delete, force_delete, rename),
+        Commands::Tag {
+            name,
+            target,
+            message,
+            annotate,
+            delete,
+            force,
+            list,
+        } => tag(name, target, message, annotate, delete, force, list),
+        Commands::Checkout {
+            target,
+            new_branch,
+            force,
+        } => checkout(target, new_branch, force),
+        Commands::Diff {
+            staged,
+            context,
+            revisions,
+            paths,
+        } => diff(staged, context, revisions, paths),
+        Commands::Merge {
+            target,
+            message,
+            no_ff,
+            abort,
+        } => merge(target, message, no_ff, abort),
+        Commands::MigrateObjects => migrate_objects(),
+        Commands::ImportGit { path } => import_git(&path),
+        Commands::ExportGit { path } => export_git(&path),
+        Commands::CherryPick {
+            revisions,
+            cont,
+            abort,
+        } => cherry_pick(revisions, cont, abort),
+        Commands::Revert {
+            revisions,
+            cont,
+            abort,
+        } => revert(revisions, cont, abort),
+        Commands::Rebase {
+            upstream,
+            onto,
+            interactive,
+            cont,
+            skip,
+            abort,
+        } => rebase(upstream, onto, interactive, cont, skip, abort),
+        Commands::Stash { command } => match command {
+            Some(StashCommands::Push { message }) => stash_push(message),
+            None => stash_push(None),
+            Some(StashCommands::List) => stash_list(),
+            Some(StashCommands::Show { stash }) => stash_show(stash),
+            Some(StashCommands::Apply { stash }) => stash_apply(stash, false),
+            Some(StashCommands::Pop { stash }) => stash_apply(stash, true),
+            Some(StashCommands::Drop { stash }) => stash_drop(stash),
+        },
+        Commands::Remote { command } => match command {
+            Some(RemoteCommands::Add { name, url }) => remote_add(&name, &url),
+            Some(RemoteCommands::Remove { name }) => remote_remove(&name),
+            Some(RemoteCommands::List) | None => remote_list(),
+        },
+        Commands::Clone { url, directory } => clone(&url, directory),
+        Commands::Fetch { remote } => fetch(remote),
+        Commands::Push {
+            remote,
+            branch,
+            force,
+        } => push(remote, branch, force),
+        Commands::Pull { remote, branch } => pull(remote, branch),
+        Commands::Repack => repack(),
+        Commands::Gc => gc(),
+        Commands::Serve { http, path } => serve(&http, path),
+        Commands::UploadPack { path } => protocol::upload_pack(&path),
+        Commands::ReceivePack { path } => protocol::receive_pack(&path),
+        Commands::Switch {
+            target,
+            create,
+            detach,
+            force,
+        } => switch(target, create, detach, force),
     };
 
     if let Err(e) = result {
@@ -0,0 +1,348 @@
+use crate::diff::{diff_slices, is_binary, DiffOp};
+use crate::objects::{GitObject, IndexEntry};
+use crate::repository::Repository;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Result of a line-level three-way merge.
+pub struct MergedText {
+    pub content: Vec<u8>,
+    pub conflicts: usize,
+}
+
+/// Labels written after the `<<<<<<<` and `>>>>>>>` conflict markers.
+pub struct ConflictLabels<'a> {
+    pub ours: &'a str,
+    pub theirs: &'a str,
+}
+
+/// For each line of `base`, the index of the matching line in `other`, if it survives unchanged.
+fn base_matches(base: &[&[u8]], other: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    for op in diff_slices(base, other) {
+        if let DiffOp::Equal(i, j) = op {
+            matches[i] = Some(j);
+        }
+    }
+    matches
+}
+
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|&b| b == b'\n').collect()
+}
+
+/// Merges `ours` and `theirs` line by line relative to `base` (diff3), writing
+/// conflict markers where both sides changed the same region differently.
+pub fn merge_text(base: &[u8], ours: &[u8], theirs: &[u8], labels: &ConflictLabels) -> MergedText {
+    let base_lines = split_lines(base);
+    let our_lines = split_lines(ours);
+    let their_lines = split_lines(theirs);
+    let to_ours = base_matches(&base_lines, &our_lines);
+    let to_theirs = base_matches(&base_lines, &their_lines);
+
+    let mut content = Vec::new();
+    let mut conflicts = 0;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+
+    loop {
+        // Copy lines that are unchanged on both sides
+        while i < base_lines.len() && to_ours[i] == Some(j) && to_theirs[i] == Some(k) {
+            content.extend_from_slice(base_lines[i]);
+            i += 1;
+            j += 1;
+            k += 1;
+        }
+
+        // Find the next base line both sides kept; everything before it is a changed chunk
+        let next_stable =
+            (i..base_lines.len()).find(|&x| to_ours[x].is_some() && to_theirs[x].is_some());
+        let (i_end, j_end, k_end) = match next_stable {
+            Some(x) => (x, to_ours[x].unwrap_or(j), to_theirs[x].unwrap_or(k)),
+            None => (base_lines.len(), our_lines.len(), their_lines.len()),
+        };
+        if i == i_end && j == j_end && k == k_end {
+            break;
+        }
+
+        let base_chunk = &base_lines[i..i_end];
+        let our_chunk = &our_lines[j..j_end];
+        let their_chunk = &their_lines[k..k_end];
+        if our_chunk == base_chunk || our_chunk == their_chunk {
+            their_chunk
+                .iter()
+                .for_each(|l| content.extend_from_slice(l));
+        } else if their_chunk == base_chunk {
+            our_chunk.iter().for_each(|l| content.extend_from_slice(l));
+        } else {
+            conflicts += 1;
+            write_conflict(&mut content, our_chunk, their_chunk, labels);
+        }
+        (i, j, k) = (i_end, j_end, k_end);
+    }
+
+    MergedText { content, conflicts }
+}
+
+fn write_conflict(out: &mut Vec<u8>, ours: &[&[u8]], theirs: &[&[u8]], labels: &ConflictLabels) {
+    let write_side = |out: &mut Vec<u8>, lines: &[&[u8]]| {
+        for line in lines {
+            out.extend_from_slice(line);
+        }
+        if lines.last().is_some_and(|l| !l.ends_with(b"\n")) {
+            out.push(b'\n');
+        }
+    };
+    out.extend_from_slice(format!("<<<<<<< {}\n", labels.ours).as_bytes());
+    write_side(out, ours);
+    out.extend_from_slice(b"=======\n");
+    write_side(out, theirs);
+    out.extend_from_slice(format!(">>>>>>> {}\n", labels.theirs).as_bytes());
+}
+
+/// Outcome of merging two trees against their base.
+pub struct TreeMerge {
+    /// The merged index: stage 0 for resolved paths, stages 1-3 for conflicts.
+    pub index: Vec<IndexEntry>,
+    /// What the working tree should contain, including conflict-marked files.
+    pub worktree: Vec<IndexEntry>,
+    /// Conflicted paths with a short description of the conflict.
+    pub conflicts: Vec<(String, &'static str)>,
+}
+
+fn blob_data(repo: &Repository, hash: &str) -> Result<Vec<u8>> {
+    match repo.read_object(hash)? {
+        GitObject::Blob(data) => Ok(data),
+        _ => anyhow::bail!("{} is not a blob", hash),
+    }
+}
+
+fn write_blob(repo: &Repository, data: Vec<u8>, path: &str, mode: &str) -> Result<IndexEntry> {
+    let size = data.len() as u64;
+    let hash = repo.write_object(&GitObject::Blob(data))?;
+    Ok(IndexEntry {
+        path: path.to_string(),
+        hash,
+        size,
+        mode: mode.to_string(),
+        stage: 0,
+    })
+}
+
+fn staged(entry: &IndexEntry, stage: u8) -> IndexEntry {
+    IndexEntry {
+        stage,
+        ..entry.clone()
+    }
+}
+
+/// Three-way merges flattened trees path by path, falling back to a line-level
+/// merge when both sides changed the same file.
+pub fn merge_trees(
+    repo: &Repository,
+    base: &[IndexEntry],
+    ours: &[IndexEntry],
+    theirs: &[IndexEntry],
+    labels: &ConflictLabels,
+) -> Result<TreeMerge> {
+    let by_path = |entries: &[IndexEntry]| -> BTreeMap<String, IndexEntry> {
+        entries
+            .iter()
+            .map(|e| (e.path.clone(), e.clone()))
+            .collect()
+    };
+    let (base, ours, theirs) = (by_path(base), by_path(ours), by_path(theirs));
+    let paths: BTreeSet<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+    let same = |a: Option<&IndexEntry>, b: Option<&IndexEntry>| match (a, b) {
+        (Some(a), Some(b)) => a.hash == b.hash && a.mode == b.mode,
+        (None, None) => true,
+        _ => false,
+    };
+
+    let mut result = TreeMerge {
+        index: Vec::new(),
+        worktree: Vec::new(),
+        conflicts: Vec::new(),
+    };
+
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+
+        // Trivial cases: one side unchanged, or both changed identically
+        let resolved = if same(o, t) || same(b, t) {
+            Some(o)
+        } else if same(b, o) {
+            Some(t)
+        } else {
+            None
+        };
+        if let Some(entry) = resolved {
+            if let Some(entry) = entry {
+                result.index.push(entry.clone());
+                result.worktree.push(entry.clone());
+            }
+            continue;
+        }
+
+        match (o, t) {
+            (Some(o), Some(t)) => {
+                // Mode changes merge independently of content
+                let mode = match b {
+                    Some(b) if o.mode == b.mode => t.mode.clone(),
+                    _ => o.mode.clone(),
+                };
+                let base_data = match b {
+                    Some(b) => blob_data(repo, &b.hash)?,
+                    None => Vec::new(),
+                };
+                let our_data = blob_data(repo, &o.hash)?;
+                let their_data = blob_data(repo, &t.hash)?;
+
+                if o.hash == t.hash {
+                    let entry = write_blob(repo, our_data, path, &mode)?;
+                    result.index.push(entry.clone());
+                    result.worktree.push(entry);
+                    continue;
+                }
+
+                let binary =
+                    is_binary(&base_data) || is_binary(&our_data) || is_binary(&their_data);
+                let merged =
+                    (!binary).then(|| merge_text(&base_data, &our_data, &their_data, labels));
+                match merged {
+                    Some(m) if m.conflicts == 0 => {
+                        let entry = write_blob(repo, m.content, path, &mode)?;
+                        result.index.push(entry.clone());
+                        result.worktree.push(entry);
+                    }
+                    merged => {
+                        result.index.extend(b.map(|b| staged(b, 1)));
+                        result.index.push(staged(o, 2));
+                        result.index.push(staged(t, 3));
+                        let shown = match merged {
+                            Some(m) => write_blob(repo, m.content, path, &mode)?,
+                            None => o.clone(),
+                        };
+                        result.worktree.push(shown);
+                        let kind = match (b, binary) {
+                            (_, true) => "binary file changed on both sides",
+                            (Some(_), false) => "both modified",
+                            (None, false) => "both added",
+                        };
+                        result.conflicts.push((path.clone(), kind));
+                    }
+                }
+            }
+            // One side deleted the file while the other changed it
+            (survivor, _) => {
+                let (kept, stage, kind) = match survivor {
+                    Some(o) => (o, 2, "deleted by them"),
+                    None => (
+                        t.expect("path present on at least one side"),
+                        3,
+                        "deleted by us",
+                    ),
+                };
+                result.index.extend(b.map(|b| staged(b, 1)));
+                result.index.push(staged(kept, stage));
+                result.worktree.push(kept.clone());
+                result.conflicts.push((path.clone(), kind));
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABELS: ConflictLabels = ConflictLabels {
+        ours: "HEAD",
+        theirs: "topic",
+    };
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, usize) {
+        let merged = merge_text(base.as_bytes(), ours.as_bytes(), theirs.as_bytes(), &LABELS);
+        (String::from_utf8(merged.content).unwrap(), merged.conflicts)
+    }
+
+    #[test]
+    fn unchanged_sides_keep_base() {
+        assert_eq!(merge("a\nb\n", "a\nb\n", "a\nb\n"), ("a\nb\n".into(), 0));
+        assert_eq!(merge("", "", ""), (String::new(), 0));
+    }
+
+    #[test]
+    fn takes_the_side_that_changed() {
+        assert_eq!(merge("a\nb\n", "a\nB\n", "a\nb\n"), ("a\nB\n".into(), 0));
+        assert_eq!(merge("a\nb\n", "a\nb\n", "A\nb\n"), ("A\nb\n".into(), 0));
+    }
+
+    #[test]
+    fn combines_changes_to_separate_lines() {
+        let base = "1\n2\n3\n4\n5\n";
+        let ours = "one\n2\n3\n4\n5\n";
+        let theirs = "1\n2\n3\n4\nfive\nsix\n";
+        assert_eq!(
+            merge(base, ours, theirs),
+            ("one\n2\n3\n4\nfive\nsix\n".into(), 0)
+        );
+    }
+
+    #[test]
+    fn identical_changes_do_not_conflict() {
+        assert_eq!(
+            merge("a\nb\nc\n", "a\nx\nc\n", "a\nx\nc\n"),
+            ("a\nx\nc\n".into(), 0)
+        );
+    }
+
+    #[test]
+    fn deletion_on_one_side_applies() {
+        assert_eq!(
+            merge("a\nb\nc\n", "a\nc\n", "a\nb\nc\nd\n"),
+            ("a\nc\nd\n".into(), 0)
+        );
+    }
+
+    #[test]
+    fn overlapping_changes_conflict() {
+        let (content, conflicts) = merge("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n");
+        assert_eq!(conflicts, 1);
+        assert_eq!(
+            content,
+            "a\n<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\nc\n"
+        );
+    }
+
+    #[test]
+    fn conflicts_are_counted_per_region() {
+        let base = "1\n2\n3\n4\n5\n";
+        let ours = "x\n2\n3\n4\ny\n";
+        let theirs = "X\n2\n3\n4\nY\n";
+        assert_eq!(merge(base, ours, theirs).1, 2);
+    }
+
+    #[test]
+    fn conflict_markers_start_on_their_own_line() {
+        // Neither side ends with a newline; markers must still be on separate lines
+        let (content, conflicts) = merge("a\nb", "a\nours", "a\ntheirs");
+        assert_eq!(conflicts, 1);
+        assert_eq!(
+            content,
+            "a\n<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\n"
+        );
+    }
+
+    #[test]
+    fn additions_at_the_same_place_conflict() {
+        let (content, conflicts) = merge("a\n", "a\nb\n", "a\nc\n");
+        assert_eq!(conflicts, 1);
+        assert_eq!(content, "a\n<<<<<<< HEAD\nb\n=======\nc\n>>>>>>> topic\n");
+    }
+}
@@ -9,6 +9,13 @@ pub struct IndexEntry {
     pub hash: String,
     pub size: u64,
     pub mode: String, // File permissions/mode
+    /// Merge stage: 0 when resolved, 1/2/3 for the base/ours/theirs sides of a conflict
+    #[serde(default, skip_serializing_if = "is_stage_zero")]
+    pub stage: u8,
+}
+
+fn is_stage_zero(stage: &u8) -> bool {
+    *stage == 0
 }
 
 #[derive(Debug, Clone)]
@@ -16,6 +23,7 @@ pub enum GitObject {
     Blob(Vec<u8>),
     Tree(Vec<TreeEntry>),
     Commit(Commit),
+    Tag(Tag),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,13 +33,274 @@ pub struct TreeEntry {
     pub hash: String,
 }
 
+/// How commits and trees are laid out on disk, chosen when the repository is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFormat {
+    /// The original layout: files sorted before directories. Older commits
+    /// carry an author without timezone and a `timestamp` line instead of a committer.
+    Vcs,
+    /// Byte-for-byte Git layout, so hashes match Git for identical content.
+    Git,
+}
+
+impl ObjectFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "vcs" => Ok(ObjectFormat::Vcs),
+            "git" => Ok(ObjectFormat::Git),
+            _ => anyhow::bail!("Unknown object format '{}' (expected 'vcs' or 'git')", name),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ObjectFormat::Vcs => "vcs",
+            ObjectFormat::Git => "git",
+        }
+    }
+}
+
+/// Who made a change and when, in the timezone they recorded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    /// Seconds since the Unix epoch.
+    pub time: i64,
+    /// Minutes east of UTC.
+    pub offset: i32,
+}
+
+impl Signature {
+    /// `Name <email>`, as shown in logs.
+    pub fn identity(&self) -> String {
+        if self.email.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} <{}>", self.name, self.email)
+        }
+    }
+
+    /// Git's `Name <email> 1700000000 +0100` form.
+    pub fn to_git(&self) -> String {
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let minutes = self.offset.abs();
+        format!(
+            "{} <{}> {} {}{:02}{:02}",
+            self.name,
+            self.email,
+            self.time,
+            sign,
+            minutes / 60,
+            minutes % 60
+        )
+    }
+
+    /// Parses Git's `Name <email> 1700000000 +0100` form.
+    pub fn parse_git(value: &str) -> Result<Self> {
+        let open = value
+            .find('<')
+            .context("Invalid signature: missing email")?;
+        let close = value
+            .rfind('>')
+            .context("Invalid signature: missing email")?;
+        if close < open {
+            anyhow::bail!("Invalid signature: malformed email");
+        }
+        let mut when = value[close + 1..].split_whitespace();
+        let time = when
+            .next()
+            .context("Invalid signature: missing time")?
+            .parse()?;
+        let tz = when.next().unwrap_or("+0000");
+        let digits: i32 = tz.trim_start_matches(['+', '-']).parse()?;
+        let offset = (digits / 100 * 60 + digits % 100) * if tz.starts_with('-') { -1 } else { 1 };
+        Ok(Signature {
+            name: value[..open].trim_end().to_string(),
+            email: value[open + 1..close].to_string(),
+            time,
+            offset,
+        })
+    }
+
+    /// The recorded time in the recorded timezone.
+    pub fn date_time(&self) -> Result<chrono::DateTime<chrono::FixedOffset>> {
+        let zone = chrono::FixedOffset::east_opt(self.offset * 60).context("Invalid timezone")?;
+        let utc = chrono::DateTime::from_timestamp(self.time, 0).context("Invalid timestamp")?;
+        Ok(utc.with_timezone(&zone))
+    }
+
+    /// Splits `Name <email>` into its name and email.
+    pub fn parse_identity(value: &str) -> Result<(String, String)> {
+        let (name, email) = match (value.find('<'), value.rfind('>')) {
+            (Some(open), Some(close)) if open < close && !value[..open].trim().is_empty() => (
+                value[..open].trim().to_string(),
+                value[open + 1..close].trim().to_string(),
+            ),
+            _ => anyhow::bail!("Invalid identity '{}' (expected 'Name <email>')", value),
+        };
+        Signature::check_identity_part("name", &name)?;
+        Signature::check_identity_part("email", &email)?;
+        Ok((name, email))
+    }
+
+    /// Refuses a name or email that would break the `Name <email> time zone`
+    /// header line: line breaks, NUL or angle brackets.
+    pub fn check_identity_part(kind: &str, value: &str) -> Result<()> {
+        if value.contains(['\n', '\r', '\0', '<', '>']) {
+            anyhow::bail!(
+                "Invalid {} '{}': must not contain line breaks, NUL, '<' or '>'",
+                kind,
+                value.escape_debug()
+            );
+        }
+        Ok(())
+    }
+
+    /// Parses a date as Unix time and minutes east of UTC. Accepts Git's
+    /// internal `1700000000 +0100` (or `@1700000000`), RFC 2822, and ISO 8601
+    /// (`2024-01-31 12:00:00 +0100`, `2024-01-31T12:00:00Z`); dates without a
+    /// zone are taken in the local timezone.
+    pub fn parse_date(value: &str) -> Result<(i64, i32)> {
+        use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone};
+        let value = value.trim();
+        let from = |date: DateTime<chrono::FixedOffset>| {
+            (date.timestamp(), date.offset().local_minus_utc() / 60)
+        };
+
+        let mut parts = value.split_whitespace();
+        if let Some(Ok(time)) = parts
+            .next()
+            .map(|t| t.trim_start_matches('@').parse::<i64>())
+        {
+            if value.starts_with('@') || parts.clone().next().is_some() {
+                let tz = parts.next().unwrap_or("+0000");
+                let zone = DateTime::parse_from_str(&format!("0 {}", tz), "%s %z")
+                    .with_context(|| format!("Invalid timezone '{}'", tz))?;
+                return Ok((time, zone.offset().local_minus_utc() / 60));
+            }
+        }
+        if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+            return Ok(from(date));
+        }
+        if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+            return Ok(from(date));
+        }
+        for format in [
+            "%Y-%m-%d %H:%M:%S %z",
+            "%Y-%m-%dT%H:%M:%S%z",
+            "%Y-%m-%d %H:%M %z",
+        ] {
+            if let Ok(date) = DateTime::parse_from_str(value, format) {
+                return Ok(from(date));
+            }
+        }
+        let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+            .or_else(|| {
+                NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+            })
+            .with_context(|| format!("Invalid date '{}'", value))?;
+        let local = chrono::Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .with_context(|| format!("Invalid local time '{}'", value))?;
+        Ok(from(local.fixed_offset()))
+    }
+
+    /// Builds a signature from the original format's `author` and `timestamp` lines.
+    fn from_vcs(author: &str, time: i64) -> Self {
+        let (name, email) = match (author.find('<'), author.rfind('>')) {
+            (Some(open), Some(close)) if open < close => (
+                author[..open].trim_end().to_string(),
+                author[open + 1..close].to_string(),
+            ),
+            _ => (author.to_string(), String::new()),
+        };
+        Signature {
+            name,
+            email,
+            time,
+            offset: 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Commit {
     pub tree: String,
-    pub parent: Option<String>,
-    pub author: String,
+    pub parents: Vec<String>,
+    pub author: Signature,
+    pub committer: Signature,
+    /// Headers this implementation does not interpret (e.g. `gpgsig`), kept for lossless round trips.
+    pub extra_headers: Vec<(String, String)>,
     pub message: String,
-    pub timestamp: i64,
+}
+
+/// An annotated tag: a named, signed pointer to another object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tag {
+    pub object: String,
+    /// Type of the tagged object (`commit`, `tree`, `blob` or `tag`).
+    pub target_type: String,
+    pub name: String,
+    /// Absent on some tags imported from old Git repositories.
+    pub tagger: Option<Signature>,
+    pub extra_headers: Vec<(String, String)>,
+    pub message: String,
+}
+
+/// Splits a commit or tag payload into its headers, joining continuation lines,
+/// and its message.
+fn parse_headers(content: &[u8]) -> Result<(Vec<(String, String)>, String)> {
+    let content_str = std::str::from_utf8(content)?;
+    let (header_block, message) = match content_str.find("\n\n") {
+        Some(pos) => (&content_str[..pos], &content_str[pos + 2..]),
+        None => (content_str.trim_end_matches('\n'), ""),
+    };
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in header_block.lines() {
+        if let Some(continued) = line.strip_prefix(' ') {
+            let last = headers.last_mut().context("Invalid object header")?;
+            last.1.push('\n');
+            last.1.push_str(continued);
+        } else {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            headers.push((key.to_string(), value.to_string()));
+        }
+    }
+    Ok((headers, message.to_string()))
+}
+
+fn write_extra_headers(data: &mut Vec<u8>, headers: &[(String, String)]) {
+    for (key, value) in headers {
+        // Multi-line values continue on lines starting with a space
+        let value = value.replace('\n', "\n ");
+        data.extend_from_slice(format!("{} {}\n", key, value).as_bytes());
+    }
+}
+
+/// Orders tree entries the way `format` hashes them. Git compares directory
+/// names as if they ended in `/`; the original format lists files before directories.
+pub fn sort_tree_entries(entries: &mut [TreeEntry], format: ObjectFormat) {
+    match format {
+        ObjectFormat::Git => entries.sort_by_cached_key(|e| {
+            let mut key = e.name.clone().into_bytes();
+            if e.mode == "40000" {
+                key.push(b'/');
+            }
+            key
+        }),
+        ObjectFormat::Vcs => entries.sort_by(|a, b| {
+            (a.mode == "40000")
+                .cmp(&(b.mode == "40000"))
+                .then_with(|| a.name.cmp(&b.name))
+        }),
+    }
 }
 
 impl GitObject {
@@ -61,11 +330,15 @@ impl GitObject {
             GitObject::Commit(commit) => {
                 let mut data = Vec::new();
                 data.extend_from_slice(format!("tree {}\n", commit.tree).as_bytes());
-                if let Some(ref parent) = commit.parent {
+                for parent in &commit.parents {
                     data.extend_from_slice(format!("parent {}\n", parent).as_bytes());
                 }
-                data.extend_from_slice(format!("author {}\n", commit.author).as_bytes());
-                data.extend_from_slice(format!("timestamp {}\n", commit.timestamp).as_bytes());
+                data.extend_from_slice(format!("author {}\n", commit.author.to_git()).as_bytes());
+                data.extend_from_slice(
+                    format!("committer {}\n", commit.committer.to_git()).as_bytes(),
+                );
+                // Both formats keep headers they do not interpret (e.g. `gpgsig`)
+                write_extra_headers(&mut data, &commit.extra_headers);
                 data.push(b'\n');
                 data.extend_from_slice(commit.message.as_bytes());
 
@@ -74,6 +347,24 @@ impl GitObject {
                 result.extend_from_slice(&data);
                 Ok(result)
             }
+            // Both formats use Git's tag layout
+            GitObject::Tag(tag) => {
+                let mut data = Vec::new();
+                data.extend_from_slice(format!("object {}\n", tag.object).as_bytes());
+                data.extend_from_slice(format!("type {}\n", tag.target_type).as_bytes());
+                data.extend_from_slice(format!("tag {}\n", tag.name).as_bytes());
+                if let Some(tagger) = &tag.tagger {
+                    data.extend_from_slice(format!("tagger {}\n", tagger.to_git()).as_bytes());
+                }
+                write_extra_headers(&mut data, &tag.extra_headers);
+                data.push(b'\n');
+                data.extend_from_slice(tag.message.as_bytes());
+
+                let header = format!("tag {}\0", data.len());
+                let mut result = header.into_bytes();
+                result.extend_from_slice(&data);
+                Ok(result)
+            }
         }
     }
 
@@ -130,47 +421,71 @@ impl GitObject {
                 Ok(GitObject::Tree(entries))
             }
             "commit" => {
-                let content_str = std::str::from_utf8(content)?;
+                let (headers, message) = parse_headers(content)?;
+
                 let mut tree = None;
-                let mut parent = None;
+                let mut parents = Vec::new();
                 let mut author = None;
+                let mut committer = None;
                 let mut timestamp = None;
-                let mut message = String::new();
-
-                let mut lines = content_str.lines();
-                let mut in_message = false;
-
-                for line in lines.by_ref() {
-                    if in_message {
-                        if !message.is_empty() {
-                            message.push('\n');
-                        }
-                        message.push_str(line);
-                        continue;
-                    }
-
-                    if line.is_empty() {
-                        in_message = true;
-                        continue;
+                let mut extra_headers = Vec::new();
+                for (key, value) in headers {
+                    match key.as_str() {
+                        "tree" => tree = Some(value),
+                        "parent" => parents.push(value),
+                        "author" => author = Some(value),
+                        "committer" => committer = Some(value),
+                        "timestamp" => timestamp = Some(value.parse::<i64>()?),
+                        _ => extra_headers.push((key, value)),
                     }
+                }
+                let author = author.context("Missing author in commit")?;
 
-                    if let Some(t) = line.strip_prefix("tree ") {
-                        tree = Some(t.to_string());
-                    } else if let Some(p) = line.strip_prefix("parent ") {
-                        parent = Some(p.to_string());
-                    } else if let Some(a) = line.strip_prefix("author ") {
-                        author = Some(a.to_string());
-                    } else if let Some(ts) = line.strip_prefix("timestamp ") {
-                        timestamp = Some(ts.parse()?);
+                // Commits carry a committer line; older commits in the original format a timestamp line
+                let (author, committer) = match committer {
+                    Some(committer) => (
+                        Signature::parse_git(&author)?,
+                        Signature::parse_git(&committer)?,
+                    ),
+                    None => {
+                        let time = timestamp.context("Missing timestamp in commit")?;
+                        let signature = Signature::from_vcs(&author, time);
+                        (signature.clone(), signature)
                     }
-                }
+                };
 
                 Ok(GitObject::Commit(Commit {
                     tree: tree.context("Missing tree in commit")?,
-                    parent,
-                    author: author.context("Missing author in commit")?,
+                    parents,
+                    author,
+                    committer,
+                    extra_headers,
+                    message,
+                }))
+            }
+            "tag" => {
+                let (headers, message) = parse_headers(content)?;
+                let mut object = None;
+                let mut target_type = None;
+                let mut name = None;
+                let mut tagger = None;
+                let mut extra_headers = Vec::new();
+                for (key, value) in headers {
+                    match key.as_str() {
+                        "object" => object = Some(value),
+                        "type" => target_type = Some(value),
+                        "tag" => name = Some(value),
+                        "tagger" => tagger = Some(Signature::parse_git(&value)?),
+                        _ => extra_headers.push((key, value)),
+                    }
+                }
+                Ok(GitObject::Tag(Tag {
+                    object: object.context("Missing object in tag")?,
+                    target_type: target_type.context("Missing type in tag")?,
+                    name: name.context("Missing name in tag")?,
+                    tagger,
+                    extra_headers,
                     message,
-                    timestamp: timestamp.context("Missing timestamp in commit")?,
                 }))
             }
             _ => anyhow::bail!("Unknown object type: {}", obj_type),
@@ -181,3 +496,93 @@ impl GitObject {
 pub fn read_file(path: &Path) -> Result<Vec<u8>> {
     fs::read(path).with_context(|| format!("Failed to read file: {}", path.display()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_signatures_round_trip() {
+        let signature = Signature::parse_git("A U Thor <a@example.com> 1700000000 -0130").unwrap();
+        assert_eq!(signature.name, "A U Thor");
+        assert_eq!(signature.email, "a@example.com");
+        assert_eq!(signature.time, 1_700_000_000);
+        assert_eq!(signature.offset, -90);
+        assert_eq!(
+            signature.to_git(),
+            "A U Thor <a@example.com> 1700000000 -0130"
+        );
+    }
+
+    #[test]
+    fn malformed_git_signatures_are_errors() {
+        for value in [
+            "x > <y 0 +0000",
+            "><",
+            "Name a@example.com 0 +0000",
+            "Name <a@example.com>",
+            "Name <a@example.com> soon +0000",
+        ] {
+            assert!(
+                Signature::parse_git(value).is_err(),
+                "{} should be rejected",
+                value
+            );
+        }
+    }
+
+    fn hash(object: &GitObject) -> String {
+        crate::utils::hash_object(&object.serialize().unwrap())
+    }
+
+    fn entry(mode: &str, name: &str, hash: String) -> TreeEntry {
+        TreeEntry {
+            mode: mode.to_string(),
+            name: name.to_string(),
+            hash,
+        }
+    }
+
+    #[test]
+    fn git_format_objects_hash_like_git() {
+        assert_eq!(
+            hash(&GitObject::Tree(Vec::new())),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+        let hello = hash(&GitObject::Blob(b"hello\n".to_vec()));
+        assert_eq!(hello, "ce013625030ba8dba906f756967f9e9ca394464a");
+
+        // `sub` sorts after `sub.txt` in Git, as if it were `sub/`
+        let blob = |content: &str| hash(&GitObject::Blob(content.as_bytes().to_vec()));
+        let sub = hash(&GitObject::Tree(vec![entry(
+            "100644",
+            "file",
+            blob("inner\n"),
+        )]));
+        let mut entries = vec![
+            entry("40000", "sub", sub),
+            entry("100644", "sub.txt", blob("text\n")),
+            entry("100755", "run.sh", blob("#!/bin/sh\n")),
+            entry("120000", "link", blob("hello")),
+            entry("100644", "hello", hello),
+            entry("100644", "d-e", blob("dash\n")),
+        ];
+        sort_tree_entries(&mut entries, ObjectFormat::Git);
+        let tree = hash(&GitObject::Tree(entries));
+        assert_eq!(tree, "3e58d252cbf6e931a8590cf6108f2a4e7b7af1c7");
+
+        let commit = Commit {
+            tree,
+            parents: Vec::new(),
+            author: Signature::parse_git("A U Thor <author@example.com> 1700000000 +0130").unwrap(),
+            committer: Signature::parse_git("C O Mitter <committer@example.com> 1700003600 -0800")
+                .unwrap(),
+            extra_headers: Vec::new(),
+            message: "Known answer\n".to_string(),
+        };
+        assert_eq!(
+            hash(&GitObject::Commit(commit)),
+            "06969ac8cf74d2dd6eaf7f091c17cace953284fc"
+        );
+    }
+}
@@ -0,0 +1,963 @@
+use crate::utils;
+use anyhow::{Context, Result};
+use flate2::read::ZlibDecoder;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Packfile object type codes (same numbering as Git's pack format)
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+const IDX_MAGIC: &[u8; 4] = b"\xfftOc";
+/// How many preceding objects of the same kind are tried as delta bases.
+const DELTA_WINDOW: usize = 10;
+/// Longest delta chain allowed, bounding the work needed to read one object.
+const MAX_DELTA_DEPTH: usize = 50;
+/// Block size used to find matching regions when computing deltas.
+const DELTA_BLOCK: usize = 16;
+/// Most memory reserved up front from a size read out of a pack or delta;
+/// larger objects grow their buffers as data actually arrives.
+const MAX_PREALLOC: usize = 1 << 20;
+
+fn type_code(obj_type: &str) -> Result<u8> {
+    match obj_type {
+        "commit" => Ok(OBJ_COMMIT),
+        "tree" => Ok(OBJ_TREE),
+        "blob" => Ok(OBJ_BLOB),
+        "tag" => Ok(OBJ_TAG),
+        _ => anyhow::bail!("Cannot pack object of type {}", obj_type),
+    }
+}
+
+fn type_name(code: u8) -> Result<&'static str> {
+    match code {
+        OBJ_COMMIT => Ok("commit"),
+        OBJ_TREE => Ok("tree"),
+        OBJ_BLOB => Ok("blob"),
+        OBJ_TAG => Ok("tag"),
+        _ => anyhow::bail!("Unknown pack object type {}", code),
+    }
+}
+
+/// Splits serialized object bytes (`type size\0payload`) into type and payload.
+pub fn split_raw(raw: &[u8]) -> Result<(&str, &[u8])> {
+    let null_pos = raw
+        .iter()
+        .position(|&b| b == 0)
+        .context("Invalid object format")?;
+    let header = std::str::from_utf8(&raw[..null_pos])?;
+    let obj_type = header.split(' ').next().context("Invalid object header")?;
+    Ok((obj_type, &raw[null_pos + 1..]))
+}
+
+/// Rebuilds serialized object bytes from a type and payload.
+pub fn join_raw(obj_type: &str, payload: &[u8]) -> Vec<u8> {
+    let mut raw = format!("{} {}\0", obj_type, payload.len()).into_bytes();
+    raw.extend_from_slice(payload);
+    raw
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos).context("Truncated delta header")?;
+        *pos += 1;
+        push_size_bits(&mut value, &mut shift, byte)?;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Adds the low 7 bits of `byte` to a little-endian size varint, refusing
+/// sizes that do not fit in `usize` rather than overflowing the shift.
+fn push_size_bits(size: &mut usize, shift: &mut u32, byte: u8) -> Result<()> {
+    let bits = (byte & 0x7f) as usize;
+    if *shift >= usize::BITS || (bits << *shift) >> *shift != bits {
+        anyhow::bail!("Object size out of range");
+    }
+    *size |= bits << *shift;
+    *shift += 7;
+    Ok(())
+}
+
+/// Adds the next byte of an `OFS_DELTA` base distance (big-endian, with the
+/// implicit +1 per continuation), refusing distances that overflow.
+fn push_distance_bits(distance: u64, byte: u8) -> Result<u64> {
+    if distance >= 1 << 57 {
+        anyhow::bail!("Invalid delta base offset");
+    }
+    Ok(((distance + 1) << 7) | (byte & 0x7f) as u64)
+}
+
+fn flush_insert(out: &mut Vec<u8>, pending: &mut Vec<u8>) {
+    for chunk in pending.chunks(0x7f) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+    pending.clear();
+}
+
+fn push_copy(out: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        // Staying below 0x10000 avoids the "size 0 means 64KiB" special case
+        let chunk = len.min(0xffff);
+        let mut cmd = 0x80u8;
+        let mut args = Vec::with_capacity(6);
+        for i in 0..4 {
+            let byte = ((offset >> (8 * i)) & 0xff) as u8;
+            if byte != 0 {
+                cmd |= 1 << i;
+                args.push(byte);
+            }
+        }
+        for i in 0..2 {
+            let byte = ((chunk >> (8 * i)) & 0xff) as u8;
+            if byte != 0 {
+                cmd |= 0x10 << i;
+                args.push(byte);
+            }
+        }
+        out.push(cmd);
+        out.extend_from_slice(&args);
+        offset += chunk;
+        len -= chunk;
+    }
+}
+
+/// Encodes `target` as copy/insert instructions against `base`.
+pub fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, base.len());
+    write_varint(&mut out, target.len());
+
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    if base.len() >= DELTA_BLOCK {
+        for start in (0..=base.len() - DELTA_BLOCK).step_by(DELTA_BLOCK) {
+            blocks
+                .entry(&base[start..start + DELTA_BLOCK])
+                .or_insert(start);
+        }
+    }
+
+    let mut pending = Vec::new();
+    let mut pos = 0;
+    while pos < target.len() {
+        let found = (pos + DELTA_BLOCK <= target.len())
+            .then(|| blocks.get(&target[pos..pos + DELTA_BLOCK]))
+            .flatten();
+        let Some(&base_start) = found else {
+            pending.push(target[pos]);
+            pos += 1;
+            continue;
+        };
+
+        // Extend the match forwards, then backwards over bytes queued for insertion
+        let mut len = DELTA_BLOCK;
+        while pos + len < target.len()
+            && base_start + len < base.len()
+            && target[pos + len] == base[base_start + len]
+        {
+            len += 1;
+        }
+        let mut back = 0;
+        while back < pending.len()
+            && back < base_start
+            && pending[pending.len() - 1 - back] == base[base_start - 1 - back]
+        {
+            back += 1;
+        }
+        pending.truncate(pending.len() - back);
+        flush_insert(&mut out, &mut pending);
+        push_copy(&mut out, base_start - back, len + back);
+        pos += len;
+    }
+    flush_insert(&mut out, &mut pending);
+    out
+}
+
+/// Reconstructs an object from its delta base and delta instructions.
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    let base_len = read_varint(delta, &mut pos)?;
+    let target_len = read_varint(delta, &mut pos)?;
+    if base_len != base.len() {
+        anyhow::bail!("Delta base size mismatch");
+    }
+
+    let mut out = Vec::with_capacity(target_len.min(MAX_PREALLOC));
+    while pos < delta.len() {
+        let cmd = delta[pos];
+        pos += 1;
+        if cmd & 0x80 != 0 {
+            let mut read_arg = |bits: u8, shift: usize| -> Result<usize> {
+                if cmd & bits == 0 {
+                    return Ok(0);
+                }
+                let byte = *delta.get(pos).context("Truncated delta")?;
+                pos += 1;
+                Ok((byte as usize) << shift)
+            };
+            let offset =
+                read_arg(0x01, 0)? | read_arg(0x02, 8)? | read_arg(0x04, 16)? | read_arg(0x08, 24)?;
+            let mut size = read_arg(0x10, 0)? | read_arg(0x20, 8)? | read_arg(0x40, 16)?;
+            if size == 0 {
+                size = 0x10000;
+            }
+            if out.len() + size > target_len {
+                anyhow::bail!("Delta produced wrong size");
+            }
+            let chunk = base
+                .get(offset..offset + size)
+                .context("Delta copy out of range")?;
+            out.extend_from_slice(chunk);
+        } else if cmd != 0 {
+            let len = cmd as usize;
+            if out.len() + len > target_len {
+                anyhow::bail!("Delta produced wrong size");
+            }
+            let chunk = delta.get(pos..pos + len).context("Truncated delta")?;
+            out.extend_from_slice(chunk);
+            pos += len;
+        } else {
+            anyhow::bail!("Invalid delta instruction");
+        }
+    }
+
+    if out.len() != target_len {
+        anyhow::bail!("Delta produced wrong size");
+    }
+    Ok(out)
+}
+
+/// An object to be packed. `name` is a path hint used to pick good delta bases.
+pub struct PackEntry {
+    pub hash: String,
+    pub raw: Vec<u8>,
+    pub name: String,
+}
+
+fn write_object_header(out: &mut Vec<u8>, code: u8, size: usize) {
+    let mut byte = (code << 4) | (size & 0x0f) as u8;
+    let mut rest = size >> 4;
+    while rest != 0 {
+        out.push(byte | 0x80);
+        byte = (rest & 0x7f) as u8;
+        rest >>= 7;
+    }
+    out.push(byte);
+}
+
+fn write_ofs_offset(out: &mut Vec<u8>, distance: u64) {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    let mut rest = distance >> 7;
+    while rest != 0 {
+        rest -= 1;
+        bytes.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    bytes.reverse();
+    out.extend_from_slice(&bytes);
+}
+
+/// Offset and CRC of each object written to a pack, keyed by hash.
+pub struct PackLayout {
+    pub entries: Vec<(String, u64, u32)>,
+    pub checksum: [u8; 20],
+}
+
+/// Serializes objects into a packfile, delta-compressing similar objects.
+pub fn build_pack(mut objects: Vec<PackEntry>) -> Result<(Vec<u8>, PackLayout)> {
+    // Group similar objects together: same type, same file name, largest first
+    let mut keyed = Vec::with_capacity(objects.len());
+    for obj in objects.drain(..) {
+        let (obj_type, payload) = split_raw(&obj.raw)?;
+        let code = type_code(obj_type)?;
+        let payload = payload.to_vec();
+        keyed.push((code, obj.name, obj.hash, payload));
+    }
+    keyed.sort_by(|a, b| {
+        (a.0, &a.1)
+            .cmp(&(b.0, &b.1))
+            .then(b.3.len().cmp(&a.3.len()))
+    });
+
+    let mut pack = Vec::new();
+    pack.extend_from_slice(b"PACK");
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&(keyed.len() as u32).to_be_bytes());
+
+    let mut entries = Vec::with_capacity(keyed.len());
+    // (index into keyed, offset, delta depth) for the recent window
+    let mut window: Vec<(usize, u64, usize)> = Vec::new();
+    for (i, (code, _, hash, payload)) in keyed.iter().enumerate() {
+        let offset = pack.len() as u64;
+
+        let mut best: Option<(Vec<u8>, u64, usize)> = None;
+        for &(j, base_offset, depth) in window.iter().rev() {
+            let (base_code, _, _, base_payload) = &keyed[j];
+            if base_code != code || depth >= MAX_DELTA_DEPTH || base_payload.len() < 64 {
+                continue;
+            }
+            let delta = create_delta(base_payload, payload);
+            let limit = best.as_ref().map_or(payload.len() / 2, |b| b.0.len());
+            if delta.len() < limit {
+                best = Some((delta, base_offset, depth + 1));
+            }
+        }
+
+        let mut record = Vec::new();
+        let depth = match best {
+            Some((delta, base_offset, depth)) => {
+                write_object_header(&mut record, OBJ_OFS_DELTA, delta.len());
+                write_ofs_offset(&mut record, offset - base_offset);
+                record.extend_from_slice(&utils::compress(&delta)?);
+                depth
+            }
+            None => {
+                write_object_header(&mut record, *code, payload.len());
+                record.extend_from_slice(&utils::compress(payload)?);
+                0
+            }
+        };
+
+        let mut crc = flate2::Crc::new();
+        crc.update(&record);
+        entries.push((hash.clone(), offset, crc.sum()));
+        pack.extend_from_slice(&record);
+
+        window.push((i, offset, depth));
+        if window.len() > DELTA_WINDOW {
+            window.remove(0);
+        }
+    }
+
+    let checksum: [u8; 20] = Sha1::digest(&pack).into();
+    pack.extend_from_slice(&checksum);
+    Ok((pack, PackLayout { entries, checksum }))
+}
+
+/// Builds a version 2 pack index for the layout of a pack.
+pub fn build_index(layout: &PackLayout) -> Result<Vec<u8>> {
+    let mut entries: Vec<([u8; 20], u64, u32)> = Vec::with_capacity(layout.entries.len());
+    for (hash, offset, crc) in &layout.entries {
+        let raw: [u8; 20] = hex::decode(hash)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid object hash {}", hash))?;
+        entries.push((raw, *offset, *crc));
+    }
+    entries.sort_by_key(|e| e.0);
+
+    let mut idx = Vec::new();
+    idx.extend_from_slice(IDX_MAGIC);
+    idx.extend_from_slice(&2u32.to_be_bytes());
+    let mut fanout = [0u32; 256];
+    for (name, _, _) in &entries {
+        fanout[name[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in fanout.iter_mut() {
+        total += *count;
+        *count = total;
+    }
+    for count in fanout {
+        idx.extend_from_slice(&count.to_be_bytes());
+    }
+    for (name, _, _) in &entries {
+        idx.extend_from_slice(name);
+    }
+    for (_, _, crc) in &entries {
+        idx.extend_from_slice(&crc.to_be_bytes());
+    }
+    let mut large = Vec::new();
+    for (_, offset, _) in &entries {
+        if *offset < 0x8000_0000 {
+            idx.extend_from_slice(&(*offset as u32).to_be_bytes());
+        } else {
+            idx.extend_from_slice(&(0x8000_0000 | large.len() as u32).to_be_bytes());
+            large.push(*offset);
+        }
+    }
+    for offset in large {
+        idx.extend_from_slice(&offset.to_be_bytes());
+    }
+    idx.extend_from_slice(&layout.checksum);
+    let idx_checksum: [u8; 20] = Sha1::digest(&idx).into();
+    idx.extend_from_slice(&idx_checksum);
+    Ok(idx)
+}
+
+/// Writes a pack and its index into `pack_dir`, returning the pack's path.
+pub fn write_pack(pack_dir: &Path, objects: Vec<PackEntry>) -> Result<PathBuf> {
+    let (pack, layout) = build_pack(objects)?;
+    store_pack(pack_dir, &pack, &layout)
+}
+
+/// Writes a pack and an index for its layout into a pack directory.
+pub fn store_pack(pack_dir: &Path, pack: &[u8], layout: &PackLayout) -> Result<PathBuf> {
+    let idx = build_index(layout)?;
+    let name = format!("pack-{}", hex::encode(layout.checksum));
+    fs::create_dir_all(pack_dir)?;
+
+    let pack_path = pack_dir.join(format!("{}.pack", name));
+    let idx_path = pack_dir.join(format!("{}.idx", name));
+    for (path, data) in [(&pack_path, pack), (&idx_path, &idx[..])] {
+        // The index is renamed into place last, so readers never see an index without its pack
+        let mut temp = tempfile::NamedTempFile::new_in(pack_dir)?;
+        temp.write_all(data)?;
+        temp.flush()?;
+        temp.persist(path)
+            .with_context(|| format!("Failed to persist {}", path.display()))?;
+    }
+    Ok(pack_path)
+}
+
+enum Record {
+    Full(&'static str, Vec<u8>),
+    OfsDelta(u64, Vec<u8>),
+    RefDelta(String, Vec<u8>),
+}
+
+/// Parses a received pack stream and computes its layout, resolving deltas.
+/// `lookup` resolves REF_DELTA bases that are not in the pack itself.
+pub fn index_pack(data: &[u8], lookup: &dyn Fn(&str) -> Result<Vec<u8>>) -> Result<PackLayout> {
+    if data.len() < 32 || &data[..4] != b"PACK" || data[4..8] != 2u32.to_be_bytes() {
+        anyhow::bail!("Invalid pack stream");
+    }
+    let body = &data[..data.len() - 20];
+    let checksum: [u8; 20] = data[data.len() - 20..].try_into()?;
+    if <[u8; 20]>::from(Sha1::digest(body)) != checksum {
+        anyhow::bail!("Pack checksum mismatch");
+    }
+    let count = u32::from_be_bytes(data[8..12].try_into()?) as usize;
+
+    let mut records = Vec::with_capacity(count.min(body.len()));
+    let mut pos = 12;
+    for _ in 0..count {
+        let offset = pos;
+        let byte = *body.get(pos).context("Truncated pack stream")?;
+        pos += 1;
+        let code = (byte >> 4) & 0x07;
+        let mut size = (byte & 0x0f) as usize;
+        let mut shift = 4;
+        let mut more = byte & 0x80 != 0;
+        while more {
+            let byte = *body.get(pos).context("Truncated pack stream")?;
+            pos += 1;
+            push_size_bits(&mut size, &mut shift, byte)?;
+            more = byte & 0x80 != 0;
+        }
+
+        let base = match code {
+            OBJ_OFS_DELTA => {
+                let mut byte = *body.get(pos).context("Truncated pack stream")?;
+                pos += 1;
+                let mut distance = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    byte = *body.get(pos).context("Truncated pack stream")?;
+                    pos += 1;
+                    distance = push_distance_bits(distance, byte)?;
+                }
+                Some(Ok((offset as u64)
+                    .checked_sub(distance)
+                    .context("Invalid delta base offset")?))
+            }
+            OBJ_REF_DELTA => {
+                let hash = body.get(pos..pos + 20).context("Truncated pack stream")?;
+                pos += 20;
+                Some(Err(hex::encode(hash)))
+            }
+            _ => None,
+        };
+
+        // Inflate at most one byte past the declared size, so a small stream
+        // cannot expand without bound
+        let mut decoder = flate2::bufread::ZlibDecoder::new(&body[pos..]);
+        let mut payload = Vec::with_capacity(size.min(MAX_PREALLOC));
+        (&mut decoder)
+            .take(size as u64 + 1)
+            .read_to_end(&mut payload)?;
+        if payload.len() != size {
+            anyhow::bail!("Pack object has wrong size");
+        }
+        pos += decoder.total_in() as usize;
+
+        let mut crc = flate2::Crc::new();
+        crc.update(&body[offset..pos]);
+        let record = match base {
+            Some(Ok(base_offset)) => Record::OfsDelta(base_offset, payload),
+            Some(Err(base_hash)) => Record::RefDelta(base_hash, payload),
+            None => Record::Full(type_name(code)?, payload),
+        };
+        records.push((offset as u64, crc.sum(), record));
+    }
+    if pos != body.len() {
+        anyhow::bail!("Trailing data in pack stream");
+    }
+
+    // Resolve objects in passes: bases usually precede their deltas, so one pass
+    // tends to suffice; REF_DELTA bases missing from the pack come from `lookup`.
+    let by_offset: HashMap<u64, usize> = records
+        .iter()
+        .enumerate()
+        .map(|(i, (offset, _, _))| (*offset, i))
+        .collect();
+    let mut resolved: Vec<Option<(&'static str, Vec<u8>)>> = vec![None; records.len()];
+    let mut by_hash: HashMap<String, usize> = HashMap::new();
+    let mut entries = Vec::with_capacity(records.len());
+    while entries.len() < records.len() {
+        let before = entries.len();
+        for i in 0..records.len() {
+            if resolved[i].is_some() {
+                continue;
+            }
+            let object = match &records[i].2 {
+                Record::Full(obj_type, payload) => Some((*obj_type, payload.clone())),
+                Record::OfsDelta(base_offset, delta) => {
+                    let base = *by_offset
+                        .get(base_offset)
+                        .context("Invalid delta base offset")?;
+                    match &resolved[base] {
+                        Some((obj_type, base)) => Some((*obj_type, apply_delta(base, delta)?)),
+                        None => None,
+                    }
+                }
+                Record::RefDelta(base_hash, delta) => match by_hash.get(base_hash) {
+                    Some(&base) => {
+                        let (obj_type, base) = resolved[base].as_ref().unwrap();
+                        Some((*obj_type, apply_delta(base, delta)?))
+                    }
+                    None => None,
+                },
+            };
+            if let Some((obj_type, payload)) = object {
+                let hash = hex::encode(Sha1::digest(join_raw(obj_type, &payload)));
+                by_hash.insert(hash.clone(), i);
+                entries.push((hash, records[i].0, records[i].1));
+                resolved[i] = Some((obj_type, payload));
+            }
+        }
+        if entries.len() > before {
+            continue;
+        }
+
+        // No progress: the remaining chains start at bases outside the pack
+        let (i, base_hash, delta) = records
+            .iter()
+            .enumerate()
+            .find_map(|(i, (_, _, record))| match record {
+                Record::RefDelta(hash, delta) if resolved[i].is_none() => Some((i, hash, delta)),
+                _ => None,
+            })
+            .context("Unresolvable deltas in pack stream")?;
+        let raw = lookup(base_hash)?;
+        let (obj_type, base) = split_raw(&raw)?;
+        let obj_type = type_name(type_code(obj_type)?)?;
+        let payload = apply_delta(base, delta)?;
+        let hash = hex::encode(Sha1::digest(join_raw(obj_type, &payload)));
+        by_hash.insert(hash.clone(), i);
+        entries.push((hash, records[i].0, records[i].1));
+        resolved[i] = Some((obj_type, payload));
+    }
+    Ok(PackLayout { entries, checksum })
+}
+
+/// A packfile together with its in-memory index.
+pub struct PackFile {
+    pub pack_path: PathBuf,
+    idx: Vec<u8>,
+    count: usize,
+}
+
+impl PackFile {
+    /// Opens `pack-*.idx` and its matching `.pack`.
+    pub fn open(idx_path: &Path) -> Result<Self> {
+        let idx = fs::read(idx_path)
+            .with_context(|| format!("Failed to read pack index {}", idx_path.display()))?;
+        if idx.len() < 8 + 256 * 4 || &idx[..4] != IDX_MAGIC || idx[4..8] != 2u32.to_be_bytes() {
+            anyhow::bail!("Unsupported pack index format: {}", idx_path.display());
+        }
+        let count = u32::from_be_bytes(idx[8 + 255 * 4..8 + 256 * 4].try_into()?) as usize;
+        if idx.len() < 8 + 256 * 4 + count * 28 + 40 {
+            anyhow::bail!("Truncated pack index: {}", idx_path.display());
+        }
+        Ok(PackFile {
+            pack_path: idx_path.with_extension("pack"),
+            idx,
+            count,
+        })
+    }
+
+    /// Loads every `*.idx` in a pack directory.
+    pub fn load_dir(pack_dir: &Path) -> Result<Vec<PackFile>> {
+        let mut packs = Vec::new();
+        if !pack_dir.is_dir() {
+            return Ok(packs);
+        }
+        for entry in fs::read_dir(pack_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "idx") && path.with_extension("pack").exists()
+            {
+                packs.push(PackFile::open(&path)?);
+            }
+        }
+        Ok(packs)
+    }
+
+    fn fanout(&self, byte: usize) -> usize {
+        let pos = 8 + byte * 4;
+        u32::from_be_bytes(self.idx[pos..pos + 4].try_into().unwrap()) as usize
+    }
+
+    fn name(&self, i: usize) -> &[u8] {
+        let pos = 8 + 256 * 4 + i * 20;
+        &self.idx[pos..pos + 20]
+    }
+
+    fn offset(&self, i: usize) -> u64 {
+        let offsets = 8 + 256 * 4 + self.count * 24;
+        let pos = offsets + i * 4;
+        let small = u32::from_be_bytes(self.idx[pos..pos + 4].try_into().unwrap());
+        if small & 0x8000_0000 == 0 {
+            return small as u64;
+        }
+        let large = offsets + self.count * 4 + (small & 0x7fff_ffff) as usize * 8;
+        u64::from_be_bytes(self.idx[large..large + 8].try_into().unwrap())
+    }
+
+    /// Binary-searches the index for an object, returning its offset in the pack.
+    pub fn find(&self, hash: &str) -> Option<u64> {
+        let raw = hex::decode(hash).ok()?;
+        if raw.len() != 20 {
+            return None;
+        }
+        let first = raw[0] as usize;
+        let (mut lo, mut hi) = (
+            if first == 0 {
+                0
+            } else {
+                self.fanout(first - 1)
+            },
+            self.fanout(first),
+        );
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.name(mid).cmp(&raw[..]) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(self.offset(mid)),
+            }
+        }
+        None
+    }
+
+    /// Hex names of every object in the pack.
+    pub fn hashes(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.count).map(|i| hex::encode(self.name(i)))
+    }
+
+    /// Reads the object at `offset` as (type, payload), resolving delta chains.
+    /// `lookup` resolves REF_DELTA bases that live outside this pack.
+    pub fn read_at(
+        &self,
+        offset: u64,
+        lookup: &dyn Fn(&str) -> Result<Vec<u8>>,
+    ) -> Result<(&'static str, Vec<u8>)> {
+        let file = File::open(&self.pack_path)
+            .with_context(|| format!("Failed to open {}", self.pack_path.display()))?;
+        let mut reader = BufReader::new(file);
+        self.read_entry(&mut reader, offset, lookup, 0)
+    }
+
+    fn read_entry(
+        &self,
+        reader: &mut BufReader<File>,
+        offset: u64,
+        lookup: &dyn Fn(&str) -> Result<Vec<u8>>,
+        depth: usize,
+    ) -> Result<(&'static str, Vec<u8>)> {
+        if depth > 10_000 {
+            anyhow::bail!("Delta chain too deep in {}", self.pack_path.display());
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        let code = (byte[0] >> 4) & 0x07;
+        let mut size = (byte[0] & 0x0f) as usize;
+        let mut shift = 4;
+        while byte[0] & 0x80 != 0 {
+            reader.read_exact(&mut byte)?;
+            push_size_bits(&mut size, &mut shift, byte[0])?;
+        }
+
+        match code {
+            OBJ_OFS_DELTA => {
+                reader.read_exact(&mut byte)?;
+                let mut distance = (byte[0] & 0x7f) as u64;
+                while byte[0] & 0x80 != 0 {
+                    reader.read_exact(&mut byte)?;
+                    distance = push_distance_bits(distance, byte[0])?;
+                }
+                let delta = inflate(reader, size)?;
+                let base_offset = offset
+                    .checked_sub(distance)
+                    .context("Invalid delta base offset")?;
+                let (obj_type, base) = self.read_entry(reader, base_offset, lookup, depth + 1)?;
+                Ok((obj_type, apply_delta(&base, &delta)?))
+            }
+            OBJ_REF_DELTA => {
+                let mut base_hash = [0u8; 20];
+                reader.read_exact(&mut base_hash)?;
+                let delta = inflate(reader, size)?;
+                let base_hash = hex::encode(base_hash);
+                let (obj_type, base) = match self.find(&base_hash) {
+                    Some(base_offset) => self.read_entry(reader, base_offset, lookup, depth + 1)?,
+                    None => {
+                        let raw = lookup(&base_hash)?;
+                        let (obj_type, payload) = split_raw(&raw)?;
+                        (type_name(type_code(obj_type)?)?, payload.to_vec())
+                    }
+                };
+                Ok((obj_type, apply_delta(&base, &delta)?))
+            }
+            _ => Ok((type_name(code)?, inflate(reader, size)?)),
+        }
+    }
+}
+
+fn inflate(reader: &mut BufReader<File>, size: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size.min(MAX_PREALLOC));
+    ZlibDecoder::new(reader)
+        .take(size as u64 + 1)
+        .read_to_end(&mut data)?;
+    if data.len() != size {
+        anyhow::bail!("Pack object has wrong size");
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(content: &[u8]) -> PackEntry {
+        let raw = join_raw("blob", content);
+        PackEntry {
+            hash: hex::encode(Sha1::digest(&raw)),
+            raw,
+            name: "file.txt".to_string(),
+        }
+    }
+
+    /// An undeltified record: header bytes followed by the compressed payload.
+    fn record(header: &[u8], payload: &[u8]) -> Vec<u8> {
+        [header, &utils::compress(payload).unwrap()].concat()
+    }
+
+    /// Wraps pack records in a header and trailing checksum.
+    fn seal(count: u32, records: &[u8]) -> Vec<u8> {
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&count.to_be_bytes());
+        pack.extend_from_slice(records);
+        let checksum: [u8; 20] = Sha1::digest(&pack).into();
+        pack.extend_from_slice(&checksum);
+        pack
+    }
+
+    fn no_lookup(hash: &str) -> Result<Vec<u8>> {
+        anyhow::bail!("unexpected lookup of {}", hash)
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        let long: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut edited = long.clone();
+        edited[100_000] ^= 0xff;
+        edited.extend_from_slice(b"tail");
+        let cases: Vec<(&[u8], &[u8])> = vec![
+            (b"", b""),
+            (b"", b"new content"),
+            (b"old content", b""),
+            (b"same", b"same"),
+            (b"hello world\n", b"hello brave new world\n"),
+            (b"prefix and the rest", b"the rest"),
+            (&long, &edited),
+        ];
+        for (base, target) in cases {
+            let delta = create_delta(base, target);
+            assert_eq!(apply_delta(base, &delta).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn delta_of_similar_content_is_small() {
+        let base: Vec<u8> = (0..10_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut target = base.clone();
+        target[5_000] = 7;
+        assert!(create_delta(&base, &target).len() < 200);
+    }
+
+    #[test]
+    fn apply_delta_rejects_bad_input() {
+        // Wrong base size
+        let delta = create_delta(b"abc", b"abd");
+        assert!(apply_delta(b"abcd", &delta).is_err());
+        // Copy past the end of the base
+        assert!(apply_delta(b"abc", &[3, 4, 0x91, 0, 4]).is_err());
+        // Truncated insert
+        assert!(apply_delta(b"", &[0, 4, 4, b'a']).is_err());
+        // Target size that is never produced must not be trusted for allocation
+        let mut huge = vec![0];
+        write_varint(&mut huge, usize::MAX);
+        assert!(apply_delta(b"", &huge).is_err());
+        // Size varint longer than a usize
+        assert!(apply_delta(b"", &[0xff; 12]).is_err());
+    }
+
+    #[test]
+    fn apply_delta_stops_at_the_target_size() {
+        // Copies of the whole 64 KiB base, far more than the declared target
+        let base = vec![b'x'; 0x10000];
+        let mut delta = Vec::new();
+        write_varint(&mut delta, base.len());
+        write_varint(&mut delta, 10);
+        for _ in 0..1000 {
+            delta.extend_from_slice(&[0x80]);
+        }
+        let err = apply_delta(&base, &delta).unwrap_err();
+        assert!(err.to_string().contains("wrong size"));
+        // Inserts past the target size fail the same way
+        assert!(apply_delta(b"", &[0, 2, 3, b'a', b'b', b'c']).is_err());
+        assert_eq!(
+            apply_delta(b"", &[0, 3, 3, b'a', b'b', b'c']).unwrap(),
+            b"abc"
+        );
+    }
+
+    #[test]
+    fn varints_round_trip_and_reject_overflow() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as usize, usize::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&out, &mut pos).unwrap(), value);
+            assert_eq!(pos, out.len());
+        }
+        let mut overlong = vec![0xff; 9];
+        overlong.push(0x7f);
+        assert!(read_varint(&overlong, &mut 0).is_err());
+        assert!(read_varint(&[0x80, 0x80], &mut 0).is_err());
+    }
+
+    #[test]
+    fn built_packs_index_and_read_back() {
+        let mut contents: Vec<Vec<u8>> = (0..20)
+            .map(|i| {
+                format!("line one\nline two {}\n", i)
+                    .repeat(50)
+                    .into_bytes()
+            })
+            .collect();
+        contents.push(Vec::new());
+        let objects: Vec<PackEntry> = contents.iter().map(|c| blob(c)).collect();
+        let hashes: Vec<String> = objects.iter().map(|o| o.hash.clone()).collect();
+
+        let (pack, layout) = build_pack(objects).unwrap();
+        let reindexed = index_pack(&pack, &no_lookup).unwrap();
+        let mut built = layout.entries.clone();
+        let mut parsed = reindexed.entries.clone();
+        built.sort();
+        parsed.sort();
+        assert_eq!(built, parsed);
+
+        let dir = tempfile::tempdir().unwrap();
+        let pack_path = store_pack(dir.path(), &pack, &layout).unwrap();
+        let packs = PackFile::load_dir(dir.path()).unwrap();
+        assert_eq!(packs.len(), 1);
+        assert_eq!(packs[0].pack_path, pack_path);
+        assert_eq!(packs[0].hashes().count(), contents.len());
+        for (hash, content) in hashes.iter().zip(&contents) {
+            let offset = packs[0].find(hash).expect("object is indexed");
+            let (obj_type, payload) = packs[0].read_at(offset, &no_lookup).unwrap();
+            assert_eq!(obj_type, "blob");
+            assert_eq!(&payload, content);
+        }
+        assert_eq!(packs[0].find(&"0".repeat(40)), None);
+        assert_eq!(packs[0].find("not a hash"), None);
+    }
+
+    #[test]
+    fn index_pack_rejects_malformed_streams() {
+        let good = seal(1, &record(&[OBJ_BLOB << 4 | 3], b"abc"));
+        assert!(index_pack(&good, &no_lookup).is_ok());
+
+        let mut corrupt = good.clone();
+        corrupt[14] ^= 1;
+        assert!(index_pack(&corrupt, &no_lookup).is_err());
+        assert!(index_pack(b"PACK", &no_lookup).is_err());
+
+        // A huge object count must fail on the truncated stream, not allocate
+        assert!(index_pack(&seal(u32::MAX, &[]), &no_lookup).is_err());
+
+        // Object size varint that overflows the shift
+        let mut overlong = vec![OBJ_BLOB << 4 | 0x8f];
+        overlong.extend_from_slice(&[0xff; 10]);
+        overlong.push(0x01);
+        let Err(err) = index_pack(&seal(1, &overlong), &no_lookup) else {
+            panic!("overlong size accepted");
+        };
+        assert!(err.to_string().contains("out of range"));
+
+        // Offset delta base distance that overflows
+        let mut distance = vec![OBJ_OFS_DELTA << 4 | 1];
+        distance.extend_from_slice(&[0xff; 10]);
+        distance.push(0x7f);
+        assert!(index_pack(&seal(1, &distance), &no_lookup).is_err());
+
+        // Offset delta pointing before the start of the pack
+        let before = record(&[OBJ_OFS_DELTA << 4 | 2, 0x7f], &[0, 0]);
+        assert!(index_pack(&seal(1, &before), &no_lookup).is_err());
+
+        // Declared size smaller than the inflated data
+        let short = seal(1, &record(&[OBJ_BLOB << 4 | 2], b"abc"));
+        assert!(index_pack(&short, &no_lookup).is_err());
+    }
+
+    #[test]
+    fn read_at_stops_inflating_at_the_declared_size() {
+        // A small declared size in front of a large compressed payload
+        let records = record(&[OBJ_BLOB << 4 | 4], &vec![b'x'; 1 << 20]);
+        let pack = seal(1, &records);
+        let layout = PackLayout {
+            entries: vec![("ab".repeat(20), 12, 0)],
+            checksum: pack[pack.len() - 20..].try_into().unwrap(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        store_pack(dir.path(), &pack, &layout).unwrap();
+        let packs = PackFile::load_dir(dir.path()).unwrap();
+        let offset = packs[0].find(&"ab".repeat(20)).unwrap();
+        let err = packs[0].read_at(offset, &no_lookup).unwrap_err();
+        assert!(err.to_string().contains("wrong size"));
+    }
+}
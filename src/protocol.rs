@@ -0,0 +1,528 @@
+use crate::history;
+use crate::objects::ObjectFormat;
+use crate::pack::{self, PackEntry};
+use crate::remote::{Advertisement, RefStatus, RefUpdate, Transport};
+use crate::repository::{validate_branch_name, Repository};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Largest payload that fits in one pkt-line.
+const MAX_PKT_DATA: usize = 65516;
+
+/// Writes one pkt-line: four hex digits of total length, then the data.
+pub fn write_pkt(out: &mut impl Write, data: &[u8]) -> Result<()> {
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+/// Writes a flush packet, which ends a section.
+pub fn write_flush(out: &mut impl Write) -> Result<()> {
+    out.write_all(b"0000")?;
+    Ok(())
+}
+
+/// Reads one pkt-line; `None` is a flush packet.
+pub fn read_pkt(input: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    input
+        .read_exact(&mut len)
+        .context("Connection closed unexpectedly")?;
+    let len =
+        usize::from_str_radix(std::str::from_utf8(&len)?, 16).context("Invalid packet length")?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if len < 4 {
+        anyhow::bail!("Invalid packet length");
+    }
+    let mut data = vec![0u8; len - 4];
+    input.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+/// Reads a text pkt-line without its trailing newline, surfacing `ERR` packets.
+fn read_line(input: &mut impl Read) -> Result<Option<String>> {
+    let Some(data) = read_pkt(input)? else {
+        return Ok(None);
+    };
+    let line = String::from_utf8(data).context("Invalid packet")?;
+    let line = line.strip_suffix('\n').unwrap_or(&line).to_string();
+    if let Some(message) = line.strip_prefix("ERR ") {
+        anyhow::bail!("Remote error: {}", message);
+    }
+    Ok(Some(line))
+}
+
+fn write_line(out: &mut impl Write, line: &str) -> Result<()> {
+    write_pkt(out, format!("{}\n", line).as_bytes())
+}
+
+/// Sends pack data as a run of pkt-lines ended by a flush.
+fn write_pack_data(out: &mut impl Write, data: &[u8]) -> Result<()> {
+    for chunk in data.chunks(MAX_PKT_DATA) {
+        write_pkt(out, chunk)?;
+    }
+    write_flush(out)
+}
+
+fn read_pack_data(input: &mut impl Read) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = read_pkt(input)? {
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Packs the objects the other side lacks.
+fn pack_missing(repo: &Repository, wants: &[String], haves: &[String]) -> Result<Vec<u8>> {
+    let mut entries = Vec::new();
+    for (hash, name) in history::missing_objects(repo, wants, haves)? {
+        entries.push(PackEntry {
+            raw: repo.read_raw_object(&hash)?,
+            hash,
+            name,
+        });
+    }
+    Ok(pack::build_pack(entries)?.0)
+}
+
+fn check_hash(hash: &str) -> Result<()> {
+    if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid object name: {}", hash);
+    }
+    Ok(())
+}
+
+/// Sends the object format, checked-out branch and branch tips.
+pub fn write_advertisement(repo: &Repository, out: &mut impl Write) -> Result<()> {
+    let advertisement = crate::remote::advertise(repo)?;
+    write_line(out, &format!("format {}", advertisement.format.as_str()))?;
+    if let Some(head) = &advertisement.head {
+        write_line(out, &format!("head {}", head))?;
+    }
+    for (branch, hash) in &advertisement.branches {
+        write_line(out, &format!("{} refs/heads/{}", hash, branch))?;
+    }
+    write_flush(out)
+}
+
+pub fn read_advertisement(input: &mut impl Read) -> Result<Advertisement> {
+    let mut branches = BTreeMap::new();
+    let mut head = None;
+    let mut format = None;
+    while let Some(line) = read_line(input)? {
+        if let Some(name) = line.strip_prefix("format ") {
+            format = Some(ObjectFormat::parse(name)?);
+        } else if let Some(name) = line.strip_prefix("head ") {
+            // Names become paths under .vcs/refs, so a hostile remote must not
+            // be able to smuggle in "../" or other invalid names
+            validate_branch_name(name).context("Invalid advertisement")?;
+            head = Some(name.to_string());
+        } else {
+            let (hash, name) = line.split_once(' ').context("Invalid advertisement")?;
+            let branch = name
+                .strip_prefix("refs/heads/")
+                .context("Invalid advertisement")?;
+            validate_branch_name(branch).context("Invalid advertisement")?;
+            check_hash(hash)?;
+            branches.insert(branch.to_string(), hash.to_string());
+        }
+    }
+    Ok(Advertisement {
+        branches,
+        head,
+        format: format.context("Remote did not advertise an object format")?,
+    })
+}
+
+/// Answers a fetch request: reads `want`/`have` lines up to `done` and sends
+/// a pack of what the client is missing. A client that hangs up instead of
+/// asking for anything is not an error.
+pub fn serve_fetch(repo: &Repository, input: &mut impl Read, out: &mut impl Write) -> Result<()> {
+    let mut wants = Vec::new();
+    let mut haves = Vec::new();
+    let mut first = true;
+    loop {
+        let line = match read_line(input) {
+            Ok(Some(line)) => line,
+            Ok(None) if first => return Ok(()),
+            Err(_) if first => return Ok(()),
+            result => result?.context("Invalid fetch request")?,
+        };
+        first = false;
+        if line == "done" {
+            break;
+        } else if let Some(hash) = line.strip_prefix("want ") {
+            check_hash(hash)?;
+            if !repo.has_object(hash)? {
+                anyhow::bail!("Not our object: {}", hash);
+            }
+            wants.push(hash.to_string());
+        } else if let Some(hash) = line.strip_prefix("have ") {
+            check_hash(hash)?;
+            if repo.has_object(hash)? {
+                haves.push(hash.to_string());
+            }
+        } else {
+            anyhow::bail!("Invalid fetch request: {}", line);
+        }
+    }
+    write_pack_data(out, &pack_missing(repo, &wants, &haves)?)
+}
+
+/// Answers a push request: reads `update` lines and a pack, stores the pack and
+/// reports `ok` or `ng <reason>` for each branch.
+pub fn serve_push(repo: &Repository, input: &mut impl Read, out: &mut impl Write) -> Result<()> {
+    let mut updates = Vec::new();
+    loop {
+        let line = match read_line(input) {
+            Ok(line) => line,
+            Err(_) if updates.is_empty() => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let Some(line) = line else { break };
+        let mut parts = line.splitn(4, ' ');
+        let (Some("update"), Some(new), Some(branch), Some(mode)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("Invalid push request: {}", line);
+        };
+        check_hash(new)?;
+        updates.push(RefUpdate {
+            branch: branch.to_string(),
+            new: new.to_string(),
+            force: mode == "force",
+        });
+    }
+    if updates.is_empty() {
+        return Ok(());
+    }
+
+    let data = read_pack_data(input)?;
+    let _lock = crate::lock::RepoLock::new(repo).context("Repository is locked")?;
+    repo.store_pack(&data)?;
+    for (branch, status) in crate::remote::apply_ref_updates(repo, &updates)? {
+        match status {
+            Ok(()) => write_line(out, &format!("ok {}", branch))?,
+            Err(reason) => write_line(out, &format!("ng {} {}", branch, reason))?,
+        }
+    }
+    write_flush(out)
+}
+
+/// Runs a server side of the protocol on stdin/stdout, reporting failures to
+/// the client as an `ERR` packet.
+fn serve_stdio(path: &str, push: bool) -> Result<()> {
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+
+    let result = Repository::new(path.into()).and_then(|repo| {
+        if !repo.vcs_dir.is_dir() {
+            anyhow::bail!("Not a VCS repository: {}", path);
+        }
+        write_advertisement(&repo, &mut out)?;
+        out.flush()?;
+        if push {
+            serve_push(&repo, &mut input, &mut out)
+        } else {
+            serve_fetch(&repo, &mut input, &mut out)
+        }
+    });
+    if let Err(e) = &result {
+        let _ = write_line(&mut out, &format!("ERR {}", e));
+    }
+    out.flush()?;
+    result
+}
+
+/// Serves fetches from the repository at `path` over stdin/stdout.
+pub fn upload_pack(path: &str) -> Result<()> {
+    serve_stdio(path, false)
+}
+
+/// Accepts pushes into the repository at `path` over stdin/stdout.
+pub fn receive_pack(path: &str) -> Result<()> {
+    serve_stdio(path, true)
+}
+
+/// Sends a fetch request for `wants`, listing `haves` so the server can leave
+/// out what is already here.
+pub fn write_fetch_request(out: &mut impl Write, wants: &[String], haves: &[String]) -> Result<()> {
+    for want in wants {
+        write_line(out, &format!("want {}", want))?;
+    }
+    for have in haves {
+        write_line(out, &format!("have {}", have))?;
+    }
+    write_line(out, "done")
+}
+
+/// Reads the pack answering a fetch and stores it in `repo`.
+pub fn read_fetch_response(repo: &Repository, input: &mut impl Read) -> Result<usize> {
+    let data = read_pack_data(input)?;
+    repo.store_pack(&data)
+}
+
+/// Sends branch updates followed by a pack of the objects the server lacks.
+pub fn write_push_request(
+    repo: &Repository,
+    out: &mut impl Write,
+    updates: &[RefUpdate],
+    haves: &[String],
+) -> Result<()> {
+    for update in updates {
+        let mode = if update.force {
+            "force"
+        } else {
+            "fast-forward"
+        };
+        write_line(
+            out,
+            &format!("update {} {} {}", update.new, update.branch, mode),
+        )?;
+    }
+    write_flush(out)?;
+    let wants: Vec<String> = updates.iter().map(|u| u.new.clone()).collect();
+    write_pack_data(out, &pack_missing(repo, &wants, haves)?)
+}
+
+pub fn read_push_response(input: &mut impl Read) -> Result<Vec<RefStatus>> {
+    let mut statuses = Vec::new();
+    while let Some(line) = read_line(input)? {
+        if let Some(branch) = line.strip_prefix("ok ") {
+            statuses.push((branch.to_string(), Ok(())));
+        } else if let Some(rest) = line.strip_prefix("ng ") {
+            let (branch, reason) = rest.split_once(' ').unwrap_or((rest, "rejected"));
+            statuses.push((branch.to_string(), Err(reason.to_string())));
+        } else {
+            anyhow::bail!("Invalid push response: {}", line);
+        }
+    }
+    Ok(statuses)
+}
+
+/// A repository served by a child process speaking the protocol over its
+/// stdin/stdout, such as `ssh host vcs upload-pack <path>`.
+pub struct PipeTransport {
+    child: Child,
+    input: BufReader<ChildStdout>,
+    output: Option<ChildStdin>,
+    advertisement: Option<Advertisement>,
+}
+
+impl PipeTransport {
+    pub fn spawn(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {:?}", command.get_program()))?;
+        let input = BufReader::new(child.stdout.take().unwrap());
+        let output = child.stdin.take();
+        Ok(PipeTransport {
+            child,
+            input,
+            output,
+            advertisement: None,
+        })
+    }
+
+    fn output(&mut self) -> Result<&mut ChildStdin> {
+        self.output.as_mut().context("Connection already closed")
+    }
+
+    /// Hangs up and checks that the server exited cleanly.
+    fn finish(&mut self) -> Result<()> {
+        self.output = None;
+        let status = self.child.wait()?;
+        if !status.success() {
+            anyhow::bail!("Remote command failed ({})", status);
+        }
+        Ok(())
+    }
+}
+
+impl Transport for PipeTransport {
+    fn advertise(&mut self) -> Result<Advertisement> {
+        if self.advertisement.is_none() {
+            if self.input.fill_buf()?.is_empty() {
+                self.finish()?;
+                anyhow::bail!("Remote closed the connection");
+            }
+            self.advertisement = Some(read_advertisement(&mut self.input)?);
+        }
+        Ok(self.advertisement.clone().unwrap())
+    }
+
+    fn fetch(&mut self, repo: &Repository, wants: &[String], haves: &[String]) -> Result<usize> {
+        self.advertise()?;
+        if wants.is_empty() {
+            self.finish()?;
+            return Ok(0);
+        }
+        let output = self.output()?;
+        write_fetch_request(output, wants, haves)?;
+        output.flush()?;
+        let received = read_fetch_response(repo, &mut self.input)?;
+        self.finish()?;
+        Ok(received)
+    }
+
+    fn push(
+        &mut self,
+        repo: &Repository,
+        updates: &[RefUpdate],
+        haves: &[String],
+    ) -> Result<Vec<RefStatus>> {
+        self.advertise()?;
+        let output = self.output()?;
+        write_push_request(repo, output, updates, haves)?;
+        output.flush()?;
+        let statuses = read_push_response(&mut self.input)?;
+        self.finish()?;
+        Ok(statuses)
+    }
+}
+
+impl Drop for PipeTransport {
+    fn drop(&mut self) {
+        self.output = None;
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{commit, repo};
+
+    #[test]
+    fn pkt_lines_round_trip() {
+        let mut out = Vec::new();
+        write_pkt(&mut out, b"hello\n").unwrap();
+        write_flush(&mut out).unwrap();
+        write_pkt(&mut out, b"").unwrap();
+        assert_eq!(out, b"000ahello\n00000004");
+
+        let mut input = &out[..];
+        assert_eq!(read_pkt(&mut input).unwrap(), Some(b"hello\n".to_vec()));
+        assert_eq!(read_pkt(&mut input).unwrap(), None);
+        assert_eq!(read_pkt(&mut input).unwrap(), Some(Vec::new()));
+        assert!(read_pkt(&mut input).is_err());
+
+        assert!(read_pkt(&mut &b"0003"[..]).is_err());
+        assert!(read_pkt(&mut &b"zzzz"[..]).is_err());
+        assert!(read_pkt(&mut &b"0010short"[..]).is_err());
+        assert!(read_line(&mut &b"000dERR nope\n"[..]).is_err());
+    }
+
+    #[test]
+    fn advertisement_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = repo(dir.path());
+        let tip = commit(&server, &[], "one");
+        server.write_ref("refs/heads/master", &tip, "test").unwrap();
+        server.write_ref("refs/heads/topic", &tip, "test").unwrap();
+
+        let mut out = Vec::new();
+        write_advertisement(&server, &mut out).unwrap();
+        let advertisement = read_advertisement(&mut &out[..]).unwrap();
+        assert_eq!(advertisement.format, ObjectFormat::Vcs);
+        assert_eq!(advertisement.head.as_deref(), Some("master"));
+        assert_eq!(advertisement.branches.len(), 2);
+        assert_eq!(advertisement.branches.get("topic"), Some(&tip));
+    }
+
+    #[test]
+    fn fetch_sends_a_pack_of_missing_objects() {
+        let (server_dir, client_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let server = repo(server_dir.path());
+        let client = repo(client_dir.path());
+        let first = commit(&server, &[], "one");
+        let second = commit(&server, &[&first], "two");
+
+        let mut request = Vec::new();
+        write_fetch_request(&mut request, std::slice::from_ref(&second), &[]).unwrap();
+        let mut response = Vec::new();
+        serve_fetch(&server, &mut &request[..], &mut response).unwrap();
+        assert_eq!(read_fetch_response(&client, &mut &response[..]).unwrap(), 6);
+        assert_eq!(
+            history::read_commit(&client, &second).unwrap().parents,
+            vec![first.clone()]
+        );
+
+        // Objects the client already has are left out
+        let third = commit(&server, &[&second], "three");
+        let mut request = Vec::new();
+        write_fetch_request(&mut request, &[third], &[second]).unwrap();
+        let mut response = Vec::new();
+        serve_fetch(&server, &mut &request[..], &mut response).unwrap();
+        assert_eq!(read_fetch_response(&client, &mut &response[..]).unwrap(), 3);
+    }
+
+    #[test]
+    fn fetch_rejects_unknown_wants() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = repo(dir.path());
+        let mut request = Vec::new();
+        write_fetch_request(&mut request, &["1".repeat(40)], &[]).unwrap();
+        assert!(serve_fetch(&server, &mut &request[..], &mut Vec::new()).is_err());
+        let mut request = Vec::new();
+        write_fetch_request(&mut request, &["../../etc/passwd".to_string()], &[]).unwrap();
+        assert!(serve_fetch(&server, &mut &request[..], &mut Vec::new()).is_err());
+        // A client that hangs up without asking is fine
+        assert!(serve_fetch(&server, &mut &b""[..], &mut Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn push_updates_refs_and_reports_each_branch() {
+        let (server_dir, client_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let server = repo(server_dir.path());
+        let client = repo(client_dir.path());
+        let base = commit(&client, &[], "base");
+        let ours = commit(&client, &[&base], "ours");
+        let theirs = commit(&client, &[&base], "theirs");
+
+        let push = |updates: &[RefUpdate], haves: &[String]| {
+            let mut request = Vec::new();
+            write_push_request(&client, &mut request, updates, haves).unwrap();
+            let mut response = Vec::new();
+            serve_push(&server, &mut &request[..], &mut response).unwrap();
+            read_push_response(&mut &response[..]).unwrap()
+        };
+        let update = |branch: &str, new: &str, force: bool| RefUpdate {
+            branch: branch.to_string(),
+            new: new.to_string(),
+            force,
+        };
+
+        let statuses = push(&[update("a", &ours, false), update("b", &ours, false)], &[]);
+        assert_eq!(
+            statuses,
+            vec![("a".to_string(), Ok(())), ("b".to_string(), Ok(()))]
+        );
+        assert_eq!(
+            server.resolve_ref("refs/heads/a").unwrap().as_deref(),
+            Some(ours.as_str())
+        );
+
+        let statuses = push(
+            &[update("a", &theirs, false), update("b", &theirs, true)],
+            std::slice::from_ref(&ours),
+        );
+        assert_eq!(
+            statuses,
+            vec![
+                ("a".to_string(), Err("non-fast-forward".to_string())),
+                ("b".to_string(), Ok(()))
+            ]
+        );
+        assert_eq!(server.resolve_ref("refs/heads/a").unwrap(), Some(ours));
+        assert_eq!(server.resolve_ref("refs/heads/b").unwrap(), Some(theirs));
+    }
+}
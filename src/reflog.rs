@@ -0,0 +1,278 @@
+use crate::objects::Signature;
+use crate::repository::Repository;
+use anyhow::{Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Stands in for the old value of a newly created ref.
+pub const ZERO_HASH: &str = "0000000000000000000000000000000000000000";
+
+/// One recorded change of a ref, in Git's reflog line format:
+/// `<old> <new> Name <email> <time> <tz>\t<message>`.
+pub struct ReflogEntry {
+    pub old: String,
+    pub new: String,
+    pub who: Signature,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    fn parse(line: &str) -> Result<Self> {
+        let (head, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut parts = head.splitn(3, ' ');
+        let (Some(old), Some(new), Some(who)) = (parts.next(), parts.next(), parts.next()) else {
+            anyhow::bail!("Invalid reflog entry: {}", line);
+        };
+        Ok(ReflogEntry {
+            old: old.to_string(),
+            new: new.to_string(),
+            who: Signature::parse_git(who)?,
+            message: message.to_string(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {}\t{}\n",
+            self.old,
+            self.new,
+            self.who.to_git(),
+            self.message
+        )
+    }
+}
+
+/// Path of the log for `ref_name` (`HEAD` or a name under `refs/`).
+pub fn log_path(repo: &Repository, ref_name: &str) -> PathBuf {
+    repo.vcs_dir.join("logs").join(ref_name)
+}
+
+/// Whether updates of `ref_name` are logged: HEAD, branches, remote-tracking
+/// branches and the stash (whose entries live in its log) are; tags, which
+/// are not expected to move, are not.
+fn is_logged(ref_name: &str) -> bool {
+    ref_name == "HEAD"
+        || ref_name == "refs/stash"
+        || ref_name.starts_with("refs/heads/")
+        || ref_name.starts_with("refs/remotes/")
+}
+
+/// Records that `ref_name` moved from `old` to `new`.
+pub fn append(
+    repo: &Repository,
+    ref_name: &str,
+    old: Option<&str>,
+    new: &str,
+    reason: &str,
+) -> Result<()> {
+    if !is_logged(ref_name) || old == Some(new) {
+        return Ok(());
+    }
+    let path = log_path(repo, ref_name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Messages are single-line; keep only the subject of a commit message
+    let entry = ReflogEntry {
+        old: old.unwrap_or(ZERO_HASH).to_string(),
+        new: new.to_string(),
+        who: repo.reflog_signature()?,
+        message: reason.lines().next().unwrap_or("").to_string(),
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open reflog {}", path.display()))?;
+    file.write_all(entry.to_line().as_bytes())?;
+    Ok(())
+}
+
+/// Replaces a ref's log with `entries`, oldest first.
+pub fn write(repo: &Repository, ref_name: &str, entries: &[ReflogEntry]) -> Result<()> {
+    let path = log_path(repo, ref_name);
+    let text: String = entries.iter().map(ReflogEntry::to_line).collect();
+    fs::write(&path, text).with_context(|| format!("Failed to write reflog {}", path.display()))
+}
+
+/// Reads a ref's log, oldest entry first; a ref without a log has none.
+pub fn read(repo: &Repository, ref_name: &str) -> Result<Vec<ReflogEntry>> {
+    let path = log_path(repo, ref_name);
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read reflog {}", path.display()))?;
+    text.lines()
+        .filter(|l| !l.is_empty())
+        .map(ReflogEntry::parse)
+        .collect()
+}
+
+/// Removes a ref's log along with any directories that leaves empty.
+pub fn delete(repo: &Repository, ref_name: &str) -> Result<()> {
+    let path = log_path(repo, ref_name);
+    if path.is_file() {
+        fs::remove_file(&path)?;
+    }
+    let logs = repo.vcs_dir.join("logs");
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == logs || !d.starts_with(&logs) || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+    Ok(())
+}
+
+/// Moves a ref's log to follow a rename.
+pub fn rename(repo: &Repository, old: &str, new: &str) -> Result<()> {
+    let from = log_path(repo, old);
+    if !from.is_file() {
+        return Ok(());
+    }
+    let to = log_path(repo, new);
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&from, &to)?;
+    delete(repo, old)
+}
+
+/// Every hash recorded in any log, so GC keeps what the logs refer to.
+pub fn all_hashes(repo: &Repository) -> Result<Vec<String>> {
+    let logs = repo.vcs_dir.join("logs");
+    let mut hashes = Vec::new();
+    if !logs.is_dir() {
+        return Ok(hashes);
+    }
+    for entry in walkdir::WalkDir::new(&logs) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let ref_name = entry
+            .path()
+            .strip_prefix(&logs)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        for log_entry in read(repo, &ref_name)? {
+            hashes.extend(
+                [log_entry.old, log_entry.new]
+                    .into_iter()
+                    .filter(|h| h != ZERO_HASH),
+            );
+        }
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::ObjectFormat;
+
+    fn repo(dir: &std::path::Path) -> Repository {
+        let repo = Repository::create_at(dir.to_path_buf(), ObjectFormat::Vcs).unwrap();
+        let config = crate::config::Config::new(&repo);
+        config.set("user.name", "Test", false).unwrap();
+        config.set("user.email", "test@example.com", false).unwrap();
+        repo
+    }
+
+    fn hash(n: u8) -> String {
+        format!("{:02x}", n).repeat(20)
+    }
+
+    #[test]
+    fn ref_updates_are_logged_for_the_branch_and_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repo(dir.path());
+        repo.write_ref("refs/heads/master", &hash(1), "commit (initial): one")
+            .unwrap();
+        repo.write_ref("refs/heads/master", &hash(2), "commit: two\n\nbody")
+            .unwrap();
+        // Writing the same value again records nothing
+        repo.write_ref("refs/heads/master", &hash(2), "noop")
+            .unwrap();
+        repo.write_ref("refs/heads/topic", &hash(3), "branch: Created")
+            .unwrap();
+
+        let master = read(&repo, "refs/heads/master").unwrap();
+        let moves: Vec<_> = master
+            .iter()
+            .map(|e| (e.old.as_str(), e.new.as_str(), e.message.as_str()))
+            .collect();
+        assert_eq!(
+            moves,
+            vec![
+                (ZERO_HASH, hash(1).as_str(), "commit (initial): one"),
+                (hash(1).as_str(), hash(2).as_str(), "commit: two"),
+            ]
+        );
+        assert_eq!(master[0].who.name, "Test");
+        assert_eq!(master[0].who.email, "test@example.com");
+        // HEAD follows master, which is checked out, but not topic
+        assert_eq!(read(&repo, "HEAD").unwrap().len(), 2);
+        assert_eq!(read(&repo, "refs/heads/topic").unwrap().len(), 1);
+
+        let resolve = |expr| crate::revision::resolve(&repo, expr);
+        assert_eq!(resolve("master@{0}").unwrap(), hash(2));
+        assert_eq!(resolve("master@{1}").unwrap(), hash(1));
+        assert_eq!(resolve("HEAD@{1}").unwrap(), hash(1));
+        assert!(resolve("master@{2}").is_err());
+    }
+
+    #[test]
+    fn tags_are_not_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repo(dir.path());
+        repo.write_ref("refs/tags/v1", &hash(1), "tag").unwrap();
+        assert!(read(&repo, "refs/tags/v1").unwrap().is_empty());
+        assert!(!log_path(&repo, "refs/tags/v1").exists());
+    }
+
+    #[test]
+    fn logs_follow_renames_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repo(dir.path());
+        repo.write_ref("refs/heads/feature/a", &hash(1), "one")
+            .unwrap();
+        repo.write_ref("refs/heads/feature/a", &hash(2), "two")
+            .unwrap();
+
+        rename(&repo, "refs/heads/feature/a", "refs/heads/b").unwrap();
+        assert_eq!(read(&repo, "refs/heads/b").unwrap().len(), 2);
+        assert!(read(&repo, "refs/heads/feature/a").unwrap().is_empty());
+        // The emptied directory goes too
+        assert!(!repo.vcs_dir.join("logs/refs/heads/feature").exists());
+
+        let mut hashes = all_hashes(&repo).unwrap();
+        hashes.sort();
+        hashes.dedup();
+        assert_eq!(hashes, vec![hash(1), hash(2)]);
+
+        delete(&repo, "refs/heads/b").unwrap();
+        assert!(read(&repo, "refs/heads/b").unwrap().is_empty());
+        assert!(all_hashes(&repo).unwrap().is_empty());
+    }
+
+    #[test]
+    fn entries_round_trip_through_the_log_format() {
+        let line = format!(
+            "{} {} A U Thor <a@example.com> 1700000000 -0130\tcheckout: moving from a to b",
+            ZERO_HASH,
+            hash(7)
+        );
+        let entry = ReflogEntry::parse(&line).unwrap();
+        assert_eq!(entry.old, ZERO_HASH);
+        assert_eq!(entry.new, hash(7));
+        assert_eq!(entry.who.name, "A U Thor");
+        assert_eq!(entry.who.offset, -90);
+        assert_eq!(entry.message, "checkout: moving from a to b");
+        assert_eq!(entry.to_line(), format!("{}\n", line));
+        assert!(ReflogEntry::parse("garbage").is_err());
+    }
+}
@@ -0,0 +1,507 @@
+use crate::history;
+use crate::http::HttpTransport;
+use crate::objects::{GitObject, ObjectFormat};
+use crate::protocol::PipeTransport;
+use crate::repository::Repository;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What a remote repository advertises before a transfer.
+#[derive(Clone)]
+pub struct Advertisement {
+    /// Branches under `refs/heads`, by name.
+    pub branches: BTreeMap<String, String>,
+    /// The branch the remote has checked out, if any.
+    pub head: Option<String>,
+    pub format: ObjectFormat,
+}
+
+/// A requested update of one branch on the remote.
+pub struct RefUpdate {
+    pub branch: String,
+    pub new: String,
+    pub force: bool,
+}
+
+/// Outcome of a ref update: the branch, and the reason if it was rejected.
+pub type RefStatus = (String, std::result::Result<(), String>);
+
+/// A way of talking to another repository.
+pub trait Transport {
+    fn advertise(&mut self) -> Result<Advertisement>;
+
+    /// Stores the objects reachable from `wants` but not from `haves` in `repo`,
+    /// returning how many were received.
+    fn fetch(&mut self, repo: &Repository, wants: &[String], haves: &[String]) -> Result<usize>;
+
+    /// Sends the objects the remote lacks (it already holds `haves`) and asks it
+    /// to move its branches.
+    fn push(
+        &mut self,
+        repo: &Repository,
+        updates: &[RefUpdate],
+        haves: &[String],
+    ) -> Result<Vec<RefStatus>>;
+}
+
+/// Which side of a transfer a connection is opened for; pipe transports start a
+/// different server program for each.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Fetch,
+    Push,
+}
+
+impl Service {
+    fn program(self) -> &'static str {
+        match self {
+            Service::Fetch => "upload-pack",
+            Service::Push => "receive-pack",
+        }
+    }
+}
+
+/// Opens a transport for a remote URL. `token` authenticates HTTP requests.
+pub fn open_transport(
+    url: &str,
+    service: Service,
+    token: Option<&str>,
+) -> Result<Box<dyn Transport>> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Ok(Box::new(LocalTransport::open(Path::new(path))?));
+    }
+    if url.starts_with("http://") {
+        return Ok(Box::new(HttpTransport::open(url, token)?));
+    }
+    if let Some((host, port, path)) = parse_ssh_url(url)? {
+        return Ok(Box::new(PipeTransport::spawn(ssh_command(
+            &host, port, &path, service,
+        ))?));
+    }
+    if url.contains("://") {
+        anyhow::bail!("Unsupported remote URL: {}", url);
+    }
+    Ok(Box::new(LocalTransport::open(Path::new(url))?))
+}
+
+/// A URL for printing, without any credentials it embeds.
+pub fn display_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    match rest[..authority_end].rsplit_once('@') {
+        Some((_, host)) => format!("{}://{}{}", scheme, host, &rest[authority_end..]),
+        None => url.to_string(),
+    }
+}
+
+/// Splits `ssh://[user@]host[:port]/path` or scp-style `[user@]host:path` into
+/// host, port and path.
+fn parse_ssh_url(url: &str) -> Result<Option<(String, Option<String>, String)>> {
+    if let Some(rest) = url.strip_prefix("ssh://") {
+        let slash = rest
+            .find('/')
+            .with_context(|| format!("Missing path in URL: {}", url))?;
+        let (authority, path) = rest.split_at(slash);
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.to_string())),
+            None => (authority, None),
+        };
+        check_ssh_host(host, url)?;
+        if port
+            .as_deref()
+            .is_some_and(|p| p.is_empty() || !p.chars().all(|c| c.is_ascii_digit()))
+        {
+            anyhow::bail!("Invalid port in URL: {}", url);
+        }
+        return Ok(Some((host.to_string(), port, path.to_string())));
+    }
+    if url.contains("://") {
+        return Ok(None);
+    }
+    // A colon before any slash marks scp syntax; a single letter is a drive
+    let Some((host, path)) = url.split_once(':') else {
+        return Ok(None);
+    };
+    if host.len() < 2 || host.contains('/') || path.is_empty() {
+        return Ok(None);
+    }
+    check_ssh_host(host, url)?;
+    Ok(Some((host.to_string(), None, path.to_string())))
+}
+
+/// Refuses hosts that ssh would read as an option (e.g. `-oProxyCommand=...`),
+/// which would let a crafted URL run a local command.
+fn check_ssh_host(host: &str, url: &str) -> Result<()> {
+    if host.is_empty() || host.starts_with('-') {
+        anyhow::bail!("Invalid host in URL: {}", url);
+    }
+    Ok(())
+}
+
+/// Builds the command that runs the server side over SSH. `VCS_SSH` replaces the
+/// `ssh` program, e.g. with a wrapper that adds options.
+fn ssh_command(host: &str, port: Option<String>, path: &str, service: Service) -> Command {
+    let program = std::env::var("VCS_SSH").unwrap_or_else(|_| "ssh".to_string());
+    let mut command = Command::new(program);
+    if let Some(port) = port {
+        command.arg("-p").arg(port);
+    }
+    let quoted = format!("'{}'", path.replace('\'', "'\\''"));
+    // "--" ends ssh's options, so the host can never be read as one
+    command
+        .arg("--")
+        .arg(host)
+        .arg(format!("vcs {} {}", service.program(), quoted));
+    command
+}
+
+/// Builds the advertisement for a repository being served.
+pub fn advertise(repo: &Repository) -> Result<Advertisement> {
+    let mut branches = BTreeMap::new();
+    for name in repo.list_branches()? {
+        if let Some(hash) = repo.resolve_ref(&format!("refs/heads/{}", name))? {
+            branches.insert(name, hash);
+        }
+    }
+    let head = repo.current_branch()?.filter(|b| branches.contains_key(b));
+    Ok(Advertisement {
+        branches,
+        head,
+        format: repo.format,
+    })
+}
+
+/// Copies objects between repositories, referenced objects before the objects
+/// that refer to them.
+pub fn copy_objects(
+    src: &Repository,
+    dst: &Repository,
+    objects: &[(String, String)],
+) -> Result<usize> {
+    let mut copied = 0;
+    for (hash, _) in objects.iter().rev() {
+        if !dst.has_object(hash)? {
+            let written = dst.write_raw_object(&src.read_raw_object(hash)?)?;
+            if written != *hash {
+                anyhow::bail!("Object {} is corrupt", hash);
+            }
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Applies pushed branch updates to the repository receiving them. Non-fast-forward
+/// updates are rejected unless forced; the checked-out branch is only moved when
+/// its working tree can be updated without losing local changes.
+pub fn apply_ref_updates(repo: &Repository, updates: &[RefUpdate]) -> Result<Vec<RefStatus>> {
+    let mut statuses = Vec::new();
+    for update in updates {
+        let status = apply_ref_update(repo, update)?;
+        statuses.push((update.branch.clone(), status));
+    }
+    Ok(statuses)
+}
+
+fn apply_ref_update(
+    repo: &Repository,
+    update: &RefUpdate,
+) -> Result<std::result::Result<(), String>> {
+    if let Err(e) = crate::repository::validate_branch_name(&update.branch) {
+        return Ok(Err(e.to_string()));
+    }
+    if let Err(reason) = check_new_tip(repo, &update.new)? {
+        return Ok(Err(reason));
+    }
+    let ref_name = format!("refs/heads/{}", update.branch);
+    let old = repo.resolve_ref(&ref_name)?;
+    if old.as_deref() == Some(update.new.as_str()) {
+        return Ok(Ok(()));
+    }
+    if let Some(old) = &old {
+        if !update.force && !history::is_ancestor(repo, old, &update.new)? {
+            return Ok(Err("non-fast-forward".to_string()));
+        }
+    }
+
+    if repo.current_branch()?.as_deref() == Some(update.branch.as_str()) {
+        let current = match &old {
+            Some(old) => commit_entries(repo, old)?,
+            None => Vec::new(),
+        };
+        let target = commit_entries(repo, &update.new)?;
+        if crate::worktree::switch_tree(repo, &current, &target, false).is_err() {
+            return Ok(Err(
+                "branch is checked out with conflicting local changes".to_string()
+            ));
+        }
+    }
+    repo.write_ref(&ref_name, &update.new, "push")?;
+    Ok(Ok(()))
+}
+
+/// Checks that a pushed tip is a commit and that every object reachable from
+/// it is present. The walk stops at existing ref tips, whose history is complete.
+fn check_new_tip(repo: &Repository, hash: &str) -> Result<std::result::Result<(), String>> {
+    if !repo.has_object(hash)? {
+        return Ok(Err("missing objects".to_string()));
+    }
+    if !matches!(repo.read_object(hash)?, GitObject::Commit(_)) {
+        return Ok(Err("not a commit".to_string()));
+    }
+    let tips: HashSet<String> = repo.list_refs()?.into_iter().map(|(_, h)| h).collect();
+    if history::reachable_objects(repo, &[hash.to_string()], &tips).is_err() {
+        return Ok(Err("missing objects".to_string()));
+    }
+    Ok(Ok(()))
+}
+
+fn commit_entries(repo: &Repository, hash: &str) -> Result<Vec<crate::objects::IndexEntry>> {
+    let tree = crate::worktree::commit_tree(repo, hash)?;
+    crate::worktree::read_tree_entries(repo, &tree)
+}
+
+/// Another repository on the local filesystem.
+pub struct LocalTransport {
+    remote: Repository,
+}
+
+impl LocalTransport {
+    pub fn open(path: &Path) -> Result<Self> {
+        let remote = Repository::new(PathBuf::from(path))?;
+        if !remote.vcs_dir.is_dir() {
+            anyhow::bail!("Not a VCS repository: {}", path.display());
+        }
+        Ok(LocalTransport { remote })
+    }
+}
+
+impl Transport for LocalTransport {
+    fn advertise(&mut self) -> Result<Advertisement> {
+        advertise(&self.remote)
+    }
+
+    fn fetch(&mut self, repo: &Repository, wants: &[String], haves: &[String]) -> Result<usize> {
+        let objects = history::missing_objects(&self.remote, wants, haves)?;
+        copy_objects(&self.remote, repo, &objects)
+    }
+
+    fn push(
+        &mut self,
+        repo: &Repository,
+        updates: &[RefUpdate],
+        haves: &[String],
+    ) -> Result<Vec<RefStatus>> {
+        let _lock =
+            crate::lock::RepoLock::new(&self.remote).context("Remote repository is locked")?;
+        let wants: Vec<String> = updates.iter().map(|u| u.new.clone()).collect();
+        let objects = history::missing_objects(repo, &wants, haves)?;
+        copy_objects(repo, &self.remote, &objects)?;
+        apply_ref_updates(&self.remote, updates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::Commit;
+    use crate::test_support::{commit, repo};
+
+    fn update(branch: &str, new: &str, force: bool) -> RefUpdate {
+        RefUpdate {
+            branch: branch.to_string(),
+            new: new.to_string(),
+            force,
+        }
+    }
+
+    #[test]
+    fn ssh_urls_split_into_host_port_and_path() {
+        let parse = |url| parse_ssh_url(url).unwrap();
+        assert_eq!(
+            parse("ssh://me@example.com:2222/srv/repo"),
+            Some((
+                "me@example.com".to_string(),
+                Some("2222".to_string()),
+                "/srv/repo".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("example.com:repo"),
+            Some(("example.com".to_string(), None, "repo".to_string()))
+        );
+        assert_eq!(parse("C:/repo"), None);
+        assert_eq!(parse("../repo"), None);
+        assert_eq!(parse("http://example.com/repo"), None);
+    }
+
+    #[test]
+    fn ssh_urls_cannot_smuggle_options() {
+        for url in [
+            "-oProxyCommand=touch pwned:repo",
+            "ssh://-oProxyCommand=touch pwned/repo",
+            "ssh://:22/repo",
+            "ssh:///repo",
+            "ssh://example.com:-oFoo/repo",
+            "ssh://example.com",
+        ] {
+            assert!(parse_ssh_url(url).is_err(), "{} should be rejected", url);
+        }
+        let command = ssh_command("example.com", Some("22".to_string()), "/r", Service::Fetch);
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            ["-p", "22", "--", "example.com", "vcs upload-pack '/r'"]
+        );
+    }
+
+    #[test]
+    fn push_and_fetch_round_trip() {
+        let (local_dir, remote_dir, clone_dir) = (
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+        );
+        let local = repo(local_dir.path());
+        repo(remote_dir.path());
+        let first = commit(&local, &[], "one");
+        let second = commit(&local, &[&first], "two");
+
+        let mut transport = LocalTransport::open(remote_dir.path()).unwrap();
+        let statuses = transport
+            .push(&local, &[update("topic", &second, false)], &[])
+            .unwrap();
+        assert_eq!(statuses, vec![("topic".to_string(), Ok(()))]);
+
+        let clone = repo(clone_dir.path());
+        let mut transport = LocalTransport::open(remote_dir.path()).unwrap();
+        let advertised = transport.advertise().unwrap();
+        assert_eq!(advertised.branches.get("topic"), Some(&second));
+        assert_eq!(advertised.format, ObjectFormat::Vcs);
+        let wants = std::slice::from_ref(&second);
+        // Two commits, two trees and two blobs
+        assert_eq!(transport.fetch(&clone, wants, &[]).unwrap(), 6);
+        assert_eq!(
+            history::read_commit(&clone, &second).unwrap().parents,
+            vec![first.clone()]
+        );
+        assert!(clone.read_object(&first).is_ok());
+        // Nothing is sent twice
+        assert_eq!(transport.fetch(&clone, wants, wants).unwrap(), 0);
+    }
+
+    #[test]
+    fn push_sends_only_what_the_remote_lacks() {
+        let (local_dir, remote_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let local = repo(local_dir.path());
+        let remote = repo(remote_dir.path());
+        let first = commit(&local, &[], "one");
+        let second = commit(&local, &[&first], "two");
+        let mut transport = LocalTransport::open(remote_dir.path()).unwrap();
+        transport
+            .push(&local, &[update("topic", &first, false)], &[])
+            .unwrap();
+        let before = remote.all_objects().unwrap().len();
+        transport
+            .push(
+                &local,
+                &[update("topic", &second, false)],
+                std::slice::from_ref(&first),
+            )
+            .unwrap();
+        assert_eq!(remote.all_objects().unwrap().len(), before + 3);
+        assert_eq!(
+            remote.resolve_ref("refs/heads/topic").unwrap(),
+            Some(second)
+        );
+    }
+
+    #[test]
+    fn non_fast_forward_pushes_need_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = repo(dir.path());
+        let base = commit(&remote, &[], "base");
+        let ours = commit(&remote, &[&base], "ours");
+        let theirs = commit(&remote, &[&base], "theirs");
+        remote.write_ref("refs/heads/topic", &ours, "test").unwrap();
+
+        let statuses = apply_ref_updates(&remote, &[update("topic", &theirs, false)]).unwrap();
+        assert_eq!(statuses[0].1, Err("non-fast-forward".to_string()));
+        assert_eq!(
+            remote.resolve_ref("refs/heads/topic").unwrap().as_deref(),
+            Some(ours.as_str())
+        );
+        // Moving back to an ancestor is not a fast-forward either
+        let statuses = apply_ref_updates(&remote, &[update("topic", &base, false)]).unwrap();
+        assert!(statuses[0].1.is_err());
+
+        let statuses = apply_ref_updates(&remote, &[update("topic", &theirs, true)]).unwrap();
+        assert_eq!(statuses[0].1, Ok(()));
+        assert_eq!(
+            remote.resolve_ref("refs/heads/topic").unwrap(),
+            Some(theirs)
+        );
+    }
+
+    #[test]
+    fn pushes_of_invalid_tips_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = repo(dir.path());
+        let tip = commit(&remote, &[], "tip");
+        let tree = history::read_commit(&remote, &tip).unwrap().tree;
+        let orphan = commit(&remote, &[&"2".repeat(40)], "orphan");
+        let treeless = remote
+            .write_object(&GitObject::Commit(Commit {
+                tree: "3".repeat(40),
+                ..history::read_commit(&remote, &tip).unwrap()
+            }))
+            .unwrap();
+        let statuses = apply_ref_updates(
+            &remote,
+            &[
+                update("missing", &"1".repeat(40), false),
+                update("../escape", &tip, false),
+                update("tree", &tree, false),
+                update("orphan", &orphan, false),
+                update("treeless", &treeless, false),
+                update("good", &tip, false),
+            ],
+        )
+        .unwrap();
+        let reasons: Vec<_> = statuses.iter().map(|(_, status)| status.clone()).collect();
+        assert_eq!(reasons[0], Err("missing objects".to_string()));
+        assert!(reasons[1].is_err());
+        assert_eq!(reasons[2], Err("not a commit".to_string()));
+        assert_eq!(reasons[3], Err("missing objects".to_string()));
+        assert_eq!(reasons[4], Err("missing objects".to_string()));
+        assert_eq!(reasons[5], Ok(()));
+        for branch in ["missing", "tree", "orphan", "treeless"] {
+            let ref_name = format!("refs/heads/{}", branch);
+            assert_eq!(remote.resolve_ref(&ref_name).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn push_to_the_checked_out_branch_updates_its_worktree() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = repo(dir.path());
+        let first = commit(&remote, &[], "one");
+        let second = commit(&remote, &[&first], "two");
+        let statuses = apply_ref_updates(&remote, &[update("master", &first, false)]).unwrap();
+        assert_eq!(statuses[0].1, Ok(()));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("file.txt")).unwrap(),
+            "one"
+        );
+
+        // Local edits that the update would overwrite block it
+        std::fs::write(dir.path().join("file.txt"), "edited").unwrap();
+        let statuses = apply_ref_updates(&remote, &[update("master", &second, false)]).unwrap();
+        assert!(statuses[0].1.is_err());
+        assert_eq!(remote.get_head().unwrap(), Some(first));
+    }
+}
@@ -1,20 +1,49 @@
+use crate::objects::{IndexEntry, ObjectFormat};
+use crate::pack::{self, PackEntry, PackFile};
 use crate::utils;
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cell::{Ref, RefCell};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const INDEX_VERSION: u32 = 2;
+/// Config key recording the object format chosen at `init`.
+pub const FORMAT_KEY: &str = "core.objectformat";
+
+/// How long unreachable loose objects survive `gc`, so that objects a
+/// concurrent command has written but not yet referenced are not pruned.
+pub const PRUNE_GRACE: std::time::Duration = std::time::Duration::from_secs(14 * 24 * 60 * 60);
+
+/// On-disk index layout; version 1 was a bare JSON array of staged entries.
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    version: u32,
+    entries: Vec<IndexEntry>,
+}
 
 pub struct Repository {
     pub worktree: PathBuf,
     pub vcs_dir: PathBuf,
+    /// Object layout, from `core.objectformat` in the repository config.
+    pub format: ObjectFormat,
+    /// Pack indexes, loaded on first lookup of an object that is not loose.
+    packs: RefCell<Option<Vec<PackFile>>>,
 }
 
 impl Repository {
     pub fn new(path: PathBuf) -> Result<Self> {
         let vcs_dir = path.join(".vcs");
-        Ok(Repository {
+        let mut repo = Repository {
             worktree: path,
             vcs_dir,
-        })
+            format: ObjectFormat::Vcs,
+            packs: RefCell::new(None),
+        };
+        if let Some(format) = crate::config::Config::new(&repo).get_local(FORMAT_KEY)? {
+            repo.format = ObjectFormat::parse(&format)?;
+        }
+        Ok(repo)
     }
 
     pub fn find() -> Result<Option<Self>> {
@@ -38,9 +67,13 @@ impl Repository {
         }
     }
 
-    pub fn create() -> Result<Self> {
-        let path = std::env::current_dir()?;
-        let repo = Repository::new(path.clone())?;
+    pub fn create(format: ObjectFormat) -> Result<Self> {
+        Repository::create_at(std::env::current_dir()?, format)
+    }
+
+    /// Creates a repository with its worktree at `path`.
+    pub fn create_at(path: PathBuf, format: ObjectFormat) -> Result<Self> {
+        let mut repo = Repository::new(path)?;
 
         if repo.vcs_dir.exists() {
             anyhow::bail!("Repository already exists");
@@ -54,9 +87,13 @@ impl Repository {
         let head_path = repo.vcs_dir.join("HEAD");
         fs::write(head_path, "ref: refs/heads/master\n")?;
 
+        if format != ObjectFormat::Vcs {
+            crate::config::Config::new(&repo).set(FORMAT_KEY, format.as_str(), false)?;
+        }
+        repo.format = format;
+
         // Create index file (staging area)
-        let index_path = repo.vcs_dir.join("index");
-        fs::write(index_path, "[]")?;
+        repo.write_index(&[])?;
 
         println!(
             "Initialized empty VCS repository in {}",
@@ -78,7 +115,12 @@ impl Repository {
 
     pub fn write_object(&self, obj: &crate::objects::GitObject) -> Result<String> {
         let data = obj.serialize()?;
-        let hash = utils::hash_object(&data);
+        self.write_raw_object(&data)
+    }
+
+    /// Stores serialized object bytes (`type size\0payload`), zlib-compressed, and returns their hash.
+    pub fn write_raw_object(&self, data: &[u8]) -> Result<String> {
+        let hash = utils::hash_object(data);
         let path = self.object_path(&hash);
 
         if !path.exists() {
@@ -86,26 +128,250 @@ impl Repository {
                 .parent()
                 .with_context(|| format!("Object path has no parent: {}", path.display()))?;
             fs::create_dir_all(parent)?;
-            fs::write(&path, data)?;
+            self.write_atomic(&path, &utils::compress(data)?)?;
         }
 
         Ok(hash)
     }
 
     pub fn read_object(&self, hash: &str) -> Result<crate::objects::GitObject> {
-        let path = self.object_path(hash);
-        let data = fs::read(path).context("Object not found")?;
+        let data = self.read_raw_object(hash)?;
         crate::objects::GitObject::deserialize(&data)
     }
 
-    pub fn get_index(&self) -> Result<Vec<crate::objects::IndexEntry>> {
-        let index_path = self.vcs_dir.join("index");
-        if !index_path.exists() {
-            return Ok(vec![]);
+    /// Reads an object's serialized bytes (`type size\0payload`), from a loose file or a pack.
+    pub fn read_raw_object(&self, hash: &str) -> Result<Vec<u8>> {
+        if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid object name: '{}'", hash);
+        }
+        let path = self.object_path(hash);
+        if let Ok(data) = fs::read(path) {
+            if is_uncompressed_object(&data) {
+                // Written before loose objects were compressed
+                return Ok(data);
+            }
+            return utils::decompress(&data).with_context(|| format!("Object {} is corrupt", hash));
+        }
+
+        let packs = self.packs()?;
+        for pack_file in packs.iter() {
+            if let Some(offset) = pack_file.find(hash) {
+                let (obj_type, payload) = pack_file
+                    .read_at(offset, &|base| self.read_raw_object(base))
+                    .with_context(|| format!("Object {} is corrupt", hash))?;
+                return Ok(pack::join_raw(obj_type, &payload));
+            }
+        }
+        anyhow::bail!("Object not found")
+    }
+
+    /// Whether an object exists, loose or packed.
+    pub fn has_object(&self, hash: &str) -> Result<bool> {
+        if hash.len() == 40 && self.object_path(hash).is_file() {
+            return Ok(true);
+        }
+        Ok(self.packs()?.iter().any(|p| p.find(hash).is_some()))
+    }
+
+    pub fn pack_dir(&self) -> PathBuf {
+        self.vcs_dir.join("objects").join("pack")
+    }
+
+    fn packs(&self) -> Result<Ref<'_, Vec<PackFile>>> {
+        if self.packs.borrow().is_none() {
+            let loaded = PackFile::load_dir(&self.pack_dir())?;
+            *self.packs.borrow_mut() = Some(loaded);
+        }
+        Ok(Ref::map(self.packs.borrow(), |p| p.as_ref().unwrap()))
+    }
+
+    /// Hashes of loose objects.
+    pub fn loose_objects(&self) -> Result<Vec<String>> {
+        let objects_dir = self.vcs_dir.join("objects");
+        let mut hashes = Vec::new();
+        for entry in walkdir::WalkDir::new(&objects_dir)
+            .min_depth(2)
+            .max_depth(2)
+        {
+            let entry = entry?;
+            let rel = entry.path().strip_prefix(&objects_dir)?;
+            let hash = rel.to_string_lossy().replace(['/', '\\'], "");
+            if entry.file_type().is_file()
+                && hash.len() == 40
+                && hash.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                hashes.push(hash);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Hashes of loose objects written less than `PRUNE_GRACE` ago.
+    pub fn recent_loose_objects(&self) -> Result<std::collections::HashSet<String>> {
+        let mut recent = std::collections::HashSet::new();
+        for hash in self.loose_objects()? {
+            let modified = fs::metadata(self.object_path(&hash))?.modified()?;
+            // A timestamp in the future counts as recent
+            if modified.elapsed().map_or(true, |age| age < PRUNE_GRACE) {
+                recent.insert(hash);
+            }
+        }
+        Ok(recent)
+    }
+
+    /// Hashes of stored objects starting with a lowercase hex prefix, sorted.
+    pub fn objects_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        if prefix.len() >= 2 {
+            let dir = self.vcs_dir.join("objects").join(&prefix[..2]);
+            if dir.is_dir() {
+                for entry in fs::read_dir(&dir)? {
+                    let hash = format!("{}{}", &prefix[..2], entry?.file_name().to_string_lossy());
+                    if hash.len() == 40 && hash.starts_with(prefix) {
+                        hashes.push(hash);
+                    }
+                }
+            }
+        }
+        for pack_file in self.packs()?.iter() {
+            hashes.extend(pack_file.hashes().filter(|h| h.starts_with(prefix)));
+        }
+        hashes.sort();
+        hashes.dedup();
+        Ok(hashes)
+    }
+
+    /// Hashes of every stored object, loose or packed, sorted and deduplicated.
+    pub fn all_objects(&self) -> Result<Vec<String>> {
+        let mut hashes = self.loose_objects()?;
+        for pack_file in self.packs()?.iter() {
+            hashes.extend(pack_file.hashes());
+        }
+        hashes.sort();
+        hashes.dedup();
+        Ok(hashes)
+    }
+
+    /// Replaces the whole object store with a single pack holding `objects`
+    /// (hash and path hint pairs); every other pack and loose object is
+    /// removed, except loose objects left out of the pack that were written
+    /// within `PRUNE_GRACE`.
+    pub fn repack(&self, objects: &[(String, String)]) -> Result<Option<PathBuf>> {
+        let mut entries = Vec::with_capacity(objects.len());
+        for (hash, name) in objects {
+            entries.push(PackEntry {
+                hash: hash.clone(),
+                raw: self.read_raw_object(hash)?,
+                name: name.clone(),
+            });
+        }
+        let old_packs: Vec<PathBuf> = self.packs()?.iter().map(|p| p.pack_path.clone()).collect();
+        let packed: std::collections::HashSet<&str> =
+            objects.iter().map(|(h, _)| h.as_str()).collect();
+        let recent = self.recent_loose_objects()?;
+        let loose: Vec<String> = self
+            .loose_objects()?
+            .into_iter()
+            .filter(|hash| packed.contains(hash.as_str()) || !recent.contains(hash))
+            .collect();
+
+        let new_pack = if entries.is_empty() {
+            None
+        } else {
+            Some(pack::write_pack(&self.pack_dir(), entries)?)
+        };
+
+        // Only delete once the replacement pack is safely on disk
+        for old in old_packs {
+            if Some(&old) != new_pack.as_ref() {
+                fs::remove_file(old.with_extension("idx"))?;
+                fs::remove_file(&old)?;
+            }
+        }
+        for hash in loose {
+            let path = self.object_path(&hash);
+            fs::remove_file(&path)?;
+            if let Some(parent) = path.parent() {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        *self.packs.borrow_mut() = None;
+        Ok(new_pack)
+    }
+
+    /// Indexes a pack received from another repository and adds it to the object
+    /// store, returning how many objects it held.
+    pub fn store_pack(&self, data: &[u8]) -> Result<usize> {
+        let layout = pack::index_pack(data, &|hash| self.read_raw_object(hash))?;
+        if layout.entries.is_empty() {
+            return Ok(0);
+        }
+        pack::store_pack(&self.pack_dir(), data, &layout)?;
+        *self.packs.borrow_mut() = None;
+        Ok(layout.entries.len())
+    }
+
+    /// Starting points that keep objects alive: HEAD, every ref, reflog entries,
+    /// an in-progress merge, and blobs staged in the index.
+    pub fn gc_roots(&self) -> Result<Vec<String>> {
+        let mut roots = Vec::new();
+        roots.extend(self.get_head()?);
+        roots.extend(self.merge_head()?);
+        for (_, hash) in self.list_refs()? {
+            roots.push(hash);
+        }
+        for hash in crate::reflog::all_hashes(self)? {
+            if self.has_object(&hash)? {
+                roots.push(hash);
+            }
         }
-        let content = fs::read_to_string(&index_path)?;
-        let entries: Vec<crate::objects::IndexEntry> =
-            serde_json::from_str(&content).unwrap_or_else(|_| vec![]);
+        roots.extend(self.get_index()?.into_iter().map(|e| e.hash));
+        roots.sort();
+        roots.dedup();
+        Ok(roots)
+    }
+
+    /// Compresses loose objects written by older versions in place; returns how many were rewritten.
+    pub fn compress_loose_objects(&self) -> Result<usize> {
+        let objects_dir = self.vcs_dir.join("objects");
+        let mut rewritten = 0;
+        for entry in walkdir::WalkDir::new(&objects_dir)
+            .min_depth(2)
+            .max_depth(2)
+        {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let data = fs::read(entry.path())?;
+            if is_uncompressed_object(&data) {
+                self.write_atomic(entry.path(), &utils::compress(&data)?)?;
+                rewritten += 1;
+            }
+        }
+        Ok(rewritten)
+    }
+
+    /// Reads the index, a full snapshot of the tree the next commit will record.
+    ///
+    /// Older repositories stored a bare list of entries staged since the last
+    /// commit; those are upgraded by overlaying them on HEAD's tree.
+    pub fn get_index(&self) -> Result<Vec<IndexEntry>> {
+        let index_path = self.vcs_dir.join("index");
+        let content = if index_path.exists() {
+            fs::read_to_string(&index_path)?
+        } else {
+            "[]".to_string()
+        };
+
+        let entries = match serde_json::from_str::<IndexFile>(&content) {
+            Ok(index) => index.entries,
+            Err(_) => {
+                let staged: Vec<IndexEntry> = serde_json::from_str(&content)
+                    .with_context(|| format!("Index file is corrupt: {}", index_path.display()))?;
+                self.seed_legacy_index(staged)?
+            }
+        };
 
         // Migrate old index entries that don't have mode field
         let migrated: Vec<_> = entries
@@ -121,12 +387,33 @@ impl Repository {
         Ok(migrated)
     }
 
-    pub fn write_index(&self, entries: &[crate::objects::IndexEntry]) -> Result<()> {
+    fn seed_legacy_index(&self, staged: Vec<IndexEntry>) -> Result<Vec<IndexEntry>> {
+        let mut entries = match self.get_head()? {
+            Some(head) => {
+                let tree = crate::worktree::commit_tree(self, &head)?;
+                crate::worktree::read_tree_entries(self, &tree)?
+            }
+            None => Vec::new(),
+        };
+        for entry in staged {
+            entries.retain(|e| e.path != entry.path);
+            entries.push(entry);
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    pub fn write_index(&self, entries: &[IndexEntry]) -> Result<()> {
         use std::io::Write;
         use tempfile::NamedTempFile;
 
         let index_path = self.vcs_dir.join("index");
-        let content = serde_json::to_string_pretty(entries)?;
+        let mut entries = entries.to_vec();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let content = serde_json::to_string_pretty(&IndexFile {
+            version: INDEX_VERSION,
+            entries,
+        })?;
 
         // Atomic write: write to temp file, then rename
         let mut temp_file = NamedTempFile::new_in(&self.vcs_dir)
@@ -167,42 +454,435 @@ impl Repository {
         }
     }
 
-    pub fn set_head(&self, commit_hash: &str) -> Result<()> {
-        use std::io::Write;
-        use tempfile::NamedTempFile;
+    /// Moves the current branch, or HEAD itself when detached, to `commit_hash`;
+    /// `reason` is recorded in the reflog.
+    pub fn set_head(&self, commit_hash: &str, reason: &str) -> Result<()> {
+        match self.head_ref()? {
+            Some(ref_name) => self.write_ref(&ref_name, commit_hash, reason),
+            None => self.write_ref("HEAD", commit_hash, reason),
+        }
+    }
+
+    /// Who wrote a change: `$VCS_AUTHOR_NAME`, `$VCS_AUTHOR_EMAIL` and
+    /// `$VCS_AUTHOR_DATE`, falling back to the configured user and the
+    /// current local time.
+    /// Fails when no identity is configured (see [`Repository::committer_signature`]).
+    pub fn author_signature(&self) -> Result<crate::objects::Signature> {
+        self.signature_for("AUTHOR", true)
+    }
+
+    /// Who recorded a change (commits and tags), overridable with the
+    /// `$VCS_COMMITTER_*` variables. Fails when neither the environment nor
+    /// the config names the user, unless `user.allowDefaultIdentity` is set.
+    pub fn committer_signature(&self) -> Result<crate::objects::Signature> {
+        self.signature_for("COMMITTER", true)
+    }
+
+    /// The committer for reflog entries, which fall back to the login name
+    /// rather than fail when no identity is configured.
+    pub fn reflog_signature(&self) -> Result<crate::objects::Signature> {
+        self.signature_for("COMMITTER", false)
+    }
 
+    fn signature_for(&self, role: &str, strict: bool) -> Result<crate::objects::Signature> {
+        let config = crate::config::Config::new(self);
+        let var = |field: &str| std::env::var(format!("VCS_{}_{}", role, field)).ok();
+        let name = var("NAME").or(config.get_user_name()?);
+        let email = var("EMAIL").or(config.get_user_email()?);
+        for (kind, value) in [("name", &name), ("email", &email)] {
+            if let Some(value) = value {
+                crate::objects::Signature::check_identity_part(kind, value)?;
+            }
+        }
+        if (name.is_none() || email.is_none()) && strict && !config.allows_default_identity()? {
+            let role = if role == "AUTHOR" {
+                "Author"
+            } else {
+                "Committer"
+            };
+            anyhow::bail!(
+                "{} identity unknown\n\n\
+                 Run\n\n  \
+                 vcs config user.name \"Your Name\"\n  \
+                 vcs config user.email \"you@example.com\"\n\n\
+                 to set your identity (add --global to set it for all repositories),\n\
+                 or run 'vcs config {} true' to use your login name.",
+                role,
+                crate::config::DEFAULT_IDENTITY_KEY
+            );
+        }
+        let (time, offset) = match var("DATE") {
+            Some(date) => crate::objects::Signature::parse_date(&date)
+                .with_context(|| format!("Invalid VCS_{}_DATE", role))?,
+            None => {
+                let now = chrono::Local::now();
+                (now.timestamp(), now.offset().local_minus_utc() / 60)
+            }
+        };
+        Ok(crate::objects::Signature {
+            name: name.unwrap_or_else(crate::config::default_user_name),
+            email: email.unwrap_or_else(crate::config::default_user_email),
+            time,
+            offset,
+        })
+    }
+
+    /// Returns the commit being merged in while a conflicted merge is in progress.
+    pub fn merge_head(&self) -> Result<Option<String>> {
+        let path = self.vcs_dir.join("MERGE_HEAD");
+        if path.exists() {
+            Ok(Some(fs::read_to_string(path)?.trim().to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The message prepared for the commit concluding an in-progress merge.
+    pub fn merge_message(&self) -> Result<Option<String>> {
+        let path = self.vcs_dir.join("MERGE_MSG");
+        if path.exists() {
+            Ok(Some(fs::read_to_string(path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Records an in-progress merge so the next commit gets `merge_head` as a second parent.
+    pub fn write_merge_state(&self, merge_head: &str, message: &str) -> Result<()> {
+        self.write_atomic(
+            &self.vcs_dir.join("MERGE_HEAD"),
+            format!("{}\n", merge_head).as_bytes(),
+        )?;
+        self.write_atomic(&self.vcs_dir.join("MERGE_MSG"), message.as_bytes())
+    }
+
+    pub fn clear_merge_state(&self) -> Result<()> {
+        for name in ["MERGE_HEAD", "MERGE_MSG"] {
+            let path = self.vcs_dir.join(name);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the ref HEAD points at (e.g. `refs/heads/master`), or `None`
+    /// when HEAD is detached.
+    pub fn head_ref(&self) -> Result<Option<String>> {
         let head_path = self.vcs_dir.join("HEAD");
         let content = fs::read_to_string(&head_path)?;
-        let content = content.trim();
-        if content.starts_with("ref: ") {
-            let ref_path = content
-                .strip_prefix("ref: ")
-                .ok_or_else(|| anyhow::anyhow!("Invalid HEAD format"))?
-                .trim();
-            let ref_file = self.vcs_dir.join(ref_path);
-            let parent = ref_file
-                .parent()
-                .with_context(|| format!("Ref file has no parent: {}", ref_file.display()))?;
-            fs::create_dir_all(parent)?;
+        Ok(content
+            .trim()
+            .strip_prefix("ref: ")
+            .map(|r| r.trim().to_string()))
+    }
 
-            // Atomic write for ref file
-            let mut temp_file =
-                NamedTempFile::new_in(parent).context("Failed to create temporary ref file")?;
-            temp_file.write_all(format!("{}\n", commit_hash).as_bytes())?;
-            temp_file.flush()?;
-            temp_file
-                .persist(&ref_file)
-                .with_context(|| format!("Failed to persist ref to {}", ref_file.display()))?;
+    /// Returns the short name of the current branch, or `None` when HEAD is detached.
+    pub fn current_branch(&self) -> Result<Option<String>> {
+        Ok(self
+            .head_ref()?
+            .and_then(|r| r.strip_prefix("refs/heads/").map(|b| b.to_string())))
+    }
+
+    /// Resolves `HEAD`, a full ref name (`refs/heads/x`) or a branch name to a commit hash.
+    pub fn resolve_ref(&self, name: &str) -> Result<Option<String>> {
+        if name == "HEAD" {
+            return self.get_head();
+        }
+        let ref_name = if name.starts_with("refs/") {
+            name.to_string()
         } else {
-            // Atomic write for HEAD
-            let mut temp_file = NamedTempFile::new_in(&self.vcs_dir)
-                .context("Failed to create temporary HEAD file")?;
-            temp_file.write_all(format!("{}\n", commit_hash).as_bytes())?;
-            temp_file.flush()?;
-            temp_file
-                .persist(&head_path)
-                .with_context(|| format!("Failed to persist HEAD to {}", head_path.display()))?;
+            format!("refs/heads/{}", name)
+        };
+        check_ref_path(&ref_name)?;
+        let ref_file = self.vcs_dir.join(ref_name);
+        if ref_file.is_file() {
+            Ok(Some(fs::read_to_string(ref_file)?.trim().to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Resolves a revision expression (see `revision::resolve`), peeling
+    /// annotated tags down to the object they point at.
+    pub fn resolve_revision(&self, rev: &str) -> Result<String> {
+        self.peel(&crate::revision::resolve(self, rev)?)
+    }
+
+    /// Resolves a revision expression to the object it names, keeping tag objects.
+    pub fn resolve_object(&self, rev: &str) -> Result<String> {
+        crate::revision::resolve(self, rev)
+    }
+
+    /// Resolves a revision or an `A..B` / `A...B` range.
+    pub fn resolve_range(&self, expr: &str) -> Result<crate::revision::RevRange> {
+        crate::revision::resolve_range(self, expr)
+    }
+
+    /// Follows annotated tags to the object they ultimately tag.
+    pub fn peel(&self, hash: &str) -> Result<String> {
+        let mut hash = hash.to_string();
+        while let crate::objects::GitObject::Tag(tag) = self.read_object(&hash)? {
+            hash = tag.object;
+        }
+        Ok(hash)
+    }
+
+    /// Lists every ref under `refs/` with the hash it points to, sorted by name.
+    pub fn list_refs(&self) -> Result<Vec<(String, String)>> {
+        let refs_dir = self.vcs_dir.join("refs");
+        let mut refs = Vec::new();
+        if !refs_dir.exists() {
+            return Ok(refs);
+        }
+        for entry in walkdir::WalkDir::new(&refs_dir) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let rel = entry.path().strip_prefix(&self.vcs_dir)?;
+                let name = rel.to_string_lossy().replace('\\', "/");
+                let hash = fs::read_to_string(entry.path())?.trim().to_string();
+                refs.push((name, hash));
+            }
+        }
+        refs.sort();
+        Ok(refs)
+    }
+
+    /// Lists branch names under `refs/heads`, sorted.
+    pub fn list_branches(&self) -> Result<Vec<String>> {
+        let heads = self.vcs_dir.join("refs").join("heads");
+        let mut branches = Vec::new();
+        if !heads.exists() {
+            return Ok(branches);
+        }
+        for entry in walkdir::WalkDir::new(&heads) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let rel = entry.path().strip_prefix(&heads)?;
+                branches.push(rel.to_string_lossy().replace('\\', "/"));
+            }
+        }
+        branches.sort();
+        Ok(branches)
+    }
+
+    pub fn create_branch(&self, name: &str, commit_hash: &str, reason: &str) -> Result<()> {
+        self.check_new_branch(name)?;
+        self.write_ref(&format!("refs/heads/{}", name), commit_hash, reason)
+    }
+
+    /// Checks that a branch called `name` could be created.
+    fn check_new_branch(&self, name: &str) -> Result<()> {
+        validate_branch_name(name)?;
+        let ref_file = self.vcs_dir.join("refs").join("heads").join(name);
+        if ref_file.is_file() {
+            anyhow::bail!("A branch named '{}' already exists", name);
+        }
+        // `a` and `a/b` cannot coexist since one would be both a file and a directory
+        let heads = self.vcs_dir.join("refs").join("heads");
+        let conflict = ref_file.is_dir()
+            || ref_file
+                .ancestors()
+                .take_while(|p| *p != heads)
+                .skip(1)
+                .any(|p| p.is_file());
+        if conflict {
+            anyhow::bail!(
+                "Cannot create branch '{}': conflicts with an existing branch",
+                name
+            );
         }
         Ok(())
     }
+
+    pub fn delete_branch(&self, name: &str) -> Result<()> {
+        validate_branch_name(name)?;
+        if self.current_branch()?.as_deref() == Some(name) {
+            anyhow::bail!("Cannot delete branch '{}' checked out at HEAD", name);
+        }
+        let ref_file = self.vcs_dir.join("refs").join("heads").join(name);
+        if !ref_file.is_file() {
+            anyhow::bail!("Branch '{}' not found", name);
+        }
+        fs::remove_file(&ref_file)?;
+        self.remove_empty_ref_dirs(&ref_file);
+        crate::reflog::delete(self, &format!("refs/heads/{}", name))
+    }
+
+    pub fn rename_branch(&self, old: &str, new: &str) -> Result<()> {
+        let hash = self
+            .resolve_ref(&format!("refs/heads/{}", old))?
+            .with_context(|| format!("Branch '{}' not found", old))?;
+        let (old_ref, new_ref) = (format!("refs/heads/{}", old), format!("refs/heads/{}", new));
+        if self.resolve_ref(&new_ref)?.is_some() {
+            anyhow::bail!("A branch named '{}' already exists", new);
+        }
+        // Checked before the log moves, so a refused rename leaves nothing behind
+        self.check_new_branch(new)?;
+        crate::reflog::rename(self, &old_ref, &new_ref)?;
+        let reason = format!("Branch: renamed {} to {}", old_ref, new_ref);
+        self.create_branch(new, &hash, &reason)?;
+
+        let old_file = self.vcs_dir.join("refs").join("heads").join(old);
+        fs::remove_file(&old_file)?;
+        self.remove_empty_ref_dirs(&old_file);
+
+        if self.current_branch()?.as_deref() == Some(old) {
+            self.set_symbolic_head(&new_ref, &reason)?;
+        }
+        Ok(())
+    }
+
+    /// Points HEAD at a ref (e.g. `refs/heads/master`), logging the move of HEAD
+    /// under `reason` when it changes which commit is checked out.
+    pub fn set_symbolic_head(&self, ref_name: &str, reason: &str) -> Result<()> {
+        let old = self.get_head()?;
+        self.write_atomic(
+            &self.vcs_dir.join("HEAD"),
+            format!("ref: {}\n", ref_name).as_bytes(),
+        )?;
+        if let Some(new) = self.get_head()? {
+            crate::reflog::append(self, "HEAD", old.as_deref(), &new, reason)?;
+        }
+        Ok(())
+    }
+
+    /// Atomically writes `hash` to the ref file `ref_name` (relative to `.vcs`) and
+    /// records the update, with `reason`, in the ref's reflog and in HEAD's when
+    /// the ref is the checked-out branch.
+    pub fn write_ref(&self, ref_name: &str, hash: &str, reason: &str) -> Result<()> {
+        check_ref_path(ref_name)?;
+        let old = self.resolve_ref(ref_name)?;
+        let ref_file = self.vcs_dir.join(ref_name);
+        let parent = ref_file
+            .parent()
+            .with_context(|| format!("Ref file has no parent: {}", ref_file.display()))?;
+        fs::create_dir_all(parent)?;
+        self.write_atomic(&ref_file, format!("{}\n", hash).as_bytes())?;
+
+        crate::reflog::append(self, ref_name, old.as_deref(), hash, reason)?;
+        if ref_name != "HEAD" && self.head_ref()?.as_deref() == Some(ref_name) {
+            crate::reflog::append(self, "HEAD", old.as_deref(), hash, reason)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `hash` to the ref file `ref_name` without touching its log, for
+    /// callers that rewrite the log themselves (e.g. dropping a stash entry).
+    pub fn write_ref_unlogged(&self, ref_name: &str, hash: &str) -> Result<()> {
+        check_ref_path(ref_name)?;
+        self.write_atomic(
+            &self.vcs_dir.join(ref_name),
+            format!("{}\n", hash).as_bytes(),
+        )
+    }
+
+    /// Deletes the ref file `ref_name` (relative to `.vcs`) and any directories it leaves empty.
+    pub fn delete_ref(&self, ref_name: &str) -> Result<()> {
+        check_ref_path(ref_name)?;
+        let ref_file = self.vcs_dir.join(ref_name);
+        if ref_file.is_file() {
+            fs::remove_file(&ref_file)?;
+        }
+        crate::reflog::delete(self, ref_name)?;
+        // Keep the namespace directory itself (e.g. refs/heads)
+        let namespace: PathBuf = self
+            .vcs_dir
+            .join(ref_name)
+            .components()
+            .take(self.vcs_dir.components().count() + 2)
+            .collect();
+        let mut dir = ref_file.parent();
+        while let Some(d) = dir {
+            if d == namespace || !d.starts_with(&namespace) || fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+        Ok(())
+    }
+
+    fn write_atomic(&self, path: &Path, content: &[u8]) -> Result<()> {
+        use std::io::Write;
+        use tempfile::NamedTempFile;
+
+        let parent = path
+            .parent()
+            .with_context(|| format!("Path has no parent: {}", path.display()))?;
+        let mut temp_file =
+            NamedTempFile::new_in(parent).context("Failed to create temporary file")?;
+        temp_file.write_all(content)?;
+        temp_file.flush()?;
+        temp_file
+            .persist(path)
+            .with_context(|| format!("Failed to persist {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Removes directories left empty under `refs/heads` after deleting `ref_file`.
+    fn remove_empty_ref_dirs(&self, ref_file: &Path) {
+        let heads = self.vcs_dir.join("refs").join("heads");
+        let mut dir = ref_file.parent();
+        while let Some(d) = dir {
+            if d == heads || !d.starts_with(&heads) || fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+}
+
+/// Older loose objects were stored raw; compressed ones start with a zlib header instead.
+fn is_uncompressed_object(data: &[u8]) -> bool {
+    ["blob ", "tree ", "commit "]
+        .iter()
+        .any(|prefix| data.starts_with(prefix.as_bytes()))
+}
+
+/// Rejects branch names that would escape `refs/heads` or confuse revision parsing.
+pub fn validate_branch_name(name: &str) -> Result<()> {
+    if !is_valid_ref_name(name) {
+        anyhow::bail!("'{}' is not a valid branch name", name);
+    }
+    Ok(())
+}
+
+/// Refuses ref names that would point outside `.vcs/refs` once joined onto
+/// `.vcs` (e.g. `refs/heads/../../HEAD`).
+fn check_ref_path(ref_name: &str) -> Result<()> {
+    let inside = ref_name == "HEAD"
+        || (ref_name.starts_with("refs/")
+            && ref_name
+                .split(['/', '\\'])
+                .all(|part| !part.is_empty() && part != "." && part != ".."));
+    if !inside {
+        anyhow::bail!("Invalid ref name '{}'", ref_name);
+    }
+    Ok(())
+}
+
+pub fn validate_tag_name(name: &str) -> Result<()> {
+    if !is_valid_ref_name(name) {
+        anyhow::bail!("'{}' is not a valid tag name", name);
+    }
+    Ok(())
+}
+
+fn is_valid_ref_name(name: &str) -> bool {
+    let invalid = name.is_empty()
+        || name == "HEAD"
+        || name.starts_with('-')
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name.ends_with(".lock")
+        || name.contains("..")
+        || name.contains("//")
+        || name.contains("@{")
+        || name
+            .split('/')
+            .any(|part| part.is_empty() || part.starts_with('.'))
+        || name
+            .chars()
+            .any(|c| c.is_control() || " ~^:?*[\\".contains(c));
+    !invalid
 }
@@ -0,0 +1,342 @@
+use crate::history;
+use crate::objects::GitObject;
+use crate::repository::Repository;
+use anyhow::{Context, Result};
+
+/// Shortest abbreviated hash accepted as a revision.
+const MIN_ABBREV: usize = 4;
+
+/// Commits selected by a revision or range: everything reachable from
+/// `include` that is not reachable from `exclude`.
+pub struct RevRange {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// A step applied after the base of a revision expression.
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    /// `~n`: the nth first-parent ancestor.
+    Ancestor(usize),
+    /// `^n`: the nth parent; `^0` is the commit itself.
+    Parent(usize),
+    /// `^{}`: peel tags; `^{type}` additionally checks the object's type.
+    Peel(Option<String>),
+}
+
+/// Resolves a single revision expression to an object hash without peeling
+/// annotated tags at the end: `HEAD`, `@`, branch, tag and remote-tracking names,
+/// full or unique abbreviated hashes, `name@{n}` reflog entries, followed by
+/// any number of `~n`, `^n` and `^{}` steps.
+pub fn resolve(repo: &Repository, expr: &str) -> Result<String> {
+    if expr.contains("..") {
+        anyhow::bail!("'{}' is a range, but a single revision is expected", expr);
+    }
+    let split = expr.find(['~', '^']).unwrap_or(expr.len());
+    let (base, steps) = expr.split_at(split);
+    let steps = parse_steps(expr, steps)?;
+
+    let mut hash = resolve_base(repo, base)?;
+    for step in steps {
+        hash = apply_step(repo, expr, &hash, step)?;
+    }
+    Ok(hash)
+}
+
+fn apply_step(repo: &Repository, expr: &str, hash: &str, step: Step) -> Result<String> {
+    match step {
+        Step::Ancestor(n) => {
+            let mut hash = peel_to_commit(repo, hash)?;
+            for _ in 0..n {
+                let commit = history::read_commit(repo, &hash)?;
+                hash = commit
+                    .parents
+                    .first()
+                    .cloned()
+                    .with_context(|| format!("Revision '{}' goes past a root commit", expr))?;
+            }
+            Ok(hash)
+        }
+        Step::Parent(0) => peel_to_commit(repo, hash),
+        Step::Parent(n) => {
+            let commit = history::read_commit(repo, &peel_to_commit(repo, hash)?)?;
+            commit
+                .parents
+                .get(n - 1)
+                .cloned()
+                .with_context(|| format!("Revision '{}': commit has no parent {}", expr, n))
+        }
+        Step::Peel(None) => repo.peel(hash),
+        Step::Peel(Some(kind)) => {
+            let peeled = repo.peel(hash)?;
+            let raw = repo.read_raw_object(&peeled)?;
+            let (obj_type, _) = crate::pack::split_raw(&raw)?;
+            match (kind.as_str(), obj_type) {
+                (k, t) if k == t => Ok(peeled),
+                ("tree", "commit") => Ok(history::read_commit(repo, &peeled)?.tree),
+                _ => anyhow::bail!("Revision '{}' does not name a {}", expr, kind),
+            }
+        }
+    }
+}
+
+/// Resolves a revision or a range. `A..B` selects commits reachable from B but
+/// not A; `A...B` those reachable from either but not from both. An omitted
+/// side means `HEAD`.
+pub fn resolve_range(repo: &Repository, expr: &str) -> Result<RevRange> {
+    let side = |s: &str| repo.resolve_revision(if s.is_empty() { "HEAD" } else { s });
+    if let Some((a, b)) = expr.split_once("...") {
+        let (a, b) = (side(a)?, side(b)?);
+        let exclude = history::merge_bases(repo, &a, &b)?;
+        return Ok(RevRange {
+            include: vec![a, b],
+            exclude,
+        });
+    }
+    if let Some((a, b)) = expr.split_once("..") {
+        return Ok(RevRange {
+            include: vec![side(b)?],
+            exclude: vec![side(a)?],
+        });
+    }
+    Ok(RevRange {
+        include: vec![repo.resolve_revision(expr)?],
+        exclude: Vec::new(),
+    })
+}
+
+fn parse_steps(expr: &str, mut rest: &str) -> Result<Vec<Step>> {
+    let invalid = || anyhow::anyhow!("Invalid revision '{}'", expr);
+    let mut steps = Vec::new();
+    while let Some(op) = rest.chars().next() {
+        rest = &rest[op.len_utf8()..];
+        if op == '^' && rest.starts_with('{') {
+            let close = rest.find('}').ok_or_else(invalid)?;
+            let kind = &rest[1..close];
+            steps.push(Step::Peel((!kind.is_empty()).then(|| kind.to_string())));
+            rest = &rest[close + 1..];
+            continue;
+        }
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let n = match &rest[..digits] {
+            "" => 1,
+            d => d.parse().map_err(|_| invalid())?,
+        };
+        rest = &rest[digits..];
+        match op {
+            '~' => steps.push(Step::Ancestor(n)),
+            '^' => steps.push(Step::Parent(n)),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(steps)
+}
+
+fn resolve_base(repo: &Repository, base: &str) -> Result<String> {
+    if let Some(open) = base.find("@{") {
+        let inner = base[open + 2..]
+            .strip_suffix('}')
+            .with_context(|| format!("Invalid revision '{}'", base))?;
+        let n: usize = inner
+            .parse()
+            .with_context(|| format!("Invalid reflog index in '{}'", base))?;
+        return reflog_entry(repo, &base[..open], n);
+    }
+
+    match base {
+        "" => anyhow::bail!("Empty revision"),
+        "HEAD" | "@" => {
+            return repo
+                .get_head()?
+                .context("HEAD does not point to a commit yet")
+        }
+        "MERGE_HEAD" => return repo.merge_head()?.context("No merge in progress"),
+        _ => {}
+    }
+    for ref_name in candidate_refs(base) {
+        if let Some(hash) = repo.resolve_ref(&ref_name)? {
+            return Ok(hash);
+        }
+    }
+
+    if base.len() >= MIN_ABBREV && base.len() <= 40 && base.chars().all(|c| c.is_ascii_hexdigit()) {
+        let prefix = base.to_ascii_lowercase();
+        let mut matches = repo.objects_with_prefix(&prefix)?;
+        match matches.len() {
+            0 => {}
+            1 => return Ok(matches.remove(0)),
+            _ => {
+                let mut message = format!("Short hash '{}' is ambiguous; candidates are:", base);
+                for hash in &matches {
+                    let kind = match repo.read_object(hash) {
+                        Ok(GitObject::Blob(_)) => "blob",
+                        Ok(GitObject::Tree(_)) => "tree",
+                        Ok(GitObject::Commit(_)) => "commit",
+                        Ok(GitObject::Tag(_)) => "tag",
+                        Err(_) => "unreadable",
+                    };
+                    message.push_str(&format!("\n  {} {}", hash, kind));
+                }
+                anyhow::bail!(message);
+            }
+        }
+    }
+    anyhow::bail!("Not a valid object name: '{}'", base)
+}
+
+/// Refs a short name may refer to, in order of precedence.
+fn candidate_refs(name: &str) -> Vec<String> {
+    if name.starts_with("refs/") {
+        return vec![name.to_string()];
+    }
+    vec![
+        format!("refs/heads/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/{}", name),
+    ]
+}
+
+/// The value `name` had `n` updates ago; an empty name means the current branch.
+fn reflog_entry(repo: &Repository, name: &str, n: usize) -> Result<String> {
+    let ref_name = logged_ref(repo, name)?;
+    let entries = crate::reflog::read(repo, &ref_name)?;
+    if n >= entries.len() {
+        anyhow::bail!(
+            "Log for '{}' only has {} entries",
+            ref_name.strip_prefix("refs/heads/").unwrap_or(&ref_name),
+            entries.len()
+        );
+    }
+    Ok(entries[entries.len() - 1 - n].new.clone())
+}
+
+/// The ref whose log a short name refers to: `HEAD`, the current branch for an
+/// empty name, or the first candidate ref that has a log.
+pub fn logged_ref(repo: &Repository, name: &str) -> Result<String> {
+    Ok(match name {
+        "" => match repo.current_branch()? {
+            Some(branch) => format!("refs/heads/{}", branch),
+            None => "HEAD".to_string(),
+        },
+        "HEAD" | "@" => "HEAD".to_string(),
+        _ => candidate_refs(name)
+            .into_iter()
+            .find(|r| crate::reflog::log_path(repo, r).is_file())
+            .with_context(|| format!("No reflog for '{}'", name))?,
+    })
+}
+
+fn peel_to_commit(repo: &Repository, hash: &str) -> Result<String> {
+    let peeled = repo.peel(hash)?;
+    match repo.read_object(&peeled)? {
+        GitObject::Commit(_) => Ok(peeled),
+        _ => anyhow::bail!("{} is not a commit", &peeled[..8]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::ObjectFormat;
+
+    fn steps(expr: &str) -> Result<Vec<Step>> {
+        let split = expr.find(['~', '^']).unwrap_or(expr.len());
+        parse_steps(expr, &expr[split..])
+    }
+
+    #[test]
+    fn parses_step_chains() {
+        assert_eq!(steps("HEAD").unwrap(), vec![]);
+        assert_eq!(
+            steps("HEAD~^").unwrap(),
+            vec![Step::Ancestor(1), Step::Parent(1)]
+        );
+        assert_eq!(
+            steps("main~3^2^0").unwrap(),
+            vec![Step::Ancestor(3), Step::Parent(2), Step::Parent(0)]
+        );
+        assert_eq!(
+            steps("v1^{}^{tree}").unwrap(),
+            vec![Step::Peel(None), Step::Peel(Some("tree".to_string()))]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_steps_without_panicking() {
+        for expr in [
+            "HEAD~1é",
+            "HEAD^é",
+            "HEAD~é1",
+            "HEAD^{",
+            "HEAD^{tree",
+            "HEAD~x",
+            "HEAD~99999999999999999999999",
+        ] {
+            assert!(steps(expr).is_err(), "{} should be rejected", expr);
+        }
+    }
+
+    #[test]
+    fn short_names_try_refs_in_order() {
+        assert_eq!(
+            candidate_refs("main"),
+            vec![
+                "refs/heads/main",
+                "refs/tags/main",
+                "refs/remotes/main",
+                "refs/main"
+            ]
+        );
+        assert_eq!(candidate_refs("refs/tags/v1"), vec!["refs/tags/v1"]);
+    }
+
+    fn commit(repo: &Repository, parents: &[&str], time: i64) -> String {
+        crate::test_support::commit_at(repo, parents, &[], time)
+    }
+
+    #[test]
+    fn resolves_expressions_against_a_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::create_at(dir.path().to_path_buf(), ObjectFormat::Vcs).unwrap();
+        // root <- first <- merge, with side merged in as the second parent
+        let root = commit(&repo, &[], 100);
+        let first = commit(&repo, &[&root], 200);
+        let side = commit(&repo, &[&root], 300);
+        let merge = commit(&repo, &[&first, &side], 400);
+        repo.write_ref_unlogged("refs/heads/master", &merge)
+            .unwrap();
+        repo.write_ref_unlogged("refs/heads/side", &side).unwrap();
+
+        assert_eq!(resolve(&repo, "HEAD").unwrap(), merge);
+        assert_eq!(resolve(&repo, "@").unwrap(), merge);
+        assert_eq!(resolve(&repo, "master~1").unwrap(), first);
+        assert_eq!(resolve(&repo, "HEAD^2").unwrap(), side);
+        assert_eq!(resolve(&repo, "HEAD~2").unwrap(), root);
+        assert_eq!(resolve(&repo, "HEAD^2~1").unwrap(), root);
+        assert_eq!(resolve(&repo, "side^0").unwrap(), side);
+        assert_eq!(resolve(&repo, &merge[..8]).unwrap(), merge);
+        assert_eq!(
+            resolve(&repo, "HEAD^{tree}").unwrap(),
+            history::read_commit(&repo, &merge).unwrap().tree
+        );
+
+        assert!(resolve(&repo, "HEAD~3").is_err());
+        assert!(resolve(&repo, "HEAD^3").is_err());
+        assert!(resolve(&repo, "HEAD^{blob}").is_err());
+        assert!(resolve(&repo, "missing").is_err());
+        assert!(resolve(&repo, "master..side").is_err());
+        assert!(resolve(&repo, "HEAD~1é").is_err());
+
+        let range = resolve_range(&repo, "side..master").unwrap();
+        assert_eq!(
+            (range.include, range.exclude),
+            (vec![merge.clone()], vec![side.clone()])
+        );
+        let range = resolve_range(&repo, "side...master~1").unwrap();
+        assert_eq!(range.include, vec![side, first]);
+        assert_eq!(range.exclude, vec![root]);
+    }
+}
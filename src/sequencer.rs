@@ -0,0 +1,234 @@
+use crate::repository::Repository;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+/// What to do at one step of a sequence.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Apply the commit's change on top of HEAD.
+    Pick,
+    /// Apply the inverse of the commit's change.
+    Revert,
+    /// Pick, then edit the commit message.
+    Reword,
+    /// Pick, then stop so the commit can be amended.
+    Edit,
+    /// Fold the change into the previous commit, combining the messages.
+    Squash,
+    /// Fold the change into the previous commit, keeping its message.
+    Fixup,
+    /// Leave the commit out.
+    Drop,
+    /// Run a shell command.
+    Exec,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Pick => "pick",
+            Action::Revert => "revert",
+            Action::Reword => "reword",
+            Action::Edit => "edit",
+            Action::Squash => "squash",
+            Action::Fixup => "fixup",
+            Action::Drop => "drop",
+            Action::Exec => "exec",
+        }
+    }
+
+    /// Parses an action name or its one-letter abbreviation.
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "pick" | "p" => Ok(Action::Pick),
+            "revert" => Ok(Action::Revert),
+            "reword" | "r" => Ok(Action::Reword),
+            "edit" | "e" => Ok(Action::Edit),
+            "squash" | "s" => Ok(Action::Squash),
+            "fixup" | "f" => Ok(Action::Fixup),
+            "drop" | "d" => Ok(Action::Drop),
+            "exec" | "x" => Ok(Action::Exec),
+            _ => anyhow::bail!("Unknown action '{}'", name),
+        }
+    }
+}
+
+/// One line of a sequence: an action and the commit it applies to, or the
+/// shell command for `exec`.
+#[derive(Clone)]
+pub struct Instruction {
+    pub action: Action,
+    pub target: String,
+}
+
+impl Instruction {
+    fn parse(line: &str) -> Result<Self> {
+        let (action, target) = line
+            .split_once(' ')
+            .with_context(|| format!("Invalid sequencer instruction: {}", line))?;
+        Ok(Instruction {
+            action: Action::parse(action)?,
+            target: target.trim().to_string(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        format!("{} {}\n", self.action.name(), self.target)
+    }
+}
+
+/// Parses a todo list edited by the user: one `<action> <commit> [subject]` or
+/// `exec <command>` per line; blank lines and `#` comments are ignored.
+pub fn parse_todo(repo: &Repository, text: &str) -> Result<Vec<Instruction>> {
+    let mut todo = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (action, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let action = Action::parse(action)?;
+        let rest = rest.trim();
+        let target = match action {
+            Action::Exec if rest.is_empty() => anyhow::bail!("Missing command: {}", line),
+            Action::Exec => rest.to_string(),
+            _ => {
+                let commit = rest.split_whitespace().next().unwrap_or("");
+                if commit.is_empty() {
+                    anyhow::bail!("Missing commit: {}", line);
+                }
+                repo.resolve_revision(commit)
+                    .with_context(|| format!("Invalid todo line: {}", line))?
+            }
+        };
+        todo.push(Instruction { action, target });
+    }
+    Ok(todo)
+}
+
+/// Formats a todo list for editing, with abbreviated hashes and subjects.
+pub fn format_todo(repo: &Repository, todo: &[Instruction]) -> Result<String> {
+    let mut text = String::new();
+    for instruction in todo {
+        if instruction.action == Action::Exec {
+            text.push_str(&instruction.to_line());
+            continue;
+        }
+        let commit = crate::history::read_commit(repo, &instruction.target)?;
+        text.push_str(&format!(
+            "{} {} {}\n",
+            instruction.action.name(),
+            &instruction.target[..8],
+            commit.message.lines().next().unwrap_or("")
+        ));
+    }
+    Ok(text)
+}
+
+/// State of a multi-commit operation (`cherry-pick`, `revert`, `rebase`) kept
+/// in `.vcs/sequencer` so that it can stop and later be resumed or aborted:
+///
+/// - `command`: the command that started it
+/// - `orig-head`: HEAD before it started, restored by `--abort`
+/// - `todo`: instructions not yet started
+/// - `current`: the instruction that stopped on a conflict
+///
+/// Commands keep further values of their own there (see [`Sequencer::value`]).
+pub struct Sequencer {
+    dir: PathBuf,
+}
+
+impl Sequencer {
+    pub fn new(repo: &Repository) -> Self {
+        Sequencer {
+            dir: repo.vcs_dir.join("sequencer"),
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.dir.is_dir()
+    }
+
+    pub fn start(&self, command: &str, orig_head: &str, todo: &[Instruction]) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        self.write("command", &format!("{}\n", command))?;
+        self.write("orig-head", &format!("{}\n", orig_head))?;
+        self.set_todo(todo)
+    }
+
+    /// The command that started the sequence in progress.
+    pub fn command(&self) -> Result<String> {
+        self.read("command")
+    }
+
+    pub fn orig_head(&self) -> Result<String> {
+        self.read("orig-head")
+    }
+
+    pub fn todo(&self) -> Result<Vec<Instruction>> {
+        self.read("todo")?
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(Instruction::parse)
+            .collect()
+    }
+
+    pub fn set_todo(&self, todo: &[Instruction]) -> Result<()> {
+        let text: String = todo.iter().map(Instruction::to_line).collect();
+        self.write("todo", &text)
+    }
+
+    /// The instruction that stopped on a conflict, if any.
+    pub fn current(&self) -> Result<Option<Instruction>> {
+        self.value("current")?
+            .map(|line| Instruction::parse(&line))
+            .transpose()
+    }
+
+    pub fn set_current(&self, instruction: Option<&Instruction>) -> Result<()> {
+        let line = instruction.map(Instruction::to_line);
+        self.set_value("current", line.as_deref())
+    }
+
+    /// Reads a named value stored with the sequence, if set.
+    pub fn value(&self, name: &str) -> Result<Option<String>> {
+        if !self.dir.join(name).is_file() {
+            return Ok(None);
+        }
+        self.read(name).map(Some)
+    }
+
+    /// Stores a named value with the sequence, or removes it when `None`.
+    pub fn set_value(&self, name: &str, value: Option<&str>) -> Result<()> {
+        match value {
+            Some(value) => self.write(name, value),
+            None => {
+                let path = self.dir.join(name);
+                if path.is_file() {
+                    fs::remove_file(path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes all state, ending the sequence.
+    pub fn finish(&self) -> Result<()> {
+        if self.dir.is_dir() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, name: &str) -> Result<String> {
+        let path = self.dir.join(name);
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(text.trim_end_matches('\n').to_string())
+    }
+
+    fn write(&self, name: &str, content: &str) -> Result<()> {
+        let path = self.dir.join(name);
+        fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
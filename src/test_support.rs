@@ -0,0 +1,63 @@
+//! Repository fixtures shared by the unit tests.
+
+use crate::objects::{sort_tree_entries, Commit, GitObject, ObjectFormat, Signature, TreeEntry};
+use crate::repository::Repository;
+use std::path::Path;
+
+/// Creates an empty repository in the original object format at `dir`.
+pub fn repo(dir: &Path) -> Repository {
+    Repository::create_at(dir.to_path_buf(), ObjectFormat::Vcs).unwrap()
+}
+
+/// Commits a tree holding `file.txt` with `content`.
+pub fn commit(repo: &Repository, parents: &[&str], content: &str) -> String {
+    write_commit(
+        repo,
+        parents,
+        &[("file.txt", content)],
+        1_700_000_000,
+        content,
+    )
+}
+
+/// Commits a tree holding `files` (name and content pairs) at `time`.
+pub fn commit_at(repo: &Repository, parents: &[&str], files: &[(&str, &str)], time: i64) -> String {
+    write_commit(repo, parents, files, time, &format!("commit at {}", time))
+}
+
+fn write_commit(
+    repo: &Repository,
+    parents: &[&str],
+    files: &[(&str, &str)],
+    time: i64,
+    message: &str,
+) -> String {
+    let mut entries = Vec::new();
+    for (name, content) in files {
+        let blob = repo
+            .write_object(&GitObject::Blob(content.as_bytes().to_vec()))
+            .unwrap();
+        entries.push(TreeEntry {
+            mode: "100644".to_string(),
+            name: name.to_string(),
+            hash: blob,
+        });
+    }
+    sort_tree_entries(&mut entries, repo.format);
+    let tree = repo.write_object(&GitObject::Tree(entries)).unwrap();
+    let signature = Signature {
+        name: "Test".to_string(),
+        email: "test@example.com".to_string(),
+        time,
+        offset: 0,
+    };
+    repo.write_object(&GitObject::Commit(Commit {
+        tree,
+        parents: parents.iter().map(|p| p.to_string()).collect(),
+        author: signature.clone(),
+        committer: signature,
+        extra_headers: Vec::new(),
+        message: format!("{}\n", message),
+    }))
+    .unwrap()
+}
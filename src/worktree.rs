@@ -0,0 +1,470 @@
+use crate::objects::{GitObject, IndexEntry, ObjectFormat};
+use crate::repository::Repository;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// Normalize line endings: CRLF -> LF for consistency across platforms.
+/// This prevents false "modified" files when collaborating across Windows/Unix.
+/// Only normalize if the file appears to be text (no null bytes).
+pub fn normalize_line_endings(data: Vec<u8>) -> Vec<u8> {
+    if !data.contains(&b'\r') || crate::diff::is_binary(&data) {
+        return data;
+    }
+
+    let mut normalized = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'\r' && i + 1 < data.len() && data[i + 1] == b'\n' {
+            // CRLF -> LF
+            normalized.push(b'\n');
+            i += 2;
+        } else if data[i] == b'\r' {
+            // Standalone CR -> LF
+            normalized.push(b'\n');
+            i += 1;
+        } else {
+            normalized.push(data[i]);
+            i += 1;
+        }
+    }
+    normalized
+}
+
+/// Whether `path` is something `add` tracks: a regular file, or in Git format also a symlink.
+pub fn is_worktree_file(repo: &Repository, path: &Path) -> bool {
+    path.is_file() || (repo.format == ObjectFormat::Git && path.is_symlink())
+}
+
+/// Reads a working tree file as the blob content `add` stores. The original
+/// format normalizes line endings; Git format keeps bytes as-is and stores a
+/// symlink's target.
+pub fn read_worktree_blob(repo: &Repository, path: &Path) -> Result<Vec<u8>> {
+    if repo.format == ObjectFormat::Vcs {
+        return Ok(normalize_line_endings(crate::objects::read_file(path)?));
+    }
+    if path.is_symlink() {
+        let target = fs::read_link(path)
+            .with_context(|| format!("Failed to read link: {}", path.display()))?;
+        return Ok(target.to_string_lossy().replace('\\', "/").into_bytes());
+    }
+    crate::objects::read_file(path)
+}
+
+/// Hashes a working tree file the same way `add` would store it, or `None` if it is missing.
+pub fn worktree_hash(repo: &Repository, rel_path: &str) -> Result<Option<String>> {
+    let full_path = repo.worktree.join(rel_path);
+    if !is_worktree_file(repo, &full_path) {
+        return Ok(None);
+    }
+    let blob = GitObject::Blob(read_worktree_blob(repo, &full_path)?);
+    Ok(Some(crate::utils::hash_object(&blob.serialize()?)))
+}
+
+/// Returns the mode recorded for a working tree file. The original format keeps
+/// the raw permission bits; Git format records only `100644`, `100755` or `120000`.
+pub fn file_mode(repo: &Repository, path: &Path) -> Result<String> {
+    if repo.format == ObjectFormat::Git {
+        if path.is_symlink() {
+            return Ok("120000".to_string());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if fs::metadata(path)?.permissions().mode() & 0o111 != 0 {
+                return Ok("100755".to_string());
+            }
+        }
+        return Ok("100644".to_string());
+    }
+
+    let metadata = fs::metadata(path)?;
+    let mode = if cfg!(unix) {
+        use std::os::unix::fs::PermissionsExt;
+        format!("{:o}", metadata.permissions().mode())
+    } else {
+        "100644".to_string() // Regular file on Windows
+    };
+    Ok(mode)
+}
+
+/// Returns the tree hash of a commit.
+pub fn commit_tree(repo: &Repository, commit_hash: &str) -> Result<String> {
+    match repo.read_object(commit_hash)? {
+        GitObject::Commit(commit) => Ok(commit.tree),
+        _ => anyhow::bail!("{} is not a commit", commit_hash),
+    }
+}
+
+/// Flattens a tree into index entries, sorted by path.
+pub fn read_tree_entries(repo: &Repository, tree_hash: &str) -> Result<Vec<IndexEntry>> {
+    let mut entries = Vec::new();
+    collect_tree_entries(repo, tree_hash, "", &mut entries)?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn collect_tree_entries(
+    repo: &Repository,
+    tree_hash: &str,
+    prefix: &str,
+    entries: &mut Vec<IndexEntry>,
+) -> Result<()> {
+    let GitObject::Tree(tree) = repo.read_object(tree_hash)? else {
+        anyhow::bail!("{} is not a tree", tree_hash);
+    };
+    for entry in tree {
+        let path = if prefix.is_empty() {
+            entry.name.clone()
+        } else {
+            format!("{}/{}", prefix, entry.name)
+        };
+        if entry.mode == "40000" {
+            collect_tree_entries(repo, &entry.hash, &path, entries)?;
+        } else {
+            let size = match repo.read_object(&entry.hash)? {
+                GitObject::Blob(data) => data.len() as u64,
+                _ => 0,
+            };
+            entries.push(IndexEntry {
+                path,
+                hash: entry.hash,
+                size,
+                mode: entry.mode,
+                stage: 0,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Writes a blob to the working tree at `rel_path`, restoring its permission bits.
+pub fn write_worktree_file(
+    repo: &Repository,
+    rel_path: &str,
+    hash: &str,
+    mode: &str,
+) -> Result<()> {
+    if !is_safe_path(rel_path) {
+        anyhow::bail!("Refusing to write outside the working tree: {}", rel_path);
+    }
+    let GitObject::Blob(data) = repo.read_object(hash)? else {
+        anyhow::bail!("{} is not a blob", hash);
+    };
+    let full_path = repo.worktree.join(rel_path);
+    // A symlink or file may sit where a directory needs to go; replace it
+    // rather than write through it to wherever it points
+    let mut dir = repo.worktree.clone();
+    for part in Path::new(rel_path)
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+    {
+        dir.push(part);
+        if dir.is_symlink() || dir.is_file() {
+            fs::remove_file(&dir)?;
+        }
+    }
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // A directory may sit where the file needs to go (e.g. `a/b` replaced by `a`)
+    if full_path.is_dir() && !full_path.is_symlink() {
+        fs::remove_dir_all(&full_path)?;
+    } else if full_path.is_symlink() {
+        fs::remove_file(&full_path)?;
+    }
+
+    #[cfg(unix)]
+    if mode == "120000" {
+        let target = String::from_utf8_lossy(&data).into_owned();
+        std::os::unix::fs::symlink(&target, &full_path)
+            .with_context(|| format!("Failed to create symlink {}", full_path.display()))?;
+        return Ok(());
+    }
+
+    fs::write(&full_path, data)
+        .with_context(|| format!("Failed to write {}", full_path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let bits = u32::from_str_radix(mode, 8).unwrap_or(0o100644) & 0o777;
+        let bits = if bits == 0 { 0o644 } else { bits };
+        fs::set_permissions(&full_path, fs::Permissions::from_mode(bits))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    Ok(())
+}
+
+/// Removes a file from the working tree along with any parent directories left empty.
+pub fn remove_worktree_file(repo: &Repository, rel_path: &str) -> Result<()> {
+    if !is_safe_path(rel_path) {
+        anyhow::bail!("Refusing to remove outside the working tree: {}", rel_path);
+    }
+    let full_path = repo.worktree.join(rel_path);
+    if full_path.is_file() || full_path.is_symlink() {
+        fs::remove_file(&full_path)
+            .with_context(|| format!("Failed to remove {}", full_path.display()))?;
+    }
+    let mut dir = full_path.parent();
+    while let Some(d) = dir {
+        if d == repo.worktree || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+    Ok(())
+}
+
+/// Moves the working tree and index from `current` (HEAD's tree) to `target`.
+///
+/// Paths that are identical in both trees keep their working tree and staged
+/// state. Unless `force` is set, the switch is refused when a path that
+/// differs between the trees has uncommitted changes, or when an untracked
+/// file would be overwritten or sits where the target needs a directory.
+pub fn switch_tree(
+    repo: &Repository,
+    current: &[IndexEntry],
+    target: &[IndexEntry],
+    force: bool,
+) -> Result<()> {
+    let mut index = repo.get_index()?;
+    if index.iter().any(|e| e.stage > 0) {
+        if !force {
+            anyhow::bail!("You need to resolve your current index first (unmerged paths)");
+        }
+        // Forcing discards the conflict; stage 2 ("ours") is what the worktree was based on
+        index.retain(|e| e.stage == 0 || e.stage == 2);
+        index.iter_mut().for_each(|e| e.stage = 0);
+    }
+    let head: BTreeMap<&str, &IndexEntry> = current.iter().map(|e| (e.path.as_str(), e)).collect();
+    let goal: BTreeMap<&str, &IndexEntry> = target.iter().map(|e| (e.path.as_str(), e)).collect();
+    let staged: BTreeMap<&str, &IndexEntry> = index.iter().map(|e| (e.path.as_str(), e)).collect();
+
+    let same = |a: Option<&&IndexEntry>, b: Option<&&IndexEntry>| match (a, b) {
+        (Some(a), Some(b)) => a.hash == b.hash && a.mode == b.mode,
+        (None, None) => true,
+        _ => false,
+    };
+
+    let all_paths: BTreeSet<&str> = head
+        .keys()
+        .chain(goal.keys())
+        .chain(staged.keys())
+        .copied()
+        .collect();
+
+    if !force {
+        let mut conflicts = Vec::new();
+        for path in &all_paths {
+            if same(head.get(path), goal.get(path)) {
+                continue;
+            }
+            let on_disk = worktree_hash(repo, path)?;
+            let dirty = match (staged.get(path), head.get(path)) {
+                // Untracked file in the way of a file the target tree creates
+                (None, None) => {
+                    on_disk.is_some()
+                        && on_disk.as_deref() != goal.get(path).map(|e| e.hash.as_str())
+                }
+                (index_entry, head_entry) => {
+                    !same(index_entry, head_entry)
+                        || on_disk.as_deref() != index_entry.map(|e| e.hash.as_str())
+                }
+            };
+            if dirty {
+                conflicts.push(path.to_string());
+            } else if goal.contains_key(path) {
+                conflicts.extend(untracked_in_the_way(repo, path, &head)?);
+            }
+        }
+        conflicts.sort();
+        conflicts.dedup();
+        if !conflicts.is_empty() {
+            anyhow::bail!(
+                "Your local changes to the following files would be overwritten:\n    {}\n\
+                 Commit your changes or use --force to discard them",
+                conflicts.join("\n    ")
+            );
+        }
+    }
+
+    let mut new_index = Vec::new();
+    for path in &all_paths {
+        let unaffected = same(head.get(path), goal.get(path));
+        if unaffected && !force {
+            // Carry over staged state for paths the switch does not touch
+            if let Some(entry) = staged.get(path) {
+                new_index.push((*entry).clone());
+            }
+            continue;
+        }
+
+        match goal.get(path) {
+            Some(entry) => {
+                if worktree_hash(repo, path)?.as_deref() != Some(entry.hash.as_str()) || !unaffected
+                {
+                    write_worktree_file(repo, path, &entry.hash, &entry.mode)?;
+                }
+                new_index.push((*entry).clone());
+            }
+            None => {
+                if head.contains_key(path) || staged.contains_key(path) {
+                    remove_worktree_file(repo, path)?;
+                }
+            }
+        }
+    }
+
+    repo.write_index(&new_index)?;
+    Ok(())
+}
+
+/// Puts just `paths` back to their version in `head`, in the index (dropping
+/// any conflict stages) and the working tree. Other paths are left alone.
+pub fn restore_paths(
+    repo: &Repository,
+    head: &[IndexEntry],
+    paths: &BTreeSet<String>,
+) -> Result<()> {
+    let mut index = repo.get_index()?;
+    index.retain(|e| !paths.contains(&e.path));
+    for path in paths {
+        match head.iter().find(|e| &e.path == path) {
+            Some(entry) => {
+                write_worktree_file(repo, path, &entry.hash, &entry.mode)?;
+                index.push(entry.clone());
+            }
+            None => remove_worktree_file(repo, path)?,
+        }
+    }
+    repo.write_index(&index)
+}
+
+/// Lists untracked paths that writing `path` would destroy: files under a
+/// directory where `path` goes, and a file where one of its parents goes.
+fn untracked_in_the_way(
+    repo: &Repository,
+    path: &str,
+    head: &BTreeMap<&str, &IndexEntry>,
+) -> Result<Vec<String>> {
+    for (end, _) in path.match_indices('/') {
+        let parent = &path[..end];
+        let dir = repo.worktree.join(parent);
+        if (dir.is_symlink() || dir.is_file()) && !head.contains_key(parent) {
+            return Ok(vec![parent.to_string()]);
+        }
+    }
+
+    let mut found = Vec::new();
+    let full_path = repo.worktree.join(path);
+    if full_path.is_dir() && !full_path.is_symlink() {
+        for entry in walkdir::WalkDir::new(&full_path) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let rel = entry
+                .path()
+                .strip_prefix(&repo.worktree)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            if !head.contains_key(rel.as_str()) {
+                found.push(rel);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Checks that `path` (relative to the worktree) does not escape it or reach
+/// into the repository's own `.vcs` directory (in any letter case, for
+/// case-insensitive filesystems).
+fn is_safe_path(path: &str) -> bool {
+    let p = Path::new(path);
+    !path.is_empty()
+        && !p.is_absolute()
+        && p.components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+        && path.split(['/', '\\']).all(|part| {
+            !part.is_empty() && part != "." && part != ".." && !part.eq_ignore_ascii_case(".vcs")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(repo: &Repository, path: &str, content: &str) -> IndexEntry {
+        let hash = repo
+            .write_object(&GitObject::Blob(content.as_bytes().to_vec()))
+            .unwrap();
+        IndexEntry {
+            path: path.to_string(),
+            hash,
+            size: content.len() as u64,
+            mode: "100644".to_string(),
+            stage: 0,
+        }
+    }
+
+    #[test]
+    fn untracked_directory_blocks_a_target_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::create_at(dir.path().to_path_buf(), ObjectFormat::Vcs).unwrap();
+        let target = vec![entry(&repo, "0.txt", "zero"), entry(&repo, "a", "file")];
+        fs::create_dir_all(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/keep.txt"), "mine").unwrap();
+
+        let err = switch_tree(&repo, &[], &target, false).unwrap_err();
+        assert!(err.to_string().contains("a/keep.txt"));
+        assert!(!dir.path().join("0.txt").exists());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a/keep.txt")).unwrap(),
+            "mine"
+        );
+
+        switch_tree(&repo, &[], &target, true).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("a")).unwrap(), "file");
+    }
+
+    #[test]
+    fn untracked_file_blocks_a_target_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::create_at(dir.path().to_path_buf(), ObjectFormat::Vcs).unwrap();
+        let target = vec![entry(&repo, "0.txt", "zero"), entry(&repo, "a/b", "nested")];
+        fs::write(dir.path().join("a"), "mine").unwrap();
+
+        let err = switch_tree(&repo, &[], &target, false).unwrap_err();
+        assert!(err.to_string().contains("\n    a\n"));
+        assert!(!dir.path().join("0.txt").exists());
+        assert_eq!(fs::read_to_string(dir.path().join("a")).unwrap(), "mine");
+
+        switch_tree(&repo, &[], &target, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a/b")).unwrap(),
+            "nested"
+        );
+    }
+
+    #[test]
+    fn tracked_paths_swap_between_file_and_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::create_at(dir.path().to_path_buf(), ObjectFormat::Vcs).unwrap();
+        let nested = vec![entry(&repo, "a/b", "nested")];
+        let flat = vec![entry(&repo, "a", "file")];
+        switch_tree(&repo, &[], &nested, false).unwrap();
+
+        switch_tree(&repo, &nested, &flat, false).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("a")).unwrap(), "file");
+        switch_tree(&repo, &flat, &nested, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a/b")).unwrap(),
+            "nested"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Fetches and pushes over the pipe transport, with `VCS_SSH` pointing at a
+//! shim that runs the server command locally instead of on a remote host.
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+struct Env {
+    _dir: tempfile::TempDir,
+    root: PathBuf,
+    shim: PathBuf,
+}
+
+impl Env {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let bin_dir = Path::new(env!("CARGO_BIN_EXE_vcs")).parent().unwrap();
+        // Records the arguments, skips ssh's options and the host, then runs the
+        // remote command with this build of `vcs` first on the PATH
+        let shim = root.join("ssh-shim");
+        fs::write(
+            &shim,
+            format!(
+                "#!/bin/sh\n\
+                 echo \"$@\" >> '{log}'\n\
+                 while [ \"$1\" != \"--\" ]; do shift; done\n\
+                 shift 2\n\
+                 PATH='{bin}':\"$PATH\" exec sh -c \"$1\"\n",
+                log = root.join("ssh-log").display(),
+                bin = bin_dir.display(),
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&shim, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir(root.join("home")).unwrap();
+        Env {
+            _dir: dir,
+            root,
+            shim,
+        }
+    }
+
+    fn vcs(&self, dir: &Path, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_vcs"))
+            .args(args)
+            .current_dir(dir)
+            .env("VCS_SSH", &self.shim)
+            .env("HOME", self.root.join("home"))
+            .env("VCS_EDITOR", "true")
+            .output()
+            .unwrap()
+    }
+
+    fn ok(&self, dir: &Path, args: &[&str]) -> String {
+        let output = self.vcs(dir, args);
+        assert!(
+            output.status.success(),
+            "vcs {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    /// Creates a repository with an identity and one commit of `file.txt`.
+    fn repo(&self, name: &str, content: &str) -> PathBuf {
+        let dir = self.root.join(name);
+        fs::create_dir(&dir).unwrap();
+        self.ok(&dir, &["init"]);
+        self.ok(&dir, &["config", "user.name", "Test"]);
+        self.ok(&dir, &["config", "user.email", "test@example.com"]);
+        fs::write(dir.join("file.txt"), content).unwrap();
+        self.ok(&dir, &["add", "file.txt"]);
+        self.ok(&dir, &["commit", "-m", content]);
+        dir
+    }
+
+    fn head(&self, dir: &Path) -> String {
+        self.ok(dir, &["log", "--oneline", "-n", "1"])
+    }
+}
+
+#[test]
+fn fetch_and_push_over_ssh() {
+    let env = Env::new();
+    let server = env.repo("server", "one\n");
+    let url = format!("ssh://example.com{}", server.display());
+
+    env.ok(&env.root, &["clone", &url, "client"]);
+    let client = env.root.join("client");
+    assert_eq!(
+        fs::read_to_string(client.join("file.txt")).unwrap(),
+        "one\n"
+    );
+    let log = fs::read_to_string(env.root.join("ssh-log")).unwrap();
+    assert!(log.contains("-- example.com vcs upload-pack"));
+
+    env.ok(&client, &["config", "user.name", "Test"]);
+    env.ok(&client, &["config", "user.email", "test@example.com"]);
+    fs::write(client.join("file.txt"), "two\n").unwrap();
+    env.ok(&client, &["add", "file.txt"]);
+    env.ok(&client, &["commit", "-m", "two"]);
+    env.ok(&client, &["push"]);
+    assert_eq!(env.head(&server), env.head(&client));
+    assert_eq!(
+        fs::read_to_string(server.join("file.txt")).unwrap(),
+        "two\n"
+    );
+    let log = fs::read_to_string(env.root.join("ssh-log")).unwrap();
+    assert!(log.contains("-- example.com vcs receive-pack"));
+
+    fs::write(server.join("other.txt"), "three\n").unwrap();
+    env.ok(&server, &["add", "other.txt"]);
+    env.ok(&server, &["commit", "-m", "three"]);
+    env.ok(&client, &["fetch"]);
+    env.ok(&client, &["pull"]);
+    assert_eq!(env.head(&server), env.head(&client));
+}
+
+#[test]
+fn server_errors_reach_the_client() {
+    let env = Env::new();
+    let client = env.repo("client", "one\n");
+    let missing = env.root.join("missing");
+    let url = format!("example.com:{}", missing.display());
+    env.ok(&client, &["remote", "add", "origin", &url]);
+
+    let output = env.vcs(&client, &["fetch"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Remote error: Not a VCS repository"),
+        "{}",
+        stderr
+    );
+}